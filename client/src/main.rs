@@ -1,14 +1,23 @@
 use std::{io::{Write, Read, ErrorKind, self}, 
-{str, time::Duration, thread, net::{TcpStream}, }, 
-sync::{mpsc::{self, TryRecvError}, Arc, Mutex}};
+{collections::HashMap, fs::{self, OpenOptions}, path::Path, str, time::Duration, thread, net::{TcpStream}, }, 
+sync::mpsc::{self, TryRecvError}};
 use argon2::{self, Config};
+use chrono::{DateTime, Local, NaiveDate};
+use ed25519_dalek::{Signer, SigningKey};
 use json::{self, JsonValue, object};
-use regex;
+use openssl::{error::ErrorStack, sha::sha256, ssl::{SslConnector, SslFiletype, SslMethod}};
+use rand_core::OsRng;
 
 /// Definition of server addresses
 const CHAT: &str = "0.0.0.0:8888";
 const CONNECT: &str = "0.0.0.0:8889";
 const REGISTER: &str = "0.0.0.0:8890";
+/// Size of the messages exchanged with the server.
+const BUFFER_SIZE: usize = 1024;
+/// Answer of the register port when an invite code is required.
+const INVITE_REQUIRED: &str = "!invite";
+/// Bytes of a file carried by a frame, hex encoded.
+const CHUNK_SIZE: usize = 256;
 
 struct User {
     /// The pseudo the user will use inside the chat.
@@ -25,26 +34,30 @@ struct Message {
     /// The destination of the message
     to: String,
     /// Content of the message sent.
-    content: String
+    content: String,
+    /// Seconds before the message disappears, None to keep it.
+    expires_in: Option<u64>,
+    /// Identifier of the message this one replies to.
+    reply_to: Option<u64>
 }
 
 impl User {
     /// Function to get the user's pseudo.
     /// Returns a String
     fn get_pseudo(&self) -> &String {
-        return &self.pseudo;
+        &self.pseudo
     }
 
     /// Function to get the user's password.
     /// Returns a String
     fn get_pwd(&self) -> &String {
-        return &self.pwd;
+        &self.pwd
     }
 
     /// Function to get the user's token.
     /// Returns a String
     fn get_token(&self) -> &String {
-        return &self.token;
+        &self.token
     }
 
     /// Function to set the new token of the user
@@ -62,19 +75,6 @@ impl User {
         }
     }
 
-    /// Returns a printable string containing user data.
-    fn to_string(&self) -> String {
-        let mut serie:String = String::from("User: \"");
-        serie.push_str(self.pseudo.as_str());
-        serie.push_str("\", pwd: \"");
-        serie.push_str(self.pwd.as_str());
-        serie.push_str("\", token: \"");
-        serie.push_str(self.token.as_str());
-        serie.push_str("\"");
-    
-        return serie;
-    }
-
     /// Returns a json string containing user data.
     fn to_json(&self) -> String {
         let user_json:JsonValue = object!{
@@ -83,16 +83,7 @@ impl User {
             token: self.token.clone(),
         };
 
-        return json::stringify(user_json);
-    }
-
-    /// Return a clone of user.
-    fn to_user(&self) -> User {
-        User {
-            pseudo: self.get_pseudo().clone(),
-            pwd: self.get_pwd().clone(),
-            token: self.get_token().clone()
-        }
+        json::stringify(user_json)
     }
 
     /// Create a new user.
@@ -109,7 +100,7 @@ impl Clone for User {
     fn clone(&self) -> User {
         let mut user = User::create_user(self.get_pseudo().to_string(), self.get_pwd().to_string());
         user.set_token(self.get_token().to_string());
-        return user;
+        user
     }
 }
 
@@ -119,18 +110,26 @@ impl Message {
         Message{
             from: user,
             to,
-            content
+            content,
+            expires_in: None,
+            reply_to: None
         }
     }
 
     fn to_json(&self) -> String {
-        let message:JsonValue = object!{
+        let mut message:JsonValue = object!{
             from: self.from.to_json().clone(),
             to: self.to.clone(),
             content: self.content.clone(),
         };
-        
-        return json::stringify(message);
+        if let Some(expires_in) = self.expires_in {
+            message["expires_in"] = expires_in.into();
+        }
+        if let Some(reply_to) = self.reply_to {
+            message["reply_to"] = reply_to.into();
+        }
+
+        json::stringify(message)
     }
 }
 
@@ -144,7 +143,7 @@ fn read_user_entry() -> String {
 
     user_entry = user_entry.trim().parse().unwrap_or(String::from(""));
 
-    return user_entry;
+    user_entry
 }
 
 /// Return an encoded string corresponding to the hash of the given one.
@@ -155,19 +154,21 @@ fn read_user_entry() -> String {
 /// let hash = encode_pwd(input)
 /// ```
 fn encode_pwd(pwd:String) -> String{
-    return argon2::hash_encoded(pwd.as_bytes(), String::from("rust_messaging").as_bytes(), &Config::default()).unwrap_or(String::from(""));
+    argon2::hash_encoded(pwd.as_bytes(), String::from("rust_messaging").as_bytes(), &Config::default()).unwrap_or(String::from(""))
 }
 
 /// Verify the match between the pwd and the hash.
 /// Returns true if match, else false.
-fn verify_pwd(pwd:String, hash:&String) -> bool {
-    return argon2::verify_encoded(&hash, pwd.as_bytes()).unwrap_or(false);
+fn verify_pwd(pwd:String, hash:&str) -> bool {
+    argon2::verify_encoded(hash, pwd.as_bytes()).unwrap_or(false)
 }
 
 fn general_menu() {
     loop {
         println!("What do you want to do ?");
         println!("!c - connect");
+        println!("!k - connect with a key");
+        println!("!t - connect with a certificate");
         println!("!r - register");
         println!("!q- Quit");
         let entry:String = read_user_entry();
@@ -183,7 +184,23 @@ fn general_menu() {
             }
             "!c" | "!connect" => {
                 let tmp = connect();
-                if tmp.0 == true {
+                if tmp.0 {
+                    user = tmp.1;
+                } else {
+                    continue;
+                }
+            }
+            "!k" | "!key" => {
+                let tmp = connect_with_key();
+                if tmp.0 {
+                    user = tmp.1;
+                } else {
+                    continue;
+                }
+            }
+            "!t" | "!tls" => {
+                let tmp = connect_with_certificate();
+                if tmp.0 {
                     user = tmp.1;
                 } else {
                     continue;
@@ -191,7 +208,7 @@ fn general_menu() {
             }
             "!r" | "!register" => {
                 let tmp = register();
-                if tmp.0 == true {
+                if tmp.0 {
                     user = tmp.1;
                 } else {
                     continue;
@@ -215,62 +232,352 @@ fn display_help() {
     println!("!h or !help       -> display the help");
     println!("!q or !quit       -> makes you quit the rust messaging program");
     println!("!c or !connect    -> (only on the menu) launch the connect program");
+    println!("!k or !key        -> (only on the menu) connect with a private key file, (only in chat menu) add a public key to your account");
+    println!("!t or !tls        -> (only on the menu) connect with a client certificate");
     println!("!r or !register   -> (only on the menu) launch the register program");
-    println!("!p or !private    -> (only in chat menu or inside a chat) send private message to a user");
-    println!("!l or !list       -> (only inside a chat) list all connected users");
+    println!("!p or !private    -> (only inside a chat) send a private message to a user: !p <pseudo> <message>");
+    println!("!l or !list       -> (only inside a chat) list all connected users with their status");
+    println!("!whois            -> (only inside a chat) display the status, the last connection and the chats of a user: !whois <pseudo>");
+    println!("!away             -> (only inside a chat) tell the users sharing a chat you are away, until your next message: !away [message]");
+    println!("!back             -> (only inside a chat) tell the users sharing a chat you are back");
+    println!("!o or !older      -> (only inside a chat) load the previous messages");
+    println!("!e or !ephemeral  -> (only inside a chat) send a message disappearing after a delay: !e <10s|5m|2h|1d> <message>");
+    println!("!s or !search     -> (only inside a chat) search messages: !s <words> [from:pseudo] [in:chat] [after:date] [before:date]");
+    println!("!j or !join       -> enter a chat, it becomes the active one: !j <chat>");
+    println!("!create           -> (only inside a chat) create a chat and enter it: !create <chat>");
+    println!("!leave            -> (only inside a chat) leave the active chat, or the given one: !leave [chat]");
+    println!("!edit             -> (only inside a chat) replace the content of one of your messages, given by its number: !edit <id> <message>");
+    println!("!remove           -> (only inside a chat) delete one of your messages: !remove <id>");
+    println!("!reply            -> (only inside a chat) reply to a message, given by its number, the reply quotes it: !reply <id> <message>");
+    println!("!upload           -> (only inside a chat) send a file to the active chat, an interrupted upload resumes: !upload <path> [message]");
+    println!("!save             -> (only inside a chat) save the file of a message, by default under its name: !save <id> [path]");
+    println!("!mentions         -> (only inside a chat) list the last messages mentioning you with @pseudo, @channel or @here");
+    println!("!react            -> (only inside a chat) add an emoji or a shortcode to a message, the same command removes it: !react <id> <emoji|:shortcode:>");
+    println!("!thread           -> (only inside a chat) display a message with all the replies of its thread: !thread <id>");
+    println!("!revisions        -> (only inside a chat) display the previous versions of an edited message: !revisions <id>");
+    println!("!channels         -> (only inside a chat) list the chats with their topic, the active one is marked with *");
+    println!("!topic            -> (only inside a chat) change the topic of the active chat, without text the topic is removed: !topic [text]");
+    println!("!rename           -> (only inside a chat, moderators) rename the active chat: !rename <chat>");
+    println!("!kick             -> (only inside a chat, moderators) remove a user from the members of the active chat: !kick <pseudo>");
+    println!("!unkick           -> (only inside a chat, moderators) let a kicked user enter the active chat again: !unkick <pseudo>");
+    println!("!dm               -> (only inside a chat) open the private conversation with a user, messages are then sent to this user: !dm <pseudo>");
     println!("!g or !general    -> (only in chat menu) connect to general chat");
+    println!("!w or !password   -> (only in chat menu) change your password");
+    println!("!d or !delete     -> (only in chat menu) delete your account");
+}
+
+/// Send a request on the given socket and wait for the 30 bytes answer of the server.
+/// Returns the answer, or an empty String if the server refused the request.
+fn send_request(client: &mut TcpStream, request: String) -> String {
+    let buff = match frame_bytes(request) {
+        Some(buff) => buff,
+        None => {
+            println!("The request is too long, a frame holds at most {} bytes", BUFFER_SIZE);
+            return String::new();
+        }
+    };
+    client.write_all(&buff).expect("Unable to write into socket...");
+
+    loop {
+        let mut buff = vec![0; 30];
+
+        match client.read_exact(&mut buff) {
+            Ok(_) => {
+                let msg_buffer = buff.into_iter().take_while(|&x| x != 0).collect::<Vec<_>>();
+                return String::from_utf8(msg_buffer).expect("Invalid UTF-8 sequence");
+            },
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
+            Err(_) => {
+                println!("Error ... Connection stopped");
+                return String::new();
+            }
+        }
+    }
 }
 
 fn connect() -> (bool, User) {
     let mut client = TcpStream::connect(CONNECT).expect("Failed to connect");
     client.set_nonblocking(true).expect("Non-blocking can't be initiate");
 
-    println!("");
+    println!();
     println!("--------------------");
     print!("Enter username: ");
     let pseudo:String = read_user_entry();
     print!("Enter password: ");
     let pwd:String = read_user_entry();
     println!("--------------------");
-    println!("");
+    println!();
 
     let mut user = User::create_user(pseudo, pwd);
 
-    let mut full_message = String::new();
-    full_message.push_str(user.to_json().as_str());
-    
-    let mut buff = full_message.clone().into_bytes();
-    buff.resize(256, 0);
-    client.write_all(&buff).expect("Unable to write into socket...");
+    let token = send_request(&mut client, user.to_json());
+    if token.is_empty() {
+        println!("Invalid login/pwd");
+        (false, user)
+    } else {
+        user.set_token(token);
+        (true, user)
+    }
+}
 
-    loop {
-        let mut buff = vec![0; 30];
+/// Log in by signing the nonce sent by the server with a private key loaded from a file.
+fn connect_with_key() -> (bool, User) {
+    println!();
+    println!("--------------------");
+    print!("Enter username: ");
+    let pseudo:String = read_user_entry();
+    print!("Enter private key file: ");
+    let path:String = read_user_entry();
+    println!("--------------------");
+    println!();
 
-        match client.read_exact(&mut buff) {
-            Ok(_) => {
-                let msg_buffer = buff.into_iter().take_while(|&x| x != 0).collect::<Vec<_>>();
-                let msg_ascii = String::from_utf8(msg_buffer).expect("Invalid UTF-8 sequence");
+    let mut user = User::new(pseudo, String::new());
+    let key = match load_key(&path) {
+        Some(key) => key,
+        None => {
+            println!("Unable to read a private key from {}", path);
+            return (false, user);
+        }
+    };
 
-                
-                if msg_ascii != "" {
-                    user.set_token(msg_ascii);
-                }
-                
-                break;
-            },
-            Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
-            Err(_) => {
-                println!("Error ... Connection stopped");
-                break;
+    let mut client = TcpStream::connect(CONNECT).expect("Failed to connect");
+    client.set_nonblocking(true).expect("Non-blocking can't be initiate");
+
+    let challenge = object!{
+        username: user.get_pseudo().clone(),
+        key: hex::encode(key.verifying_key().to_bytes()),
+    };
+    let nonce = send_request(&mut client, json::stringify(challenge));
+    if nonce.is_empty() {
+        println!("This key is not registered for {}", user.get_pseudo());
+        return (false, user);
+    }
+
+    let answer = object!{
+        username: user.get_pseudo().clone(),
+        signature: hex::encode(key.sign(nonce.as_bytes()).to_bytes()),
+    };
+    let token = send_request(&mut client, json::stringify(answer));
+    if token.is_empty() {
+        println!("Invalid signature");
+        (false, user)
+    } else {
+        user.set_token(token);
+        (true, user)
+    }
+}
+
+/// Log in with a client certificate signed by the CA trusted by the server.
+/// The server sends back the pseudo mapped to the certificate and a token.
+fn connect_with_certificate() -> (bool, User) {
+    println!();
+    println!("--------------------");
+    print!("Enter certificate file: ");
+    let certificate:String = read_user_entry();
+    print!("Enter private key file: ");
+    let key:String = read_user_entry();
+    print!("Enter CA file: ");
+    let ca:String = read_user_entry();
+    println!("--------------------");
+    println!();
+
+    let mut user = User::new(String::new(), String::new());
+    let connector = match build_connector(&certificate, &key, &ca) {
+        Ok(connector) => connector,
+        Err(err) => {
+            println!("Unable to load the certificate: {}", err);
+            return (false, user);
+        }
+    };
+
+    let client = TcpStream::connect(CONNECT).expect("Failed to connect");
+    // Les adresses du serveur sont des IP : seule la chaîne de certification est vérifiée
+    let stream = connector.configure().map(|x| x.verify_hostname(false).use_server_name_indication(false))
+        .map_err(|err| err.to_string())
+        .and_then(|x| x.connect("localhost", client).map_err(|err| err.to_string()));
+    let mut stream = match stream {
+        Ok(stream) => stream,
+        Err(err) => {
+            println!("The server refused the connection: {}", err);
+            return (false, user);
+        }
+    };
+
+    let mut buff = vec![0; BUFFER_SIZE];
+    if stream.read_exact(&mut buff).is_err() {
+        println!("The server refused the connection: a valid client certificate is required");
+        return (false, user);
+    }
+    let msg_buffer = buff.into_iter().take_while(|&x| x != 0).collect::<Vec<_>>();
+    let answer = json::parse(str::from_utf8(&msg_buffer).unwrap_or("")).unwrap_or(object!{});
+
+    if answer["token"].is_string() {
+        user = User::new(answer["username"].to_string(), String::new());
+        user.set_token(answer["token"].to_string());
+        (true, user)
+    } else {
+        println!("The server refused the connection: {}", answer["error"]);
+        (false, user)
+    }
+}
+
+/// Build the TLS connector presenting the given client certificate.
+/// The server certificate must be signed by the given CA.
+fn build_connector(certificate: &str, key: &str, ca: &str) -> Result<SslConnector, ErrorStack> {
+    let mut builder = SslConnector::builder(SslMethod::tls())?;
+    builder.set_certificate_chain_file(certificate)?;
+    builder.set_private_key_file(key, SslFiletype::PEM)?;
+    builder.check_private_key()?;
+    builder.set_ca_file(ca)?;
+    Ok(builder.build())
+}
+
+/// Load the hex encoded Ed25519 private key stored in the given file.
+/// Returns None if the file doesn't exist or is malformed.
+fn load_key(path: &str) -> Option<SigningKey> {
+    let content = fs::read_to_string(path).ok()?;
+    let bytes:[u8; 32] = hex::decode(content.trim()).ok()?.try_into().ok()?;
+    Some(SigningKey::from_bytes(&bytes))
+}
+
+/// Generate a new Ed25519 key pair, the private key is written in the given file
+/// and the public one in the same file suffixed by ".pub".
+fn generate_key(path: &str) -> Option<SigningKey> {
+    let key = SigningKey::generate(&mut OsRng);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    // La clé privée n'est lisible que par son propriétaire
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path).ok()?.write_all(hex::encode(key.to_bytes()).as_bytes()).ok()?;
+    fs::write(format!("{}.pub", path), hex::encode(key.verifying_key().to_bytes())).ok()?;
+    Some(key)
+}
+
+/// Register the public key of a private key file on the account of the user, the password is asked.
+/// The key pair is generated if the file doesn't exist yet.
+fn add_key(user: &User) {
+    print!("Enter password: ");
+    let pwd:String = read_user_entry();
+    print!("Enter private key file: ");
+    let path:String = read_user_entry();
+
+    let key = match load_key(&path) {
+        Some(key) => key,
+        None if fs::metadata(&path).is_err() => match generate_key(&path) {
+            Some(key) => {
+                println!("New key generated in {}", path);
+                key
+            }
+            None => {
+                println!("Unable to write a new key in {}", path);
+                return;
             }
+        },
+        None => {
+            println!("Unable to read a private key from {}", path);
+            return;
         }
+    };
+
+    let mut client = TcpStream::connect(CONNECT).expect("Failed to connect");
+    client.set_nonblocking(true).expect("Non-blocking can't be initiate");
+
+    let request = object!{
+        username: user.get_pseudo().clone(),
+        token: user.get_token().clone(),
+        pwd: encode_pwd(pwd),
+        add_key: hex::encode(key.verifying_key().to_bytes()),
+    };
+    if send_request(&mut client, json::stringify(request)).is_empty() {
+        println!("The key can't be added to your account");
+    } else {
+        println!("Key added, you can now connect with {}", path);
     }
+}
 
-    if user.get_token() == "" {
-        println!("Invalid login/pwd");
-        return (false, user);
+/// Remove the public key of a private key file from the account of the user, the password is asked.
+fn remove_key(user: &User) {
+    print!("Enter password: ");
+    let pwd:String = read_user_entry();
+    print!("Enter private key file: ");
+    let path:String = read_user_entry();
+    let key = match load_key(&path) {
+        Some(key) => key,
+        None => {
+            println!("Unable to read a private key from {}", path);
+            return;
+        }
+    };
+
+    let mut client = TcpStream::connect(CONNECT).expect("Failed to connect");
+    client.set_nonblocking(true).expect("Non-blocking can't be initiate");
+
+    let request = object!{
+        username: user.get_pseudo().clone(),
+        token: user.get_token().clone(),
+        pwd: encode_pwd(pwd),
+        remove_key: hex::encode(key.verifying_key().to_bytes()),
+    };
+    if send_request(&mut client, json::stringify(request)).is_empty() {
+        println!("The key can't be removed from your account");
     } else {
-        return (true, user);
+        println!("Key removed, {} can't be used to connect any more", path);
+    }
+}
+
+/// Replace the password of the user, the current one is asked again.
+/// The other sessions of the user are closed, the user gets the token of a new session.
+fn change_password(user: &mut User) {
+    print!("Enter current password: ");
+    let pwd:String = read_user_entry();
+    print!("Enter new password: ");
+    let new_pwd:String = read_user_entry();
+
+    let mut client = TcpStream::connect(CONNECT).expect("Failed to connect");
+    client.set_nonblocking(true).expect("Non-blocking can't be initiate");
+
+    let request = object!{
+        username: user.get_pseudo().clone(),
+        token: user.get_token().clone(),
+        pwd: encode_pwd(pwd),
+        new_pwd: encode_pwd(new_pwd),
+    };
+    let token = send_request(&mut client, json::stringify(request));
+    if token.is_empty() {
+        println!("The password can't be changed");
+    } else {
+        user.set_token(token);
+        println!("Password changed, your other sessions are closed");
+    }
+}
+
+/// Delete the account of the user after a confirmation.
+/// Returns true if the account has been deleted.
+fn delete_account(user: &User) -> bool {
+    print!("Enter password: ");
+    let pwd:String = read_user_entry();
+    print!("Type \"yes\" to delete the account {}: ", user.get_pseudo());
+    if read_user_entry() != "yes" {
+        println!("Deletion cancelled");
+        return false;
+    }
+
+    let mut client = TcpStream::connect(CONNECT).expect("Failed to connect");
+    client.set_nonblocking(true).expect("Non-blocking can't be initiate");
+
+    let request = object!{
+        username: user.get_pseudo().clone(),
+        token: user.get_token().clone(),
+        pwd: encode_pwd(pwd),
+        delete: true,
+    };
+    if send_request(&mut client, json::stringify(request)).is_empty() {
+        println!("The account can't be deleted");
+        false
+    } else {
+        println!("Account {} deleted", user.get_pseudo());
+        true
     }
 }
 
@@ -288,47 +595,35 @@ fn register() -> (bool, User) {
     let mut client = TcpStream::connect(REGISTER).expect("Failed to connect");
     client.set_nonblocking(true).expect("Non-blocking can't be initiate");
 
-    let mut full_message = String::new();
-    full_message.push_str(user.to_json().as_str());
     // println!("{}", user.to_json().as_str());
-    
-    let mut buff = full_message.clone().into_bytes();
-    buff.resize(256, 0);
-    client.write_all(&buff).expect("Unable to write into socket...");
-
-    loop {
-        let mut buff = vec![0; 30];
-        match client.read_exact(&mut buff) {
-            Ok(_) => {
-                let msg_buffer = buff.into_iter().take_while(|&x| x != 0).collect::<Vec<_>>();
-                let msg_ascii = String::from_utf8(msg_buffer).expect("Invalid UTF-8 sequence");
-
-                // println!("{}", msg_ascii);
-                if msg_ascii != "" {
-                    user.set_token(msg_ascii);
-                }
-                break;
-            },
-            Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
-            Err(_) => {
-                println!("Error ... Connection stopped");
-                break;
-            }
-        }
+    let mut token = send_request(&mut client, user.to_json());
+    if token == INVITE_REQUIRED {
+        // Inscription fermée : le serveur demande un code d'invitation
+        print!("Enter invite code: ");
+        let mut request = json::parse(user.to_json().as_str()).unwrap_or(object!{});
+        request["invite"] = read_user_entry().into();
+        token = send_request(&mut client, json::stringify(request));
     }
 
-    if user.get_token() == "" {
-        return (false, user);
+    if token.is_empty() || token == INVITE_REQUIRED {
+        println!("Registration refused");
+        (false, user)
     } else {
-        return (true, user);
+        user.set_token(token);
+        (true, user)
     }
 }
 
-fn chat_menu(user: User) {
+fn chat_menu(mut user: User) {
     println!("Welcome {}", user.get_pseudo());
 
     loop {
         println!("!g- Enter in general chat");
+        println!("!j- Enter in a chat: !j <chat>");
+        println!("!k- Add a public key to your account");
+        println!("!u- Remove a public key from your account");
+        println!("!w- Change your password");
+        println!("!d- Delete your account");
         println!("!q- Quit");
 
         let entry = read_user_entry();
@@ -338,6 +633,23 @@ fn chat_menu(user: User) {
             "!g" | "!general" => {
                 chat(String::from("general"), &user);
             }
+            _ if command_argument(entry, &["!j", "!join"]).is_some() => {
+                chat(command_argument(entry, &["!j", "!join"]).unwrap_or("general").to_string(), &user);
+            }
+            "!k" | "!key" => {
+                add_key(&user);
+            }
+            "!u" | "!unkey" => {
+                remove_key(&user);
+            }
+            "!w" | "!password" => {
+                change_password(&mut user);
+            }
+            "!d" | "!delete" => {
+                if delete_account(&user) {
+                    break;
+                }
+            }
             "!q" | "!quit" => {
                 println!("Quit");
                 break;
@@ -350,74 +662,688 @@ fn chat_menu(user: User) {
     }
 }
 
-fn chat(chat_type:String, user:&User) {
-    if chat_type == "general" {
-        let mut client = TcpStream::connect(CHAT).expect("Failed to connect");
-        client.set_nonblocking(true).expect("Non-blocking can't be initiate");
-
-        // Sender / Received
-        let (tx, rx) = mpsc::channel::<String>();
-        let data_clone = user.clone();
-        let message:Message = Message::new(data_clone.clone(), String::from("general"), String::from(""));
-                        
-        let mut buff = message.to_json().into_bytes();
-        buff.resize(256, 0);
-        client.write_all(&buff).expect("Unable to write into socket...");
-
-        // Création d'un thread permettant la reception des données venant du client
-        thread::spawn(move || loop {
-            let mut buff = vec![0; 256];
-            // Envoie des données au serveur
-            match rx.try_recv() {
-                Ok(msg) => {
-                    if msg != "" {
-
-                        let message:Message = Message::new(data_clone.clone(), String::from("general"), msg);
-                        
-                        let mut buff = message.to_json().into_bytes();
-                        buff.resize(256, 0);
-                        client.write_all(&buff).expect("Unable to write into socket...");
-                    }
-                },
-                Err(TryRecvError::Empty) => (),
-                Err(TryRecvError::Disconnected) => break
+/// Returns the request of the page of history preceding the message `before`, the latest page without cursor.
+fn history_request(user: &User, to: &str, before: Option<u64>) -> String {
+    let mut request = object!{ limit: 20 };
+    if let Some(id) = before {
+        request["before"] = id.into();
+    }
+    json::stringify(object!{
+        from: user.to_json(),
+        to: to,
+        history: request,
+    })
+}
+
+/// Split a "!p pseudo message" command.
+/// Returns the recipient and the message, None if the line isn't a complete private message.
+fn parse_private(line: &str) -> Option<(String, String)> {
+    let rest = line.strip_prefix("!p ").or_else(|| line.strip_prefix("!private "))?;
+    let (to, content) = rest.trim().split_once(' ')?;
+    if content.trim().is_empty() {
+        return None;
+    }
+    Some((to.to_string(), content.trim().to_string()))
+}
+
+/// Read a duration: seconds, or a number followed by s, m, h or d.
+/// Returns the duration in seconds.
+fn parse_duration(duration: &str) -> Option<u64> {
+    let (value, unit) = match duration.char_indices().last()? {
+        (index, unit) if unit.is_alphabetic() => (&duration[..index], unit),
+        _ => (duration, 's')
+    };
+    let value:u64 = value.parse().ok()?;
+    match unit {
+        's' => Some(value),
+        'm' => value.checked_mul(60),
+        'h' => value.checked_mul(3600),
+        'd' => value.checked_mul(86400),
+        _ => None
+    }
+}
+
+/// Split a "!e duration message" command.
+/// Returns the lifetime of the message in seconds and the message.
+fn parse_ephemeral(line: &str) -> Option<(u64, String)> {
+    let rest = line.strip_prefix("!e ").or_else(|| line.strip_prefix("!ephemeral "))?;
+    let (duration, content) = rest.trim().split_once(' ')?;
+    Some((parse_duration(duration)?, content.trim().to_string()))
+}
+
+/// Returns the date in local time, with the day if it isn't today.
+fn local_time(date: &str) -> Option<String> {
+    let date = DateTime::parse_from_rfc3339(date).ok()?.with_timezone(&Local);
+    if date.date_naive() == Local::now().date_naive() {
+        Some(date.format("%H:%M:%S").to_string())
+    } else {
+        Some(date.format("%Y-%m-%d %H:%M").to_string())
+    }
+}
+
+/// Prefix a line with the local time of the frame, e.g. "[14:05] toto : hello", the line is kept if the frame has no timestamp.
+fn with_time(data: &JsonValue, line: String) -> String {
+    match data["timestamp"].as_str().and_then(|x| DateTime::parse_from_rfc3339(x).ok()) {
+        Some(date) => format!("[{}] {}", date.with_timezone(&Local).format("%H:%M"), line),
+        None => line
+    }
+}
+
+/// Returns the header to display before a frame of another day than the previous one, in local time.
+/// `day` keeps the day of the last frame displayed.
+fn day_header(frame: &str, day: &mut Option<NaiveDate>) -> Option<String> {
+    let data = json::parse(frame).ok()?;
+    let date = DateTime::parse_from_rfc3339(data["timestamp"].as_str()?).ok()?.with_timezone(&Local).date_naive();
+    if *day == Some(date) {
+        return None;
+    }
+    *day = Some(date);
+    Some(format!("---- {} ----", date.format("%A %d %B %Y")))
+}
+
+/// Read a "!s words [from:pseudo] [in:chat] [after:date] [before:date]" command.
+/// Returns the search request, None if the line isn't a search.
+fn parse_search(line: &str) -> Option<JsonValue> {
+    let rest = line.strip_prefix("!s ").or_else(|| line.strip_prefix("!search "))?;
+    let mut search = object!{};
+    let mut words = vec![];
+    for word in rest.split_whitespace() {
+        match word.split_once(':') {
+            Some(("from", value)) => search["from"] = value.into(),
+            Some(("in", value)) => search["channel"] = value.into(),
+            Some(("after", value)) => search["after"] = value.into(),
+            Some(("before", value)) => search["before"] = value.into(),
+            _ => words.push(word)
+        }
+    }
+    search["words"] = words.join(" ").into();
+    Some(search)
+}
+
+/// Returns the argument of a command, e.g. "rust" for "!join rust" with the names ["!j", "!join"].
+fn command_argument<'a>(line: &'a str, names: &[&str]) -> Option<&'a str> {
+    let (command, argument) = line.split_once(' ')?;
+    if names.contains(&command) && !argument.trim().is_empty() {
+        Some(argument.trim())
+    } else {
+        None
+    }
+}
+
+/// Read a private message received on the chat port, `me` being the pseudo of the user and `active` the active chat.
+/// Returns the other user of the conversation, the line kept in the conversation and the line to display,
+/// prefixed by the other user outside of its conversation. None if the frame isn't a private message.
+fn read_private(frame: &str, me: &str, active: &str) -> Option<(String, String, String)> {
+    let data = json::parse(frame).ok()?;
+    if !data["private"].as_bool().unwrap_or(false) {
+        return None;
+    }
+    let peer = if data["from"] == me { data["to"].to_string() } else { data["from"].to_string() };
+    let line = format!("{} : {}", data["from"], data["content"]);
+    let display = if active == format!("@{}", peer) { line.clone() } else { format!("[@{}] {}", peer, line) };
+    Some((peer, with_time(&data, line), with_time(&data, display)))
+}
+
+/// Returns the status of a user as displayed, e.g. "away: lunch".
+fn presence_status(data: &JsonValue) -> String {
+    match (data["status"].as_str(), data["last_seen"].as_str().and_then(local_time)) {
+        (Some("away"), _) if !data["message"].is_empty() => format!("away: {}", data["message"]),
+        (Some("offline"), Some(last_seen)) => format!("offline, last seen {}", last_seen),
+        (status, _) => status.unwrap_or("offline").to_string()
+    }
+}
+
+/// Returns the presence of a user as displayed, e.g. "toto (away: lunch)".
+fn presence_line(data: &JsonValue) -> String {
+    format!("{} ({})", data["pseudo"], presence_status(data))
+}
+
+/// Returns the line to display for a frame received on the chat port, None if nothing has to be displayed.
+/// Messages of the history are JSON frames, `oldest` keeps the identifier of the oldest one received in the `active` chat.
+fn read_frame(frame: &str, active: &str, oldest: &mut Option<u64>) -> Option<String> {
+    let data = match json::parse(frame) {
+        Ok(data) if data.is_object() => data,
+        _ => return Some(frame.to_string())
+    };
+    if data.has_key("search") {
+        // Résultat d'une recherche, entouré des messages voisins
+        let message = &data["message"];
+        let marker = if message["id"] == data["search"] { ">" } else { " " };
+        return Some(format!("{} [{} #{}] {} : {}", marker, message["channel"], message["id"], message["from"], message["content"]));
+    }
+    if let Some(count) = data["results"].as_usize() {
+        return Some(format!("{} result(s)", count));
+    }
+    if data["channels"].is_array() {
+        // Liste des chats, le chat actif est marqué d'une étoile
+        let lines:Vec<String> = data["channels"].members().map(|x| format!(
+            "{} {} ({} members{}){}",
+            if x["name"] == active { "*" } else { " " },
+            x["name"],
+            x["members"],
+            if x["member"].as_bool().unwrap_or(false) { ", joined" } else { "" },
+            x["topic"].as_str().map(|topic| format!(" - {}", topic)).unwrap_or_default()
+        )).collect();
+        return Some(lines.join("\n"));
+    }
+    if let Some(kind) = data["system"].as_str() {
+        // Evénement d'un chat, affiché différemment des messages des utilisateurs
+        let text = match kind {
+            "joined" => format!("{} joined {}", data["pseudo"], data["channel"]),
+            "left" => format!("{} left {}", data["pseudo"], data["channel"]),
+            "kicked" => format!("{} was kicked from {} by {}", data["pseudo"], data["channel"], data["by"]),
+            "renamed" => format!("{} renamed {} to {}", data["pseudo"], data["channel"], data["name"]),
+            "topic" if data["topic"].is_empty() => format!("{} removed the topic of {}", data["pseudo"], data["channel"]),
+            "topic" => format!("{} changed the topic of {}: {}", data["pseudo"], data["channel"], data["topic"]),
+            other => format!("{} in {}: {}", other, data["channel"], data["pseudo"])
+        };
+        return Some(with_time(&data, format!("-- {} --", text)));
+    }
+    if data["thread"].is_number() {
+        // Fil d'un message : les réponses sont décalées sous le premier message
+        let message = &data["message"];
+        if message.is_object() {
+            let mut message = message.clone();
+            message.remove("quote");
+            let line = message_line(&message, active);
+            return Some(if message["id"] == data["thread"] { line } else { format!("  | {}", line) });
+        }
+        return Some(format!("{} message(s) in the thread #{}", data["count"], data["thread"]));
+    }
+    if data["mention"].is_number() {
+        // Mention de l'utilisateur, dans n'importe lequel de ses chats
+        return Some(with_time(&data, format!("** {} mentioned you in {} (#{}) : {}", data["from"], data["channel"], data["mention"], highlight_mentions(&data["content"].to_string()))));
+    }
+    if data["inbox"].as_bool().unwrap_or(false) {
+        // Boîte des mentions, le chat de chaque message est toujours indiqué
+        return Some(match data["count"].as_usize() {
+            Some(0) => String::from("No mentions"),
+            Some(count) => format!("{} mention(s)", count),
+            None => message_line(&data["message"], "")
+        });
+    }
+    if data["reacted"].is_number() {
+        // Réaction ajoutée ou retirée par un membre du chat
+        let prefix = if data["channel"] == active { String::new() } else { format!("[{}] ", data["channel"]) };
+        let action = if data["added"].as_bool().unwrap_or(false) { "reacted" } else { "removed" };
+        let reactions = reactions_line(&data["reactions"]).map(|x| format!("{} ", x)).unwrap_or_default();
+        return Some(format!("    {}#{} {}({} {} {})", prefix, data["reacted"], reactions, data["pseudo"], action, data["reaction"]));
+    }
+    if data.has_key("revision") {
+        // Version précédente d'un message modifié
+        return Some(with_time(&data, format!("#{} was : {}", data["revision"], data["content"])));
+    }
+    if data["revisions"].is_number() && data["count"] == 0 {
+        return Some(format!("The message #{} was never edited", data["revisions"]));
+    }
+    if data["users"].is_array() {
+        // Utilisateurs connectés
+        if data["users"].is_empty() {
+            return Some(String::from("Nobody is connected"));
+        }
+        let lines:Vec<String> = data["users"].members().map(|x| format!("  {}", presence_line(x))).collect();
+        return Some(lines.join("\n"));
+    }
+    if data["whois"].is_object() {
+        let chats:Vec<&str> = data["whois"]["channels"].members().filter_map(|x| x.as_str()).collect();
+        return Some(format!("{}\n  chats: {}", presence_line(&data["whois"]), chats.join(", ")));
+    }
+    if data["presence"].is_object() {
+        // Changement de statut d'un utilisateur qui partage un chat
+        return Some(format!("* {} is now {}", data["presence"]["pseudo"], presence_status(&data["presence"])));
+    }
+    if let Some(id) = data["id"].as_u64() {
+        if data["channel"] == active && oldest.map(|x| id < x).unwrap_or(true) {
+            *oldest = Some(id);
+        }
+        return Some(message_line(&data, active));
+    }
+    if data["count"] == 0 {
+        return Some(String::from("No older messages"));
+    }
+    None
+}
+
+/// Returns the line displayed for a message, e.g. "[14:05] [rust] #12 toto : hello".
+fn message_line(data: &JsonValue, active: &str) -> String {
+    // Les messages des autres chats sont précédés du nom de leur chat
+    let prefix = if data["channel"] == active { String::new() } else { format!("[{}] ", data["channel"]) };
+    let content = if data["deleted"].as_bool().unwrap_or(false) { String::from("message deleted") } else { data["content"].to_string() };
+    let mut line = format!("{}#{} {} : {}", prefix, data["id"], data["from"], highlight_mentions(&content));
+    // Fichier joint, enregistré avec !save
+    let attachment = &data["attachment"];
+    if attachment.is_object() {
+        line.push_str(&format!(" [file {}, {}]", attachment["name"], format_size(attachment["size"].as_u64().unwrap_or(0))));
+    }
+    if data["edited"].is_string() {
+        line.push_str(" (edited)");
+    }
+    // Un message éphémère indique l'heure de sa disparition
+    if let Some(expires) = data["expires"].as_str().and_then(local_time) {
+        line.push_str(&format!(" (disappears at {})", expires));
+    }
+    let mut line = with_time(data, line);
+    // Les réactions sont affichées sous le message
+    if let Some(reactions) = reactions_line(&data["reactions"]) {
+        line.push_str(&format!("\n    {}", reactions));
+    }
+    // Une réponse est précédée du début du message auquel elle répond
+    let quote = &data["quote"];
+    if quote.is_object() {
+        let content = if quote["deleted"].as_bool().unwrap_or(false) { String::from("message deleted") } else { quote["content"].to_string() };
+        return format!("  > #{} {} : {}\n{}", data["reply_to"], quote["from"], content, line);
+    }
+    line
+}
+
+/// Returns a size in bytes readable by the user, e.g. "2.5 KB".
+fn format_size(size: u64) -> String {
+    match size {
+        0..=1023 => format!("{} B", size),
+        1024..=1048575 => format!("{:.1} KB", size as f64 / 1024.0),
+        _ => format!("{:.1} MB", size as f64 / 1048576.0)
+    }
+}
+
+/// Returns the frame announcing the upload of a file to a chat and the hex encoded SHA-256 of the file.
+/// None if the file can't be read.
+fn upload_request(user: &User, to: &str, path: &str, content: &str) -> Option<(String, String)> {
+    let file = fs::read(path).ok()?;
+    let hash = hex::encode(sha256(&file));
+    let name = Path::new(path).file_name()?.to_str()?;
+    let request = object!{
+        from: user.to_json(),
+        to: to,
+        content: content,
+        upload: object!{ name: name, size: file.len(), hash: hash.as_str() },
+    };
+    Some((hash, json::stringify(request)))
+}
+
+/// Returns the frames carrying the content of a file from the given offset.
+fn chunk_frames(user: &User, hash: &str, content: &[u8], offset: u64) -> Vec<String> {
+    let start = (offset as usize).min(content.len());
+    content[start..].chunks(CHUNK_SIZE).enumerate()
+        .map(|(index, chunk)| json::stringify(object!{
+            from: user.to_json(),
+            upload: hash,
+            offset: start + index * CHUNK_SIZE,
+            data: hex::encode(chunk),
+        }))
+        .collect()
+}
+
+/// Read a frame of a file being downloaded, `downloads` gives the path where each file is saved.
+/// The content is written in "path.part", checked with its hash at the end of the file and then renamed.
+/// Returns the line to display, empty for a chunk. None if the frame isn't a part of a download.
+fn read_download(frame: &str, downloads: &mut HashMap<u64, String>) -> Option<String> {
+    let data = json::parse(frame).ok()?;
+    let id = data["file"].as_u64()?;
+    let path = match downloads.get(&id) {
+        Some(path) => path.clone(),
+        // Les morceaux d'un téléchargement abandonné sont ignorés
+        None => return data["data"].is_string().then(String::new)
+    };
+    let part = format!("{}.part", path);
+    if let Some(chunk) = data["data"].as_str() {
+        // Un morceau qui ne suit pas la partie déjà reçue est ignoré
+        let received = fs::metadata(&part).map(|x| x.len()).unwrap_or(0);
+        if data["offset"].as_u64() == Some(received) {
+            let chunk = hex::decode(chunk).unwrap_or_default();
+            let written = OpenOptions::new().create(true).append(true).open(&part).and_then(|mut file| file.write_all(&chunk));
+            if let Err(err) = written {
+                downloads.remove(&id);
+                fs::remove_file(&part).ok();
+                return Some(format!("Unable to write {}: {}, the download of #{} is stopped", part, err, id));
             }
-            // A la réception d'un message
-            match client.read_exact(&mut buff) {
-                Ok(_) => {
-                    let msg_buffer = buff.into_iter().take_while(|&x| x != 0).collect::<Vec<_>>();
-                    let msg_ascii = String::from_utf8(msg_buffer).expect("Invalid UTF-8 sequence");
-
-                    println!("{}", msg_ascii);
-
-                },
-                Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
-                Err(_) => {
-                    println!("Error ... Connection stopped");
-                    break;
+        }
+        return Some(String::new());
+    }
+    downloads.remove(&id);
+    let content = fs::read(&part).unwrap_or_default();
+    if data["hash"] != hex::encode(sha256(&content)) {
+        fs::remove_file(&part).ok();
+        return Some(format!("The file of #{} is corrupted, save it again", id));
+    }
+    if let Err(err) = fs::rename(&part, &path) {
+        fs::remove_file(&part).ok();
+        return Some(format!("Unable to save {}: {}", path, err));
+    }
+    Some(format!("Saved {} ({})", path, format_size(content.len() as u64)))
+}
+
+/// Returns the text with its mentions ("@pseudo", "@channel", "@here") in bold.
+fn highlight_mentions(text: &str) -> String {
+    let mut result = String::new();
+    let mut mention = false;
+    let mut previous = ' ';
+    for c in text.chars() {
+        let in_name = c.is_alphanumeric() || c == '-' || c == '_' || c == '.';
+        if mention && !in_name {
+            result.push_str("\x1b[0m");
+            mention = false;
+        }
+        // Un "@" au milieu d'un mot, comme dans une adresse mail, n'est pas une mention
+        if c == '@' && !previous.is_alphanumeric() {
+            result.push_str("\x1b[1m");
+            mention = true;
+        }
+        result.push(c);
+        previous = c;
+    }
+    if mention {
+        result.push_str("\x1b[0m");
+    }
+    result
+}
+
+/// Returns the reactions to a message with their number of users, e.g. "👍 2  :tada: 1", None if there is no reaction.
+fn reactions_line(counts: &JsonValue) -> Option<String> {
+    let reactions:Vec<String> = counts.entries().map(|(reaction, count)| format!("{} {}", reaction, count)).collect();
+    if reactions.is_empty() {
+        None
+    } else {
+        Some(reactions.join("  "))
+    }
+}
+
+/// Read the new version of an edited or deleted message, `lines` keeps the line displayed for each message.
+/// Returns the line to display, the original line struck out for a deleted message. None if the frame isn't an update.
+fn read_update(frame: &str, active: &str, lines: &mut HashMap<u64, String>) -> Option<String> {
+    let data = json::parse(frame).ok()?;
+    let message = &data["update"];
+    let id = message["id"].as_u64()?;
+    if message["deleted"].as_bool().unwrap_or(false) {
+        let original = lines.remove(&id).unwrap_or_else(|| message_line(message, active));
+        return Some(format!("\x1b[9m{}\x1b[0m (deleted)", original));
+    }
+    let line = message_line(message, active);
+    lines.insert(id, line.clone());
+    Some(line)
+}
+
+/// Returns the chat which becomes the active one after a system event, `me` being the pseudo of the user:
+/// the new name of the active chat when it is renamed, the general chat when the user is kicked from it.
+fn next_active_chat(frame: &str, active: &str, me: &str) -> Option<String> {
+    let data = json::parse(frame).ok()?;
+    if data["channel"] != active {
+        return None;
+    }
+    match data["system"].as_str()? {
+        "renamed" => Some(data["name"].to_string()),
+        "kicked" if data["pseudo"] == me => Some(String::from("general")),
+        _ => None
+    }
+}
+
+/// Send a frame on the chat connection.
+/// The frame is refused if it is too long.
+fn send_frame(client: &mut TcpStream, frame: String) {
+    match frame_bytes(frame) {
+        Some(buff) => client.write_all(&buff).expect("Unable to write into socket..."),
+        None => println!("The message is too long, a frame holds at most {} bytes", BUFFER_SIZE)
+    }
+}
+
+/// Returns the frame padded with zeros to the size of the buffer,
+/// None if it doesn't fit: cutting it could split a character.
+fn frame_bytes(frame: String) -> Option<Vec<u8>> {
+    let mut buff = frame.into_bytes();
+    if buff.len() > BUFFER_SIZE {
+        return None;
+    }
+    buff.resize(BUFFER_SIZE, 0);
+    Some(buff)
+}
+
+/// Enter a chat, the server sends back its last messages.
+fn enter_chat(client: &mut TcpStream, user: &User, channel: &str) {
+    send_frame(client, Message::new(user.clone(), channel.to_string(), String::new()).to_json());
+    println!("Active chat: {}", channel);
+}
+
+fn chat(channel:String, user:&User) {
+    let mut client = TcpStream::connect(CHAT).expect("Failed to connect");
+    client.set_nonblocking(true).expect("Non-blocking can't be initiate");
+
+    // Sender / Received
+    let (tx, rx) = mpsc::channel::<String>();
+    let data_clone = user.clone();
+    // Chat dans lequel sont envoyés les messages
+    let mut active = channel;
+    enter_chat(&mut client, &data_clone, &active);
+
+    // Identifiant du plus ancien message affiché, curseur du chargement des messages précédents
+    let mut oldest: Option<u64> = None;
+    // Jour du dernier message affiché, un en-tête sépare les messages de jours différents
+    let mut day: Option<NaiveDate> = None;
+    // Lignes affichées pour chaque message, barrées à la suppression du message
+    let mut lines: HashMap<u64, String> = HashMap::new();
+    // Messages privés reçus et envoyés pendant la session, par correspondant
+    let mut conversations: HashMap<String, Vec<String>> = HashMap::new();
+    // Fichiers annoncés au serveur, par empreinte, et fichiers en cours de téléchargement, par message
+    let mut uploads: HashMap<String, String> = HashMap::new();
+    let mut downloads: HashMap<u64, String> = HashMap::new();
+    // Noms des fichiers joints aux messages affichés
+    let mut files: HashMap<u64, String> = HashMap::new();
+
+    // Création d'un thread permettant la reception des données venant du client
+    thread::spawn(move || loop {
+        let mut buff = vec![0; BUFFER_SIZE];
+        // Envoie des données au serveur
+        match rx.try_recv() {
+            Ok(msg) => {
+                if (msg == "!older" || msg == "!o") && active.starts_with('@') {
+                    println!("The private messages are not kept by the server");
+                } else if msg == "!older" || msg == "!o" {
+                    send_frame(&mut client, history_request(&data_clone, &active, oldest));
+                } else if msg == "!list" || msg == "!l" {
+                    send_frame(&mut client, json::stringify(object!{ from: data_clone.to_json(), list: true }));
+                } else if let Some(pseudo) = command_argument(&msg, &["!whois"]) {
+                    send_frame(&mut client, json::stringify(object!{ from: data_clone.to_json(), whois: pseudo.trim_start_matches('@') }));
+                } else if msg == "!away" || command_argument(&msg, &["!away"]).is_some() {
+                    send_frame(&mut client, json::stringify(object!{ from: data_clone.to_json(), away: command_argument(&msg, &["!away"]).unwrap_or("") }));
+                } else if msg == "!back" {
+                    send_frame(&mut client, json::stringify(object!{ from: data_clone.to_json(), away: JsonValue::Null }));
+                } else if msg == "!topic" || command_argument(&msg, &["!topic"]).is_some() {
+                    send_frame(&mut client, json::stringify(object!{ from: data_clone.to_json(), to: active.as_str(), topic: command_argument(&msg, &["!topic"]).unwrap_or("") }));
+                } else if let Some(name) = command_argument(&msg, &["!rename"]) {
+                    send_frame(&mut client, json::stringify(object!{ from: data_clone.to_json(), to: active.as_str(), rename: name }));
+                } else if let Some(pseudo) = command_argument(&msg, &["!kick"]) {
+                    send_frame(&mut client, json::stringify(object!{ from: data_clone.to_json(), to: active.as_str(), kick: pseudo }));
+                } else if let Some(pseudo) = command_argument(&msg, &["!unkick"]) {
+                    send_frame(&mut client, json::stringify(object!{ from: data_clone.to_json(), to: active.as_str(), unkick: pseudo }));
+                } else if let Some((id, content)) = command_argument(&msg, &["!edit"]).and_then(|x| x.split_once(' ')) {
+                    match id.trim_start_matches('#').parse::<u64>() {
+                        Ok(id) => send_frame(&mut client, json::stringify(object!{ from: data_clone.to_json(), edit: id, content: content.trim() })),
+                        Err(_) => println!("Usage: !edit <id> <message>")
+                    }
+                } else if let Some((id, content)) = command_argument(&msg, &["!reply"]).and_then(|x| x.split_once(' ')) {
+                    // Réponse à un message du chat actif
+                    match id.trim_start_matches('#').parse::<u64>() {
+                        Ok(id) => {
+                            let mut message:Message = Message::new(data_clone.clone(), active.clone(), content.trim().to_string());
+                            message.reply_to = Some(id);
+                            send_frame(&mut client, message.to_json());
+                        }
+                        Err(_) => println!("Usage: !reply <id> <message>")
+                    }
+                } else if let Some((id, reaction)) = command_argument(&msg, &["!react"]).and_then(|x| x.split_once(' ')) {
+                    // Réagir une seconde fois de la même façon retire la réaction
+                    match id.trim_start_matches('#').parse::<u64>() {
+                        Ok(id) => send_frame(&mut client, json::stringify(object!{ from: data_clone.to_json(), react: id, reaction: reaction.trim() })),
+                        Err(_) => println!("Usage: !react <id> <emoji>")
+                    }
+                } else if let Some(id) = command_argument(&msg, &["!remove", "!revisions", "!thread"]) {
+                    let key = msg[1..].split(' ').next().unwrap_or_default();
+                    match id.trim_start_matches('#').parse::<u64>() {
+                        Ok(id) => {
+                            let mut request = object!{ from: data_clone.to_json() };
+                            request[key] = id.into();
+                            send_frame(&mut client, json::stringify(request));
+                        }
+                        Err(_) => println!("Usage: !{} <id>", key)
+                    }
+                } else if let Some(argument) = command_argument(&msg, &["!upload"]) {
+                    let (path, content) = argument.split_once(' ').unwrap_or((argument, ""));
+                    if active.starts_with('@') {
+                        println!("Files can only be sent to a chat");
+                    } else if let Some((hash, request)) = upload_request(&data_clone, &active, path, content.trim()) {
+                        uploads.insert(hash, path.to_string());
+                        send_frame(&mut client, request);
+                    } else {
+                        println!("Unable to read {}", path);
+                    }
+                } else if let Some(argument) = command_argument(&msg, &["!save"]) {
+                    let (id, path) = argument.split_once(' ').unwrap_or((argument, ""));
+                    match id.trim_start_matches('#').parse::<u64>() {
+                        Ok(id) => {
+                            // Sans chemin, le fichier est enregistré sous son nom dans le répertoire courant
+                            let path = if path.trim().is_empty() { files.get(&id).cloned().unwrap_or(format!("file-{}", id)) } else { path.trim().to_string() };
+                            // Un téléchargement interrompu reprend à la fin de la partie déjà reçue
+                            let offset = fs::metadata(format!("{}.part", path)).map(|x| x.len()).unwrap_or(0);
+                            if offset > 0 {
+                                println!("Resuming the download of #{} at {}", id, format_size(offset));
+                            }
+                            downloads.insert(id, path);
+                            send_frame(&mut client, json::stringify(object!{ from: data_clone.to_json(), download: id, offset: offset }));
+                        }
+                        Err(_) => println!("Usage: !save <id> [path]")
+                    }
+                } else if msg == "!mentions" {
+                    send_frame(&mut client, json::stringify(object!{ from: data_clone.to_json(), mentions: true }));
+                } else if msg == "!channels" {
+                    send_frame(&mut client, json::stringify(object!{ from: data_clone.to_json(), channels: true }));
+                } else if let Some(name) = command_argument(&msg, &["!j", "!join"]) {
+                    active = name.to_string();
+                    oldest = None;
+                    enter_chat(&mut client, &data_clone, &active);
+                } else if let Some(pseudo) = command_argument(&msg, &["!dm"]) {
+                    // Conversation privée : les messages sont envoyés au correspondant
+                    active = format!("@{}", pseudo.trim_start_matches('@'));
+                    println!("Active chat: {} (private)", active);
+                    for line in conversations.get(&active[1..]).into_iter().flatten() {
+                        println!("{}", line);
+                    }
+                } else if let Some(name) = command_argument(&msg, &["!create"]) {
+                    // Le chat créé devient le chat actif
+                    send_frame(&mut client, json::stringify(object!{ from: data_clone.to_json(), to: name, create: true }));
+                    active = name.to_string();
+                    oldest = None;
+                    enter_chat(&mut client, &data_clone, &active);
+                } else if msg == "!leave" || command_argument(&msg, &["!leave"]).is_some() {
+                    let name = command_argument(&msg, &["!leave"]).unwrap_or(&active).to_string();
+                    if !name.starts_with('@') {
+                        send_frame(&mut client, json::stringify(object!{ from: data_clone.to_json(), to: name.as_str(), leave: true }));
+                    }
+                    // En quittant le chat actif, l'utilisateur revient dans le chat général
+                    if name == active && active != "general" {
+                        active = String::from("general");
+                        oldest = None;
+                        enter_chat(&mut client, &data_clone, &active);
+                    }
+                } else if let Some(search) = parse_search(&msg) {
+                    send_frame(&mut client, json::stringify(object!{ from: data_clone.to_json(), to: active.as_str(), search: search }));
+                } else if let Some((expires_in, content)) = parse_ephemeral(&msg) {
+                    // Message éphémère, supprimé par le serveur à son expiration
+                    let mut message:Message = Message::new(data_clone.clone(), active.clone(), content);
+                    message.expires_in = Some(expires_in);
+                    send_frame(&mut client, message.to_json());
+                } else if let Some((to, content)) = parse_private(&msg) {
+                    // Les messages privés sont adressés à "@pseudo"
+                    let message:Message = Message::new(data_clone.clone(), format!("@{}", to), content);
+                    send_frame(&mut client, message.to_json());
+                } else if !msg.is_empty() {
+                    let message:Message = Message::new(data_clone.clone(), active.clone(), msg);
+                    send_frame(&mut client, message.to_json());
+                }
+            },
+            Err(TryRecvError::Empty) => (),
+            Err(TryRecvError::Disconnected) => break
+        }
+        // A la réception d'un message
+        let mut received = false;
+        match client.read_exact(&mut buff) {
+            Ok(_) => {
+                received = true;
+                let msg_buffer = buff.into_iter().take_while(|&x| x != 0).collect::<Vec<_>>();
+                // Une trame invalide ne doit pas arrêter la réception
+                let msg_ascii = String::from_utf8_lossy(&msg_buffer).into_owned();
+
+                if let Some(header) = day_header(&msg_ascii, &mut day) {
+                    println!("{}", header);
                 }
+                let frame = json::parse(&msg_ascii).unwrap_or(JsonValue::Null);
+                if let (Some(hash), Some(offset)) = (frame["upload"].as_str(), frame["offset"].as_u64()) {
+                    // Le serveur attend le contenu du fichier à partir de la position donnée
+                    if let Some((path, content)) = uploads.remove(hash).and_then(|path| Some((path.clone(), fs::read(path).ok()?))) {
+                        println!("Sending {} ({}{})", path, format_size(content.len() as u64), if offset > 0 { format!(", resumed at {}", format_size(offset)) } else { String::new() });
+                        // Les morceaux sont envoyés d'une traite, sans attendre le serveur
+                        client.set_nonblocking(false).expect("Blocking can't be initiate");
+                        for chunk in chunk_frames(&data_clone, hash, &content, offset) {
+                            send_frame(&mut client, chunk);
+                        }
+                        client.set_nonblocking(true).expect("Non-blocking can't be initiate");
+                    }
+                } else if let Some(line) = read_download(&msg_ascii, &mut downloads) {
+                    if !line.is_empty() {
+                        println!("{}", line);
+                    }
+                } else if let Some((peer, line, display)) = read_private(&msg_ascii, data_clone.get_pseudo(), &active) {
+                    println!("{}", display);
+                    conversations.entry(peer).or_default().push(line);
+                } else if let Some(line) = read_update(&msg_ascii, &active, &mut lines) {
+                    println!("{}", line);
+                } else if let Some(line) = read_frame(&msg_ascii, &active, &mut oldest) {
+                    if let Some(id) = frame["id"].as_u64() {
+                        lines.insert(id, line.clone());
+                        if let Some(name) = frame["attachment"]["name"].as_str().and_then(|x| Path::new(x).file_name()).and_then(|x| x.to_str()) {
+                            files.insert(id, name.to_string());
+                        }
+                    }
+                    println!("{}", line);
+                }
+                match next_active_chat(&msg_ascii, &active, data_clone.get_pseudo()) {
+                    Some(name) if name == "general" => {
+                        active = name;
+                        oldest = None;
+                        enter_chat(&mut client, &data_clone, &active);
+                    }
+                    Some(name) => {
+                        active = name;
+                        println!("Active chat: {}", active);
+                    }
+                    None => ()
+                }
+
+            },
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
+            Err(_) => {
+                println!("Error ... Connection stopped");
+                break;
             }
+        }
 
-            // Raffraîchissement du thread toutes les 100ms
+        // Raffraîchissement du thread toutes les 100ms, les trames déjà arrivées sont lues sans attendre
+        if !received {
             thread::sleep(Duration::from_millis(100));
-        });
+        }
+    });
 
 
-        // Ecriture d'un message dans le terminal
-        loop {
-            let mut buff = String::new();
-            io::stdin().read_line(&mut buff).expect("Failed to read stdin");
-            let mut msg = buff.trim().to_string();
+    // Ecriture d'un message dans le terminal
+    loop {
+        let mut buff = String::new();
+        io::stdin().read_line(&mut buff).expect("Failed to read stdin");
+        let msg = buff.trim().to_string();
 
-            // Commande pour quitter le chat
-            if msg.clone() == "!quit" || msg.clone() == "!q" || tx.send(msg.clone()).is_err() {
-                break
-            }
-            if msg == "!help" || msg == "!h" {
-                display_help();
-                msg = String::new();
-                continue;
-            }
+        // Commande pour quitter le chat
+        if msg == "!quit" || msg == "!q" || tx.send(msg.clone()).is_err() {
+            break
+        }
+        if msg == "!help" || msg == "!h" {
+            display_help();
+            continue;
         }
     }
 }
@@ -431,12 +1357,13 @@ fn main() {
     general_menu();   
 }
 
+#[cfg(test)]
 mod unit_testing {
     use super::*;
     
     #[test]
     fn test_get_pseudo() {
-        let user = User::create_user(String::from("toto"), String::from(encode_pwd(String::from("toto"))));
+        let user = User::create_user(String::from("toto"), encode_pwd(String::from("toto")));
         assert_eq!(user.get_pseudo().to_string(), String::from("toto"));
     }
 
@@ -450,8 +1377,228 @@ mod unit_testing {
     
     #[test]
     fn test_geta_and_set_token() {
-        let mut user = User::create_user(String::from("toto"), String::from(encode_pwd(String::from("toto"))));
+        let mut user = User::create_user(String::from("toto"), encode_pwd(String::from("toto")));
         user.set_token(String::from("mytoken"));
         assert_eq!(user.get_token().to_string(), String::from("mytoken"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_generate_and_load_key() {
+        let path = std::env::temp_dir().join(format!("rust_messaging_test_key_{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let key = generate_key(path).unwrap();
+
+        assert_eq!(load_key(path).unwrap().to_bytes(), key.to_bytes());
+        assert_eq!(fs::read_to_string(format!("{}.pub", path)).unwrap(), hex::encode(key.verifying_key().to_bytes()));
+        #[cfg(unix)]
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&fs::metadata(path).unwrap().permissions()) & 0o777, 0o600);
+        // Une clé existante n'est jamais écrasée
+        assert!(generate_key(path).is_none());
+        fs::remove_file(path).ok();
+        fs::remove_file(format!("{}.pub", path)).ok();
+    }
+
+    #[test]
+    fn test_frame_bytes() {
+        assert_eq!(frame_bytes(String::from("hello")).unwrap().len(), BUFFER_SIZE);
+        assert_eq!(frame_bytes("é".repeat(BUFFER_SIZE / 2)).unwrap().len(), BUFFER_SIZE);
+        assert!(frame_bytes("é".repeat(BUFFER_SIZE / 2 + 1)).is_none());
+    }
+
+    #[test]
+    fn test_read_frame() {
+        let mut oldest = None;
+        assert_eq!(read_frame("toto : hello", "general", &mut oldest), Some(String::from("toto : hello")));
+        assert_eq!(read_frame(r#"{"id": 12, "channel": "general", "from": "toto", "content": "hello"}"#, "general", &mut oldest), Some(String::from("#12 toto : hello")));
+        assert_eq!(read_frame(r#"{"id": 15, "channel": "general", "from": "toto", "content": "world"}"#, "general", &mut oldest), Some(String::from("#15 toto : world")));
+        assert_eq!(read_frame(r#"{"id": 2, "channel": "rust", "from": "titi", "content": "hi"}"#, "general", &mut oldest), Some(String::from("[rust] #2 titi : hi")));
+        assert_eq!(oldest, Some(12));
+        let frame = r#"{"channels": [{"name": "general", "members": 2, "member": true}, {"name": "rust", "members": 1, "member": false}]}"#;
+        assert_eq!(read_frame(frame, "general", &mut oldest), Some(String::from("* general (2 members, joined)\n  rust (1 members)")));
+        assert_eq!(read_frame(r#"{"history": "general", "count": 2}"#, "general", &mut oldest), None);
+        assert_eq!(read_frame(r#"{"history": "general", "count": 0}"#, "general", &mut oldest), Some(String::from("No older messages")));
+    }
+
+    #[test]
+    fn test_parse_ephemeral() {
+        assert_eq!(parse_duration("90"), Some(90));
+        assert_eq!(parse_duration("5m"), Some(300));
+        assert_eq!(parse_duration("1d"), Some(86400));
+        assert_eq!(parse_duration("5y"), None);
+        assert_eq!(parse_duration(&format!("{}d", u64::MAX)), None);
+        assert_eq!(parse_ephemeral("!e 2h see you"), Some((7200, String::from("see you"))));
+        assert_eq!(parse_ephemeral("!e soon see you"), None);
+
+        let mut oldest = None;
+        let frame = r#"{"id": 3, "channel": "general", "from": "toto", "content": "hello", "expires": "2000-01-01T00:00:00Z"}"#;
+        assert!(read_frame(frame, "general", &mut oldest).unwrap().starts_with("#3 toto : hello (disappears at "));
+    }
+
+    #[test]
+    fn test_parse_search() {
+        let search = parse_search("!s hello from:toto world after:2026-10-01").unwrap();
+        assert_eq!(search["words"], "hello world");
+        assert_eq!(search["from"], "toto");
+        assert_eq!(search["after"], "2026-10-01");
+        assert!(search["channel"].is_null());
+        assert!(parse_search("hello").is_none());
+
+        let mut oldest = None;
+        let frame = r#"{"search": 3, "message": {"id": 3, "channel": "general", "from": "toto", "content": "hello"}}"#;
+        assert_eq!(read_frame(frame, "general", &mut oldest), Some(String::from("> [general #3] toto : hello")));
+        assert_eq!(oldest, None);
+    }
+
+    #[test]
+    fn test_command_argument() {
+        assert_eq!(command_argument("!j rust", &["!j", "!join"]), Some("rust"));
+        assert_eq!(command_argument("!join  rust ", &["!j", "!join"]), Some("rust"));
+        assert_eq!(command_argument("!j", &["!j", "!join"]), None);
+        assert_eq!(command_argument("!leave rust", &["!j", "!join"]), None);
+    }
+
+    #[test]
+    fn test_read_private() {
+        let frame = r#"{"private": true, "from": "titi", "to": "toto", "content": "hello", "timestamp": "2026-10-19T10:00:00Z"}"#;
+        let time = DateTime::parse_from_rfc3339("2026-10-19T10:00:00Z").unwrap().with_timezone(&Local).format("%H:%M");
+        let line = format!("[{}] titi : hello", time);
+        assert_eq!(read_private(frame, "toto", "@titi"), Some((String::from("titi"), line.clone(), line.clone())));
+        assert_eq!(read_private(frame, "titi", "general"), Some((String::from("toto"), line, format!("[{}] [@toto] titi : hello", time))));
+        assert_eq!(read_private(r#"{"id": 1, "from": "titi", "content": "hello"}"#, "toto", "general"), None);
+        assert_eq!(read_private("Unknown user tata", "toto", "general"), None);
+    }
+
+    #[test]
+    fn test_read_presence() {
+        let mut oldest = None;
+        let frame = r#"{"users": [{"pseudo": "titi", "status": "online"}, {"pseudo": "toto", "status": "away", "message": "lunch"}]}"#;
+        assert_eq!(read_frame(frame, "general", &mut oldest), Some(String::from("  titi (online)\n  toto (away: lunch)")));
+        assert_eq!(read_frame(r#"{"users": []}"#, "general", &mut oldest), Some(String::from("Nobody is connected")));
+        assert_eq!(read_frame(r#"{"presence": {"pseudo": "toto", "status": "online"}}"#, "general", &mut oldest), Some(String::from("* toto is now online")));
+        assert_eq!(read_frame(r#"{"presence": {"pseudo": "toto", "status": "away", "message": "lunch"}}"#, "general", &mut oldest), Some(String::from("* toto is now away: lunch")));
+        let frame = r#"{"whois": {"pseudo": "toto", "status": "offline", "last_seen": "2000-01-01T00:00:00Z", "channels": ["general", "rust"]}}"#;
+        let line = read_frame(frame, "general", &mut oldest).unwrap();
+        assert!(line.starts_with("toto (offline, last seen "));
+        assert!(line.ends_with("chats: general, rust"));
+    }
+
+    #[test]
+    fn test_time_and_day_header() {
+        let mut oldest = None;
+        let frame = r#"{"id": 1, "channel": "general", "from": "toto", "content": "hello", "timestamp": "2026-10-19T10:00:00Z"}"#;
+        let time = DateTime::parse_from_rfc3339("2026-10-19T10:00:00Z").unwrap().with_timezone(&Local);
+        assert_eq!(read_frame(frame, "general", &mut oldest), Some(format!("[{}] #1 toto : hello", time.format("%H:%M"))));
+
+        let mut day = None;
+        assert_eq!(day_header(frame, &mut day), Some(format!("---- {} ----", time.format("%A %d %B %Y"))));
+        assert_eq!(day_header(frame, &mut day), None);
+        assert_eq!(day_header(r#"{"id": 2, "timestamp": "2026-10-25T10:00:00Z"}"#, &mut day).map(|x| x.contains("2026")), Some(true));
+        assert_eq!(day_header("toto : hello", &mut day), None);
+    }
+
+    #[test]
+    fn test_read_update() {
+        let mut lines = HashMap::new();
+        let edited = r#"{"update": {"id": 4, "channel": "general", "from": "toto", "content": "hello", "edited": "2026-10-19T10:00:00Z"}}"#;
+        assert_eq!(read_update(edited, "general", &mut lines), Some(String::from("#4 toto : hello (edited)")));
+        let deleted = r#"{"update": {"id": 4, "channel": "general", "from": "toto", "content": "", "deleted": true}}"#;
+        assert_eq!(read_update(deleted, "general", &mut lines), Some(String::from("\x1b[9m#4 toto : hello (edited)\x1b[0m (deleted)")));
+        assert_eq!(read_update(deleted, "general", &mut lines), Some(String::from("\x1b[9m#4 toto : message deleted\x1b[0m (deleted)")));
+        assert_eq!(read_update(r#"{"id": 4, "channel": "general", "from": "toto", "content": "hello"}"#, "general", &mut lines), None);
+    }
+
+    #[test]
+    fn test_read_thread() {
+        let mut oldest = None;
+        let reply = r#"{"id": 7, "channel": "general", "from": "titi", "content": "yes", "reply_to": 5, "quote": {"from": "toto", "content": "lunch ?", "deleted": false}}"#;
+        assert_eq!(read_frame(reply, "general", &mut oldest), Some(String::from("  > #5 toto : lunch ?\n#7 titi : yes")));
+        let root = r#"{"thread": 5, "message": {"id": 5, "channel": "general", "from": "toto", "content": "lunch ?"}}"#;
+        assert_eq!(read_frame(root, "general", &mut oldest), Some(String::from("#5 toto : lunch ?")));
+        let reply = r#"{"thread": 5, "message": {"id": 7, "channel": "general", "from": "titi", "content": "yes", "reply_to": 5, "quote": {"from": "toto"}}}"#;
+        assert_eq!(read_frame(reply, "general", &mut oldest), Some(String::from("  | #7 titi : yes")));
+        assert_eq!(read_frame(r#"{"thread": 5, "count": 2}"#, "general", &mut oldest), Some(String::from("2 message(s) in the thread #5")));
+        assert_eq!(oldest, Some(7));
+    }
+
+    #[test]
+    fn test_read_reactions() {
+        let mut oldest = None;
+        let frame = r#"{"id": 4, "channel": "general", "from": "toto", "content": "hello", "reactions": {"👍": 2, ":tada:": 1}}"#;
+        assert_eq!(read_frame(frame, "general", &mut oldest), Some(String::from("#4 toto : hello\n    👍 2  :tada: 1")));
+        let frame = r#"{"reacted": 4, "channel": "rust", "pseudo": "titi", "reaction": "👍", "added": true, "reactions": {"👍": 1}}"#;
+        assert_eq!(read_frame(frame, "general", &mut oldest), Some(String::from("    [rust] #4 👍 1 (titi reacted 👍)")));
+        let frame = r#"{"reacted": 4, "channel": "general", "pseudo": "titi", "reaction": "👍", "added": false, "reactions": {}}"#;
+        assert_eq!(read_frame(frame, "general", &mut oldest), Some(String::from("    #4 (titi removed 👍)")));
+    }
+
+    #[test]
+    fn test_read_mentions() {
+        assert_eq!(highlight_mentions("hi @toto, mail titi@example.com @here"), "hi \x1b[1m@toto\x1b[0m, mail titi@example.com \x1b[1m@here\x1b[0m");
+        let mut oldest = None;
+        let frame = r#"{"mention": 5, "channel": "rust", "from": "titi", "content": "@toto ready ?"}"#;
+        assert_eq!(read_frame(frame, "general", &mut oldest), Some(String::from("** titi mentioned you in rust (#5) : \x1b[1m@toto\x1b[0m ready ?")));
+        let frame = r#"{"inbox": true, "message": {"id": 5, "channel": "general", "from": "titi", "content": "hello"}}"#;
+        assert_eq!(read_frame(frame, "general", &mut oldest), Some(String::from("[general] #5 titi : hello")));
+        assert_eq!(read_frame(r#"{"inbox": true, "count": 0}"#, "general", &mut oldest), Some(String::from("No mentions")));
+        assert_eq!(oldest, None);
+    }
+
+    #[test]
+    fn test_file_transfer() {
+        let mut user = User::create_user(String::from("a_rather_long_pseudo"), encode_pwd(String::from("toto")));
+        user.set_token(String::from("abcdefghijklmnopqrstuvwxyz0123"));
+        let user = user.clone();
+        let content: Vec<u8> = (0..600).map(|x| (x % 256) as u8).collect();
+        let hash = hex::encode(sha256(&content));
+        let frames = chunk_frames(&user, &hash, &content, 256);
+        assert_eq!(frames.len(), 2);
+        // Un morceau tient dans une trame
+        assert!(frames.iter().all(|x| x.len() < BUFFER_SIZE));
+        assert_eq!(json::parse(&frames[1]).unwrap()["offset"], 512);
+
+        let path = std::env::temp_dir().join("rust_messaging_test_download").to_str().unwrap().to_string();
+        fs::remove_file(&path).ok();
+        fs::write(format!("{}.part", path), &content[..256]).unwrap();
+        let mut downloads = HashMap::from([(7, path.clone())]);
+        for (offset, chunk) in [(0, &content[..256]), (256, &content[256..512]), (512, &content[512..])] {
+            let frame = json::stringify(object!{ file: 7, offset: offset, data: hex::encode(chunk) });
+            assert_eq!(read_download(&frame, &mut downloads), Some(String::new()));
+        }
+        let end = json::stringify(object!{ file: 7, name: "data.bin", size: 600, hash: hash.as_str() });
+        assert_eq!(read_download(&end, &mut downloads), Some(format!("Saved {} (600 B)", path)));
+        assert_eq!(fs::read(&path).unwrap(), content);
+        assert_eq!(read_download(&end, &mut downloads), None);
+
+        // Une erreur d'écriture abandonne le téléchargement sans arrêter le client
+        let missing = std::env::temp_dir().join("rust_messaging_missing_dir").join("data.bin").to_str().unwrap().to_string();
+        let mut downloads = HashMap::from([(8, missing)]);
+        let frame = json::stringify(object!{ file: 8, offset: 0, data: hex::encode(&content[..256]) });
+        assert!(read_download(&frame, &mut downloads).unwrap().starts_with("Unable to write"));
+        assert!(downloads.is_empty());
+        assert_eq!(read_download(&frame, &mut downloads), Some(String::new()));
+
+        let frame = r#"{"id": 7, "channel": "general", "from": "titi", "content": "logs", "attachment": {"name": "server.log", "size": 2560, "hash": ""}}"#;
+        assert_eq!(message_line(&json::parse(frame).unwrap(), "general"), "#7 titi : logs [file server.log, 2.5 KB]");
+    }
+
+    #[test]
+    fn test_read_system() {
+        let mut oldest = None;
+        assert_eq!(read_frame(r#"{"system": "joined", "channel": "rust", "pseudo": "toto"}"#, "rust", &mut oldest), Some(String::from("-- toto joined rust --")));
+        assert_eq!(read_frame(r#"{"system": "topic", "channel": "rust", "pseudo": "toto", "topic": "async"}"#, "rust", &mut oldest), Some(String::from("-- toto changed the topic of rust: async --")));
+        let kicked = r#"{"system": "kicked", "channel": "rust", "pseudo": "titi", "by": "toto"}"#;
+        assert_eq!(read_frame(kicked, "rust", &mut oldest), Some(String::from("-- titi was kicked from rust by toto --")));
+        assert_eq!(next_active_chat(kicked, "rust", "titi"), Some(String::from("general")));
+        assert_eq!(next_active_chat(kicked, "rust", "tata"), None);
+        assert_eq!(next_active_chat(r#"{"system": "renamed", "channel": "rust", "pseudo": "toto", "name": "rs"}"#, "rust", "tata"), Some(String::from("rs")));
+        assert_eq!(next_active_chat(r#"{"system": "renamed", "channel": "rust", "pseudo": "toto", "name": "rs"}"#, "general", "tata"), None);
+    }
+
+    #[test]
+    fn test_parse_private() {
+        assert_eq!(parse_private("!p titi hello world"), Some((String::from("titi"), String::from("hello world"))));
+        assert_eq!(parse_private("!private titi hi"), Some((String::from("titi"), String::from("hi"))));
+        assert_eq!(parse_private("!p titi"), None);
+        assert_eq!(parse_private("hello"), None);
+    }
+}
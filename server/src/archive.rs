@@ -0,0 +1,180 @@
+use std::collections::{HashMap, HashSet};
+use chrono::{SecondsFormat, Utc};
+use json::{self, object, JsonValue};
+use crate::storage::{Account, Direction, Storage, StoredMessage};
+
+/// Name written in every archive, to recognize the files made by the server.
+const FORMAT: &str = "rust-messaging-archive";
+/// Version of the archive format, increased on each incompatible change.
+const VERSION: u64 = 1;
+
+/// Summary of an import, displayed in the server terminal.
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportReport {
+    /// Accounts added.
+    pub users: usize,
+    /// Accounts ignored because the pseudo is already taken.
+    pub skipped: Vec<String>,
+    /// Chats created.
+    pub channels: usize,
+    /// Messages added to the history.
+    pub messages: usize,
+    /// Messages ignored because they are already in the history.
+    pub duplicates: usize
+}
+
+/// Build the archive of the accounts, chats with their members and topic, and messages of a storage.
+/// The sessions and the waiting private messages are not exported.
+pub fn export(storage: &dyn Storage) -> JsonValue {
+    let channels = storage.channels();
+    let messages:Vec<JsonValue> = channels.iter()
+        .flat_map(|channel| storage.messages(channel, None, Direction::After, usize::MAX))
+        .map(|x| x.to_json())
+        .collect();
+    object!{
+        format: FORMAT,
+        version: VERSION,
+        exported: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        users: JsonValue::Array(storage.accounts().iter().map(|x| x.to_json()).collect()),
+        channels: JsonValue::Array(channels.iter().map(|x| object!{ name: x.as_str(), members: storage.members(x), topic: storage.topic(x) }).collect()),
+        messages: messages,
+    }
+}
+
+/// Add the content of an archive to a storage.
+/// The accounts keep their password hash and their keys, the messages their author and dates
+/// but get new identifiers. The pseudos already taken and the messages already stored are ignored.
+/// Nothing of an ignored account is given to the account of the server with the same pseudo:
+/// its memberships are dropped, its messages, reactions and mentions are credited to "pseudo@import".
+/// Returns an error if the archive can't be read by this server.
+pub fn import(storage: &mut dyn Storage, data: &JsonValue) -> Result<ImportReport, String> {
+    if data["format"].as_str() != Some(FORMAT) {
+        return Err(String::from("Not an archive of the server"));
+    }
+    match data["version"].as_u64() {
+        Some(VERSION) => (),
+        version => return Err(format!("Unsupported archive version {}", version.map(|x| x.to_string()).unwrap_or_default()))
+    }
+
+    let mut report = ImportReport::default();
+    // Comptes de l'archive présents sur le serveur à l'issue de l'import : créés, ou déjà importés avec le même mot de passe
+    let mut imported = HashSet::new();
+    let mut archived = HashSet::new();
+    for account in data["users"].members().map(Account::from_json) {
+        let pseudo = account.pseudo.clone();
+        archived.insert(pseudo.clone());
+        let same = storage.account(&pseudo).map(|x| x.pwd == account.pwd);
+        if storage.insert_account(account) {
+            report.users += 1;
+            imported.insert(pseudo);
+        } else {
+            if same == Some(true) {
+                imported.insert(pseudo.clone());
+            }
+            report.skipped.push(pseudo);
+        }
+    }
+    for channel in data["channels"].members() {
+        if let Some(name) = channel["name"].as_str().filter(|x| crate::is_channel_name(x)) {
+            if storage.create_channel(name) {
+                report.channels += 1;
+                // Le sujet du serveur cible est gardé pour les chats qui existaient déjà
+                if let Some(topic) = channel["topic"].as_str() {
+                    storage.set_topic(name, topic);
+                }
+            }
+            // Seuls les comptes importés rejoignent les chats de l'archive
+            for member in channel["members"].members().filter_map(|x| x.as_str()) {
+                if imported.contains(member) {
+                    storage.join_channel(name, member);
+                }
+            }
+        }
+    }
+    // Les messages sont remis dans l'ordre de réception pour garder des identifiants croissants avec le temps
+    let mut messages:Vec<StoredMessage> = data["messages"].members().map(StoredMessage::from_json).collect();
+    messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then(a.id.cmp(&b.id)));
+    // Les pseudos qui ne sont pas des comptes importés ne doivent pas être confondus avec un compte du serveur
+    let foreign:HashSet<String> = messages.iter()
+        .flat_map(|x| std::iter::once(&x.from).chain(x.mentions.iter()).chain(x.reactions.values().flatten()))
+        .filter(|x| !imported.contains(*x) && (archived.contains(*x) || storage.exists(x)))
+        .cloned()
+        .collect();
+    let attribute = |pseudo: &mut String| if foreign.contains(pseudo) {
+        pseudo.push_str("@import");
+    };
+    let channels = storage.channels();
+    let key = |x: &StoredMessage| (x.channel.clone(), x.from.clone(), x.timestamp.clone(), x.content.clone());
+    let mut known:HashMap<_, u64> = channels.iter()
+        .flat_map(|channel| storage.messages(channel, None, Direction::After, usize::MAX))
+        .map(|x| (key(&x), x.id))
+        .collect();
+    // Les messages reçoivent de nouveaux identifiants : les réponses suivent leur parent
+    let mut ids = HashMap::new();
+    for mut message in messages.into_iter().filter(|x| channels.contains(&x.channel)) {
+        let id = message.id;
+        message.reply_to = message.reply_to.and_then(|x| ids.get(&x).copied());
+        attribute(&mut message.from);
+        message.mentions.iter_mut().for_each(attribute);
+        message.reactions.values_mut().flatten().for_each(attribute);
+        match known.get(&key(&message)) {
+            Some(existing) => {
+                ids.insert(id, *existing);
+                report.duplicates += 1;
+            }
+            None => {
+                let message = storage.import_message(message);
+                known.insert(key(&message), message.id);
+                ids.insert(id, message.id);
+                report.messages += 1;
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod unit_testing {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_export_then_import() {
+        let mut source = MemoryStorage::new();
+        source.insert_account(Account::new(String::from("toto"), String::from("hash")));
+        source.insert_account(Account::new(String::from("titi"), String::from("hash")));
+        source.create_channel("rust");
+        source.join_channel("rust", "titi");
+        source.append_message("general", "toto", "hello", None, None, vec![]);
+        source.append_message("rust", "titi", "borrow", None, None, vec![]);
+        source.append_message("general", "titi", "hi", None, Some(1), vec![]);
+        let archive = json::parse(&json::stringify(export(&source))).unwrap();
+
+        let mut target = MemoryStorage::new();
+        target.insert_account(Account::new(String::from("titi"), String::from("other")));
+        target.append_message("general", "titi", "first", None, None, vec![]);
+        let report = import(&mut target, &archive).unwrap();
+        assert_eq!(report, ImportReport { users: 1, skipped: vec![String::from("titi")], channels: 1, messages: 3, duplicates: 0 });
+        assert_eq!(target.account("toto").unwrap().pwd, "hash");
+        assert_eq!(target.account("titi").unwrap().pwd, "other");
+        // Le compte titi du serveur cible n'hérite pas des chats du titi de l'archive
+        assert!(target.members("rust").is_empty());
+        let messages = target.last_messages("rust", 10);
+        // Ses messages ne sont pas attribués au titi du serveur cible
+        assert_eq!(messages[0].to_line(), "titi@import : borrow");
+        assert_eq!(messages[0].id, 3);
+        // La réponse désigne le nouvel identifiant de son parent
+        assert_eq!(target.last_messages("general", 1)[0].reply_to, Some(2));
+        assert_eq!(import(&mut target, &archive).unwrap().duplicates, 3);
+        assert_eq!(target.message_count(), 4);
+
+        let mut archive = archive;
+        archive["channels"].push(object!{ name: "bad name", members: ["toto"] }).unwrap();
+        archive["messages"].push(object!{ id: 9, channel: "bad name", from: "toto", content: "x", timestamp: "2024-01-01T00:00:00Z" }).unwrap();
+        assert_eq!(import(&mut target, &archive).unwrap().channels, 0);
+        assert!(target.channels().iter().all(|x| x != "bad name"));
+
+        assert!(import(&mut target, &object!{ format: FORMAT, version: 2 }).is_err());
+        assert!(import(&mut target, &object!{ users: [] }).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use std::{fs::{self, OpenOptions}, io::Write, net::SocketAddr};
+use chrono::{SecondsFormat, Utc};
+use json::{self, object, JsonValue};
+use crate::config::AuditConfig;
+
+/// Append-only log of the authentication and account events, one JSON object per line.
+/// The file is rotated when it reaches the configured size.
+pub struct AuditLog {
+    config: AuditConfig
+}
+
+impl AuditLog {
+    pub fn new(config: AuditConfig) -> AuditLog {
+        AuditLog { config }
+    }
+
+    /// Record an event.
+    /// `event` is the kind of action (login, register...), `reason` explains the outcome.
+    pub fn record(&mut self, addr: &SocketAddr, event: &str, username: &str, success: bool, reason: &str) {
+        let entry = object!{
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            address: addr.to_string(),
+            event: event,
+            username: username,
+            outcome: if success { "success" } else { "failure" },
+            reason: reason,
+        };
+
+        self.rotate();
+        let file = OpenOptions::new().create(true).append(true).open(&self.config.file);
+        match file {
+            Ok(mut file) => {
+                if writeln!(file, "{}", json::stringify(entry)).is_err() {
+                    println!("Unable to write in audit log {}", self.config.file);
+                }
+            }
+            Err(err) => println!("Unable to open audit log {}: {}", self.config.file, err)
+        }
+    }
+
+    /// Rename the log into "file.1" when it is full, the older files are shifted
+    /// and the ones beyond the configured count are deleted.
+    fn rotate(&self) {
+        let size = fs::metadata(&self.config.file).map(|x| x.len()).unwrap_or(0);
+        if self.config.max_size == 0 || size < self.config.max_size {
+            return;
+        }
+        if self.config.max_files == 0 {
+            fs::remove_file(&self.config.file).ok();
+            return;
+        }
+        fs::remove_file(self.rotated(self.config.max_files)).ok();
+        for index in (1..self.config.max_files).rev() {
+            fs::rename(self.rotated(index), self.rotated(index + 1)).ok();
+        }
+        fs::rename(&self.config.file, self.rotated(1)).ok();
+    }
+
+    /// Path of a rotated file.
+    fn rotated(&self, index: u32) -> String {
+        format!("{}.{}", self.config.file, index)
+    }
+
+    /// Get the last `limit` events, optionally only the ones of a user.
+    /// Returns the events from the oldest to the newest.
+    pub fn query(&self, username: Option<&str>, limit: usize) -> Vec<JsonValue> {
+        let mut events = vec![];
+        // Lecture du fichier courant puis des fichiers archivés, du plus récent au plus ancien
+        let files = std::iter::once(self.config.file.clone()).chain((1..=self.config.max_files).map(|x| self.rotated(x)));
+        for file in files {
+            let content = fs::read_to_string(file).unwrap_or_default();
+            for line in content.lines().rev() {
+                let event = match json::parse(line) {
+                    Ok(event) => event,
+                    Err(_) => continue
+                };
+                if username.map(|x| event["username"] == x).unwrap_or(true) {
+                    events.push(event);
+                }
+                if events.len() >= limit {
+                    events.reverse();
+                    return events;
+                }
+            }
+        }
+        events.reverse();
+        events
+    }
+}
+
+#[cfg(test)]
+mod unit_testing {
+    use super::*;
+
+    #[test]
+    fn test_record_rotate_and_query() {
+        let file = std::env::temp_dir().join("rust_messaging_test_audit.log");
+        let file = file.to_str().unwrap().to_string();
+        for index in 0..4 {
+            fs::remove_file(format!("{}.{}", file, index)).ok();
+        }
+        fs::remove_file(&file).ok();
+
+        let mut audit = AuditLog::new(AuditConfig { file: file.clone(), max_size: 200, max_files: 2 });
+        let addr:SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        for index in 0..10 {
+            audit.record(&addr, "login", if index % 2 == 0 { "toto" } else { "titi" }, index % 3 == 0, "test");
+        }
+
+        assert!(fs::metadata(format!("{}.1", file)).is_ok());
+        assert!(fs::metadata(format!("{}.3", file)).is_err());
+        let events = audit.query(Some("titi"), 2);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1]["username"], "titi");
+        assert_eq!(events[1]["outcome"], "success");
+        assert_eq!(events[1]["address"], "127.0.0.1:1234");
+    }
+}
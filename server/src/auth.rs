@@ -0,0 +1,379 @@
+use std::{fs::{self, OpenOptions}, io::{BufRead, BufReader, Read, Write}, net::{TcpStream, ToSocketAddrs}, process::{Command, Stdio}, sync::{Arc, Mutex}, thread, time::{Duration, Instant}};
+use argon2::{self, Config};
+use json::{self, object};
+use openssl::ssl::{SslConnector, SslMethod};
+use rand::{Rng, thread_rng, distributions::Alphanumeric};
+use crate::{config::AuthConfig, storage::{Account, Storage}};
+
+/// Time given to an external command or HTTP service to answer, the login is refused after it.
+pub const HOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Place where the passwords of the users are checked.
+/// The password received is the hash computed by the client, never the password typed by the user.
+/// The backends are shared by the connections without a global lock, a slow check only delays its own connection.
+pub trait AuthBackend: Send + Sync {
+    /// Name of the backend, displayed in the server logs.
+    fn name(&self) -> &str;
+
+    /// Verify the password of the user.
+    /// Returns true if the credentials are valid, else false.
+    fn authenticate(&self, pseudo: &str, pwd: &str) -> bool;
+
+    /// Create a new account.
+    /// Returns false if the pseudo is taken or the backend doesn't accept registrations.
+    fn register(&self, _pseudo: &str, _pwd: &str) -> bool {
+        false
+    }
+
+    /// Replace the password of a user.
+    /// Returns false if the backend doesn't manage the password of the user.
+    fn change_password(&self, _pseudo: &str, _pwd: &str) -> bool {
+        false
+    }
+
+    /// Delete the credentials of a user.
+    /// Returns false if the backend doesn't manage the credentials of the user.
+    fn delete(&self, _pseudo: &str) -> bool {
+        false
+    }
+}
+
+/// Build the backend selected in the configuration.
+/// The local backend keeps the passwords inside the storage of the server.
+pub fn from_config(config: &AuthConfig, storage: Arc<Mutex<Box<dyn Storage>>>) -> Arc<dyn AuthBackend> {
+    match config {
+        AuthConfig::Local => Arc::new(LocalBackend::new(storage)),
+        AuthConfig::Htpasswd(path) => Arc::new(HtpasswdBackend::new(path.clone())),
+        AuthConfig::Command(command) => Arc::new(CommandBackend::new(command.clone(), HOOK_TIMEOUT)),
+        AuthConfig::Http(url) => Arc::new(HttpBackend::new(url.clone(), HOOK_TIMEOUT))
+    }
+}
+
+/// Return a salted hash of the password.
+pub fn encode_pwd(pwd: &str) -> String {
+    let salt:String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect();
+    argon2::hash_encoded(pwd.as_bytes(), salt.as_bytes(), &Config::default()).unwrap()
+}
+
+/// Verify the match between the pwd and the hash.
+/// Returns true if match, else false.
+pub fn verify_pwd(pwd: &str, hash: &str) -> bool {
+    argon2::verify_encoded(hash, pwd.as_bytes()).unwrap_or(false)
+}
+
+/// Passwords kept in the storage of the server.
+pub struct LocalBackend {
+    storage: Arc<Mutex<Box<dyn Storage>>>
+}
+
+impl LocalBackend {
+    pub fn new(storage: Arc<Mutex<Box<dyn Storage>>>) -> LocalBackend {
+        LocalBackend { storage }
+    }
+}
+
+impl AuthBackend for LocalBackend {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    fn authenticate(&self, pseudo: &str, pwd: &str) -> bool {
+        // Le hachage est vérifié après avoir libéré le storage
+        let account = self.storage.lock().unwrap().account(pseudo);
+        match account {
+            Some(account) => verify_pwd(pwd, &account.pwd),
+            None => false
+        }
+    }
+
+    fn register(&self, pseudo: &str, pwd: &str) -> bool {
+        self.storage.lock().unwrap().insert_account(Account::new(pseudo.to_string(), encode_pwd(pwd)))
+    }
+
+    fn change_password(&self, pseudo: &str, pwd: &str) -> bool {
+        self.storage.lock().unwrap().set_pwd(pseudo, encode_pwd(pwd))
+    }
+
+    fn delete(&self, pseudo: &str) -> bool {
+        // Le compte lui-même est supprimé du storage par le serveur
+        self.storage.lock().unwrap().exists(pseudo)
+    }
+}
+
+/// Accounts read from a file of "pseudo:hash" lines.
+/// The file is read on each login so it can be edited while the server runs.
+pub struct HtpasswdBackend {
+    path: String,
+    /// Held while the file is modified, two changes must not rewrite it at the same time.
+    writing: Mutex<()>
+}
+
+impl HtpasswdBackend {
+    pub fn new(path: String) -> HtpasswdBackend {
+        HtpasswdBackend { path, writing: Mutex::new(()) }
+    }
+
+    /// Find the hash of the pseudo inside the file.
+    fn find(&self, pseudo: &str) -> Option<String> {
+        let content = fs::read_to_string(&self.path).unwrap_or_default();
+        content.lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| *name == pseudo)
+            .map(|(_, hash)| hash.to_string())
+    }
+
+    /// Rewrite the file, the line of the pseudo is replaced by the given hash or removed.
+    /// Returns false if the pseudo isn't in the file.
+    fn rewrite(&self, pseudo: &str, hash: Option<String>) -> bool {
+        let _writing = self.writing.lock().unwrap();
+        if self.find(pseudo).is_none() {
+            return false;
+        }
+        let content = fs::read_to_string(&self.path).unwrap_or_default();
+        let mut lines = vec![];
+        for line in content.lines() {
+            match line.split_once(':') {
+                Some((name, _)) if name == pseudo => {
+                    if let Some(hash) = &hash {
+                        lines.push(format!("{}:{}", pseudo, hash));
+                    }
+                }
+                _ => lines.push(line.to_string())
+            }
+        }
+        let tmp = format!("{}.tmp", self.path);
+        fs::write(&tmp, lines.iter().map(|x| format!("{}\n", x)).collect::<String>()).is_ok() && fs::rename(&tmp, &self.path).is_ok()
+    }
+}
+
+impl AuthBackend for HtpasswdBackend {
+    fn name(&self) -> &str {
+        "htpasswd"
+    }
+
+    fn authenticate(&self, pseudo: &str, pwd: &str) -> bool {
+        match self.find(pseudo) {
+            Some(hash) => verify_pwd(pwd, &hash),
+            None => false
+        }
+    }
+
+    fn register(&self, pseudo: &str, pwd: &str) -> bool {
+        let _writing = self.writing.lock().unwrap();
+        if pseudo.contains(':') || pseudo.contains('\n') || self.find(pseudo).is_some() {
+            return false;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&self.path);
+        match file {
+            Ok(mut file) => writeln!(file, "{}:{}", pseudo, encode_pwd(pwd)).is_ok(),
+            Err(_) => false
+        }
+    }
+
+    fn change_password(&self, pseudo: &str, pwd: &str) -> bool {
+        self.rewrite(pseudo, Some(encode_pwd(pwd)))
+    }
+
+    fn delete(&self, pseudo: &str) -> bool {
+        self.rewrite(pseudo, None)
+    }
+}
+
+/// Accounts checked by an external program, the pseudo is given as argument
+/// and the password hash on the standard input. The login is accepted if the program succeeds
+/// before the timeout, a program still running is killed.
+pub struct CommandBackend {
+    command: String,
+    timeout: Duration
+}
+
+impl CommandBackend {
+    pub fn new(command: String, timeout: Duration) -> CommandBackend {
+        CommandBackend { command, timeout }
+    }
+}
+
+impl AuthBackend for CommandBackend {
+    fn name(&self) -> &str {
+        "command"
+    }
+
+    fn authenticate(&self, pseudo: &str, pwd: &str) -> bool {
+        let child = Command::new(&self.command)
+            .arg(pseudo)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(err) => {
+                println!("Unable to run {}: {}", self.command, err);
+                return false;
+            }
+        };
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(pwd.as_bytes()).ok();
+        }
+        // La connexion attend le programme : un programme bloqué est arrêté après le délai
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) => return status.success(),
+                Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(20)),
+                Ok(None) => {
+                    println!("{} didn't answer in time for {}", self.command, pseudo);
+                    child.kill().ok();
+                    child.wait().ok();
+                    return false;
+                }
+                Err(_) => return false
+            }
+        }
+    }
+}
+
+/// Accounts checked by an HTTP service, the pseudo and the password hash are posted as JSON
+/// (`{"username": "...", "password": "..."}`) to the URL. The login is accepted if the service answers
+/// with a 2xx status before the timeout.
+pub struct HttpBackend {
+    url: String,
+    timeout: Duration
+}
+
+impl HttpBackend {
+    pub fn new(url: String, timeout: Duration) -> HttpBackend {
+        HttpBackend { url, timeout }
+    }
+
+    /// Post the body to the URL.
+    /// Returns the HTTP status of the answer.
+    fn post(&self, body: &str) -> Result<u16, String> {
+        let (https, rest) = match (self.url.strip_prefix("https://"), self.url.strip_prefix("http://")) {
+            (Some(rest), _) => (true, rest),
+            (None, Some(rest)) => (false, rest),
+            _ => return Err(String::from("the URL must start with http:// or https://"))
+        };
+        let (host, path) = match rest.split_once('/') {
+            Some((host, path)) => (host, format!("/{}", path)),
+            None => (rest, String::from("/"))
+        };
+        let address = if host.contains(':') { host.to_string() } else { format!("{}:{}", host, if https { 443 } else { 80 }) };
+        let address = address.to_socket_addrs().map_err(|err| err.to_string())?.next().ok_or("unknown host")?;
+        let stream = TcpStream::connect_timeout(&address, self.timeout).map_err(|err| err.to_string())?;
+        stream.set_read_timeout(Some(self.timeout)).map_err(|err| err.to_string())?;
+        stream.set_write_timeout(Some(self.timeout)).map_err(|err| err.to_string())?;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            path, host, body.len(), body
+        );
+        if https {
+            let connector = SslConnector::builder(SslMethod::tls()).map_err(|err| err.to_string())?.build();
+            let name = host.split(':').next().unwrap_or(host);
+            let stream = connector.connect(name, stream).map_err(|err| err.to_string())?;
+            read_status(stream, &request)
+        } else {
+            read_status(stream, &request)
+        }
+    }
+}
+
+/// Send an HTTP request on the stream.
+/// Returns the status of the answer.
+fn read_status<S: Read + Write>(mut stream: S, request: &str) -> Result<u16, String> {
+    stream.write_all(request.as_bytes()).map_err(|err| err.to_string())?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).map_err(|err| err.to_string())?;
+    // Ligne de statut : "HTTP/1.1 200 OK"
+    line.split_whitespace().nth(1).and_then(|x| x.parse().ok()).ok_or(format!("invalid answer {:?}", line.trim()))
+}
+
+impl AuthBackend for HttpBackend {
+    fn name(&self) -> &str {
+        "http"
+    }
+
+    fn authenticate(&self, pseudo: &str, pwd: &str) -> bool {
+        match self.post(&json::stringify(object!{ username: pseudo, password: pwd })) {
+            Ok(status) => (200..300).contains(&status),
+            Err(err) => {
+                println!("Unable to reach {}: {}", self.url, err);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_testing {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_local_backend() {
+        let backend = LocalBackend::new(Arc::new(Mutex::new(Box::new(MemoryStorage::new()))));
+        assert!(backend.register("toto", "pwd"));
+        assert!(!backend.register("toto", "other"));
+        assert!(backend.authenticate("toto", "pwd"));
+        assert!(!backend.authenticate("toto", "other"));
+        assert!(!backend.authenticate("titi", "pwd"));
+        assert!(backend.change_password("toto", "other"));
+        assert!(backend.authenticate("toto", "other"));
+        assert!(!backend.change_password("titi", "pwd"));
+    }
+
+    #[test]
+    fn test_htpasswd_backend() {
+        let path = std::env::temp_dir().join("rust_messaging_test_htpasswd");
+        fs::remove_file(&path).ok();
+        let backend = HtpasswdBackend::new(path.to_str().unwrap().to_string());
+        assert!(backend.register("toto", "pwd"));
+        assert!(!backend.register("toto", "pwd"));
+        assert!(!backend.register("ti:ti", "pwd"));
+        assert!(backend.authenticate("toto", "pwd"));
+        assert!(!backend.authenticate("toto", "other"));
+        assert!(backend.register("titi", "pwd"));
+        assert!(backend.change_password("toto", "other"));
+        assert!(backend.authenticate("toto", "other"));
+        assert!(backend.delete("toto"));
+        assert!(!backend.authenticate("toto", "other"));
+        assert!(backend.authenticate("titi", "pwd"));
+    }
+
+    #[test]
+    fn test_command_backend() {
+        let backend = CommandBackend::new(String::from("true"), HOOK_TIMEOUT);
+        assert!(backend.authenticate("toto", "pwd"));
+        assert!(!backend.register("toto", "pwd"));
+        let backend = CommandBackend::new(String::from("false"), HOOK_TIMEOUT);
+        assert!(!backend.authenticate("toto", "pwd"));
+        // Un programme qui ne répond pas est arrêté : "sleep 10" reçoit le pseudo en argument
+        let backend = CommandBackend::new(String::from("sleep"), Duration::from_millis(200));
+        let start = Instant::now();
+        assert!(!backend.authenticate("10", "pwd"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_http_backend() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/check", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming().take(2) {
+                let mut stream = stream.unwrap();
+                let mut buff = vec![0; 1024];
+                let read = stream.read(&mut buff).unwrap();
+                let request = String::from_utf8_lossy(&buff[..read]).to_string();
+                let status = if request.starts_with("POST /check ") && request.ends_with(r#"{"username":"toto","password":"pwd"}"#) { "200 OK" } else { "403 Forbidden" };
+                stream.write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).as_bytes()).unwrap();
+            }
+        });
+        let backend = HttpBackend::new(url, HOOK_TIMEOUT);
+        assert!(backend.authenticate("toto", "pwd"));
+        assert!(!backend.authenticate("toto", "other"));
+        assert!(!HttpBackend::new(String::from("ftp://example.com"), HOOK_TIMEOUT).authenticate("toto", "pwd"));
+    }
+}
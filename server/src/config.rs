@@ -0,0 +1,270 @@
+use std::{collections::HashMap, env, fs};
+use json::{self, JsonValue};
+
+/// Settings of the server, read from the JSON file given as first argument.
+/// Every setting is optional, a missing file or key keeps the default behaviour.
+pub struct Config {
+    /// Certificate authentication, when set the connect port only accepts TLS clients.
+    pub tls: Option<TlsConfig>,
+    /// Backend checking the passwords of the users.
+    pub auth: AuthConfig,
+    /// Closed registration, a valid invite code is required to create an account.
+    pub registration_closed: bool,
+    /// Audit log of the authentication events.
+    pub audit: AuditConfig,
+    /// Time the messages are kept.
+    pub retention: RetentionConfig,
+    /// Place where the state of the server is kept.
+    pub storage: StorageConfig,
+    /// Keys encrypting the data directory, None to keep it in clear.
+    pub encryption: Option<EncryptionConfig>,
+    /// Directory where the server keeps its data between two runs.
+    pub data_dir: String,
+    /// Number of recent messages sent to a client entering a chat.
+    pub backlog: usize,
+    /// Maximum number of private messages kept for a disconnected user.
+    pub queue_limit: usize,
+    /// Pseudos of the users allowed to rename the chats and to kick their members.
+    pub moderators: Vec<String>,
+    /// Maximum size in bytes of a file sent in a chat.
+    pub max_file_size: u64
+}
+
+/// Authentication backends available, see the auth module.
+#[derive(Debug, PartialEq)]
+pub enum AuthConfig {
+    /// Accounts kept in memory.
+    Local,
+    /// Accounts stored in the given htpasswd-style file.
+    Htpasswd(String),
+    /// Accounts checked by the given program.
+    Command(String),
+    /// Accounts checked by the HTTP service at the given URL.
+    Http(String)
+}
+
+/// Storage backends available, see the storage module.
+#[derive(Debug, PartialEq)]
+pub enum StorageConfig {
+    /// State lost when the server stops.
+    Memory,
+    /// State saved in the data directory.
+    File,
+    /// Log of the changes kept in the data directory, with a snapshot every given number of events.
+    Events(u64)
+}
+
+/// Keys encrypting the stored data, the first one encrypts the new records
+/// and the others are only kept to read the records encrypted before a rotation.
+#[derive(Debug, PartialEq)]
+pub struct EncryptionConfig {
+    pub keys: Vec<KeyConfig>,
+    /// Accept the records written in clear, to encrypt an existing data directory once.
+    pub migrate: bool
+}
+
+/// Key given by the operator.
+#[derive(Debug, PartialEq)]
+pub struct KeyConfig {
+    /// Name of the key, written next to each record it encrypts.
+    pub id: String,
+    /// Secret from which the key is derived.
+    pub secret: KeySource
+}
+
+/// Origin of the secret of a key.
+#[derive(Debug, PartialEq)]
+pub enum KeySource {
+    /// Passphrase written in the configuration.
+    Secret(String),
+    /// File whose content is the secret.
+    File(String)
+}
+
+/// Location and rotation of the audit log.
+#[derive(Debug, PartialEq)]
+pub struct AuditConfig {
+    /// Path of the current log file.
+    pub file: String,
+    /// Size in bytes from which the file is rotated, 0 to never rotate.
+    pub max_size: u64,
+    /// Number of rotated files kept.
+    pub max_files: u32
+}
+
+/// Longest retention in days accepted in the configuration, 100 years.
+const MAX_RETENTION_DAYS: u64 = 36500;
+
+/// Retention of the messages, the messages older than the retention of their chat are purged.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetentionConfig {
+    /// Days the messages are kept, None to keep them forever.
+    pub days: Option<u64>,
+    /// Retention of some chats in days, replacing the one of the server, 0 to keep the messages forever.
+    pub channels: HashMap<String, u64>,
+    /// Seconds between two purges of the expired messages.
+    pub sweep_interval: u64
+}
+
+/// Files used by the certificate authentication mode.
+pub struct TlsConfig {
+    /// PEM file of the CA signing the client certificates.
+    pub ca: String,
+    /// PEM certificate chain presented by the server.
+    pub certificate: String,
+    /// PEM private key of the server certificate.
+    pub key: String
+}
+
+impl Config {
+    /// Load the configuration file given on the command line.
+    /// Returns the default configuration if no file is given.
+    pub fn load() -> Config {
+        match env::args().nth(1) {
+            Some(path) => {
+                let content = fs::read_to_string(&path).expect("Unable to read configuration file");
+                let data = json::parse(content.as_str()).expect("Invalid configuration file");
+                println!("Configuration loaded from {}", path);
+                Config::from_json(&data)
+            }
+            None => Config::from_json(&JsonValue::Null)
+        }
+    }
+
+    /// Build the configuration from its JSON representation.
+    pub fn from_json(data: &JsonValue) -> Config {
+        let tls = &data["tls"];
+        let auth = &data["auth"];
+        let audit = &data["audit"];
+        let retention = &data["retention"];
+        let encryption = &data["encryption"];
+        Config {
+            tls: if tls.is_object() {
+                Some(TlsConfig {
+                    ca: tls["ca"].to_string(),
+                    certificate: tls["certificate"].to_string(),
+                    key: tls["key"].to_string()
+                })
+            } else {
+                None
+            },
+            auth: match auth["backend"].as_str().unwrap_or("local") {
+                "local" => AuthConfig::Local,
+                "htpasswd" => AuthConfig::Htpasswd(auth["file"].to_string()),
+                "command" => AuthConfig::Command(auth["command"].to_string()),
+                "http" => AuthConfig::Http(auth["url"].to_string()),
+                other => panic!("Unknown authentication backend: {}", other)
+            },
+            registration_closed: data["registration"] == "closed",
+            audit: AuditConfig {
+                file: audit["file"].as_str().unwrap_or("audit.log").to_string(),
+                max_size: audit["max_size"].as_u64().unwrap_or(1024 * 1024),
+                max_files: audit["max_files"].as_u32().unwrap_or(5)
+            },
+            retention: RetentionConfig {
+                days: retention["days"].as_u64().map(retention_days),
+                channels: retention["channels"].entries().filter_map(|(name, days)| Some((name.to_string(), retention_days(days.as_u64()?)))).collect(),
+                sweep_interval: retention["sweep_interval"].as_u64().unwrap_or(10)
+            },
+            storage: match data["storage"].as_str().unwrap_or("file") {
+                "file" => StorageConfig::File,
+                "memory" => StorageConfig::Memory,
+                "events" => StorageConfig::Events(data["snapshot_interval"].as_u64().unwrap_or(1000)),
+                other => panic!("Unknown storage backend: {}", other)
+            },
+            encryption: if encryption.is_object() {
+                Some(EncryptionConfig {
+                    keys: encryption["keys"].members().map(|key| {
+                        let id = key["id"].as_str().expect("Encryption key without id");
+                        // L'identifiant est écrit dans chaque enregistrement, séparé par ':'
+                        if id.is_empty() || id.contains(':') {
+                            panic!("Invalid encryption key id: {}", id);
+                        }
+                        KeyConfig {
+                            id: id.to_string(),
+                            secret: match (key["secret"].as_str(), key["file"].as_str()) {
+                                (Some(secret), None) => KeySource::Secret(secret.to_string()),
+                                (None, Some(file)) => KeySource::File(file.to_string()),
+                                _ => panic!("Encryption key {} needs either a secret or a file", id)
+                            }
+                        }
+                    }).collect(),
+                    migrate: encryption["migrate"].as_bool().unwrap_or(false)
+                })
+            } else {
+                None
+            },
+            data_dir: data["data_dir"].as_str().unwrap_or("data").to_string(),
+            backlog: data["backlog"].as_usize().unwrap_or(20),
+            queue_limit: data["queue_limit"].as_usize().unwrap_or(100),
+            moderators: data["moderators"].members().filter_map(|x| x.as_str()).map(|x| x.to_string()).collect(),
+            max_file_size: data["max_file_size"].as_u64().unwrap_or(1024 * 1024)
+        }
+    }
+}
+
+/// Check a retention read from the configuration, a retention too long to compute dates with is refused.
+fn retention_days(days: u64) -> u64 {
+    if days > MAX_RETENTION_DAYS {
+        panic!("Invalid retention of {} days, at most {} days", days, MAX_RETENTION_DAYS);
+    }
+    days
+}
+
+#[cfg(test)]
+mod unit_testing {
+    use super::*;
+
+    #[test]
+    fn test_from_json() {
+        assert!(Config::from_json(&JsonValue::Null).tls.is_none());
+        assert_eq!(Config::from_json(&JsonValue::Null).auth, AuthConfig::Local);
+        assert!(!Config::from_json(&JsonValue::Null).registration_closed);
+        assert_eq!(Config::from_json(&JsonValue::Null).data_dir, "data");
+        assert_eq!(Config::from_json(&JsonValue::Null).storage, StorageConfig::File);
+        assert_eq!(Config::from_json(&JsonValue::Null).backlog, 20);
+        assert_eq!(Config::from_json(&JsonValue::Null).queue_limit, 100);
+        assert_eq!(Config::from_json(&JsonValue::Null).max_file_size, 1024 * 1024);
+        assert_eq!(Config::from_json(&JsonValue::Null).audit, AuditConfig { file: String::from("audit.log"), max_size: 1024 * 1024, max_files: 5 });
+
+        let config = Config::from_json(&json::parse(r#"{"tls": {"ca": "ca.pem", "certificate": "server.pem", "key": "server.key"}}"#).unwrap());
+        let tls = config.tls.unwrap();
+        assert_eq!(tls.ca, "ca.pem");
+        assert_eq!(tls.certificate, "server.pem");
+        assert_eq!(tls.key, "server.key");
+
+        let config = Config::from_json(&json::parse(r#"{"auth": {"backend": "htpasswd", "file": "users"}}"#).unwrap());
+        assert_eq!(config.auth, AuthConfig::Htpasswd(String::from("users")));
+        let config = Config::from_json(&json::parse(r#"{"auth": {"backend": "http", "url": "https://auth.example.com/check"}}"#).unwrap());
+        assert_eq!(config.auth, AuthConfig::Http(String::from("https://auth.example.com/check")));
+
+        let config = Config::from_json(&json::parse(r#"{"registration": "closed", "moderators": ["toto"]}"#).unwrap());
+        assert!(config.registration_closed);
+        assert_eq!(config.moderators, vec![String::from("toto")]);
+
+        let config = Config::from_json(&json::parse(r#"{"retention": {"days": 30, "channels": {"general": 7}}}"#).unwrap());
+        assert_eq!(config.retention.days, Some(30));
+        assert_eq!(config.retention.channels["general"], 7);
+        assert_eq!(config.retention.sweep_interval, 10);
+        assert!(std::panic::catch_unwind(|| Config::from_json(&json::parse(r#"{"retention": {"days": 36501}}"#).unwrap())).is_err());
+        assert!(std::panic::catch_unwind(|| Config::from_json(&json::parse(r#"{"retention": {"channels": {"general": 18446744073709551615}}}"#).unwrap())).is_err());
+
+        let config = Config::from_json(&json::parse(r#"{"storage": "memory"}"#).unwrap());
+        assert_eq!(config.storage, StorageConfig::Memory);
+        assert!(config.encryption.is_none());
+
+        let config = Config::from_json(&json::parse(r#"{"storage": "events", "snapshot_interval": 50}"#).unwrap());
+        assert_eq!(config.storage, StorageConfig::Events(50));
+
+        let config = Config::from_json(&json::parse(r#"{"encryption": {"keys": [{"id": "new", "file": "key"}, {"id": "old", "secret": "pass"}]}}"#).unwrap());
+        assert_eq!(config.encryption.unwrap(), EncryptionConfig {
+            keys: vec![
+                KeyConfig { id: String::from("new"), secret: KeySource::File(String::from("key")) },
+                KeyConfig { id: String::from("old"), secret: KeySource::Secret(String::from("pass")) }
+            ],
+            migrate: false
+        });
+        let config = Config::from_json(&json::parse(r#"{"encryption": {"keys": [{"id": "new", "secret": "pass"}], "migrate": true}}"#).unwrap());
+        assert!(config.encryption.unwrap().migrate);
+    }
+}
@@ -0,0 +1,98 @@
+use std::{fs, io::{self, BufRead}, sync::{Arc, Mutex}, thread, time::{Duration, SystemTime}};
+use crate::{archive, audit::AuditLog, invite::Invites, storage::Storage};
+
+/// Start the thread reading the admin commands typed in the server terminal.
+pub fn start(invites: Arc<Mutex<Invites>>, audit: Arc<Mutex<AuditLog>>, storage: Arc<Mutex<Box<dyn Storage>>>) {
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break
+            };
+            let args:Vec<&str> = line.split_whitespace().collect();
+
+            match args.first().copied().unwrap_or("") {
+                "" => (),
+                "!i" | "!invite" => {
+                    let uses = args.get(1).and_then(|x| x.parse().ok()).unwrap_or(1);
+                    let hours:u64 = args.get(2).and_then(|x| x.parse().ok()).unwrap_or(24);
+                    // Une durée trop longue pour être représentée est refusée
+                    let code = hours.checked_mul(3600).and_then(|x| invites.lock().unwrap().create(uses, Duration::from_secs(x)));
+                    match code {
+                        Some(code) => println!("Invite code {} ({} uses, valid {} hours)", code, if uses == 0 { String::from("unlimited") } else { uses.to_string() }, hours),
+                        None => println!("Invalid validity of {} hours, the code is not created", hours)
+                    }
+                }
+                "!invites" => {
+                    for (code, invite) in invites.lock().unwrap().list() {
+                        let left = invite.expires.duration_since(SystemTime::now()).unwrap_or_default();
+                        let uses = invite.uses_left.map(|x| x.to_string()).unwrap_or(String::from("unlimited"));
+                        println!("{} : {} uses left, expires in {} minutes", code, uses, left.as_secs() / 60);
+                    }
+                }
+                "!revoke" => {
+                    let code = args.get(1).copied().unwrap_or("");
+                    if invites.lock().unwrap().revoke(code) {
+                        println!("Invite code {} revoked", code);
+                    } else {
+                        println!("Unknown invite code {}", code);
+                    }
+                }
+                "!a" | "!audit" => {
+                    // L'utilisateur est optionnel : "!audit 50" ou "!audit alice 50"
+                    let (username, limit) = match args.get(1).map(|x| x.parse::<usize>()) {
+                        Some(Ok(limit)) => (None, limit),
+                        Some(Err(_)) => (args.get(1).copied(), args.get(2).and_then(|x| x.parse().ok()).unwrap_or(20)),
+                        None => (None, 20)
+                    };
+                    for event in audit.lock().unwrap().query(username, limit) {
+                        println!("{} {} {} {} {} ({})", event["timestamp"], event["address"], event["event"], event["username"], event["outcome"], event["reason"]);
+                    }
+                }
+                "!export" => match args.get(1) {
+                    Some(file) => {
+                        let data = archive::export(&**storage.lock().unwrap());
+                        match fs::write(file, json::stringify_pretty(data, 2)) {
+                            Ok(_) => println!("Server data exported to {}", file),
+                            Err(err) => println!("Unable to write {}: {}", file, err)
+                        }
+                    }
+                    None => println!("Usage: !export <file>")
+                },
+                "!import" => match args.get(1) {
+                    Some(file) => {
+                        let data = fs::read_to_string(file).map_err(|x| x.to_string())
+                            .and_then(|x| json::parse(&x).map_err(|x| x.to_string()))
+                            .and_then(|x| archive::import(&mut **storage.lock().unwrap(), &x));
+                        match data {
+                            Ok(report) => {
+                                println!("{} users, {} chats and {} messages imported from {}", report.users, report.channels, report.messages, file);
+                                if report.duplicates > 0 {
+                                    println!("{} messages already in the history, not imported", report.duplicates);
+                                }
+                                if !report.skipped.is_empty() {
+                                    println!("Pseudos already taken, accounts not imported: {}", report.skipped.join(", "));
+                                }
+                            }
+                            Err(err) => println!("Unable to import {}: {}", file, err)
+                        }
+                    }
+                    None => println!("Usage: !import <file>")
+                },
+                "!h" | "!help" => display_help(),
+                other => println!("Unknown command {}, type !help", other)
+            }
+        }
+    });
+}
+
+/// Display the admin commands.
+fn display_help() {
+    println!("!h or !help                  -> display the help");
+    println!("!i or !invite [uses] [hours] -> create an invite code, 0 uses for unlimited (default 1 use, 24 hours)");
+    println!("!invites                     -> list the invite codes still valid");
+    println!("!revoke <code>               -> delete an invite code");
+    println!("!a or !audit [user] [count]  -> display the last authentication events (default 20)");
+    println!("!export <file>               -> write the users, chats and messages to an archive");
+    println!("!import <file>               -> add the content of an archive to the server");
+}
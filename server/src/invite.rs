@@ -0,0 +1,111 @@
+use std::{collections::HashMap, time::{Duration, SystemTime}};
+use rand::{Rng, thread_rng, distributions::Alphanumeric};
+
+/// Invitation allowing to register while the registration is closed.
+pub struct Invite {
+    /// Number of registrations left, None if the code can be used without limit.
+    pub uses_left: Option<u32>,
+    /// Date after which the code is refused.
+    pub expires: SystemTime
+}
+
+impl Invite {
+    /// Returns true if the code can still be used.
+    pub fn is_valid(&self) -> bool {
+        self.uses_left != Some(0) && SystemTime::now() < self.expires
+    }
+}
+
+/// Invitation codes generated by the admins.
+#[derive(Default)]
+pub struct Invites {
+    codes: HashMap<String, Invite>
+}
+
+impl Invites {
+    pub fn new() -> Invites {
+        Invites { codes: HashMap::new() }
+    }
+
+    /// Generate a new code usable `uses` times (without limit if 0) during `validity`.
+    /// Returns the code, None if the validity goes beyond the dates the system can represent.
+    pub fn create(&mut self, uses: u32, validity: Duration) -> Option<String> {
+        let expires = SystemTime::now().checked_add(validity)?;
+        let code:String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(12)
+            .map(char::from)
+            .collect();
+        self.codes.insert(code.clone(), Invite {
+            uses_left: if uses == 0 { None } else { Some(uses) },
+            expires
+        });
+        Some(code)
+    }
+
+    /// Returns true if the code exists and can still be used.
+    /// Use the code for a registration, before creating the account so that a code can't be used twice.
+    /// Returns false if the code is unknown, expired or exhausted.
+    pub fn consume(&mut self, code: &str) -> bool {
+        match self.codes.get_mut(code) {
+            Some(invite) if invite.is_valid() => {
+                if let Some(uses) = invite.uses_left.as_mut() {
+                    *uses -= 1;
+                }
+                true
+            }
+            _ => false
+        }
+    }
+
+    /// Give back a use of a code consumed by a registration which failed.
+    pub fn restore(&mut self, code: &str) {
+        if let Some(uses) = self.codes.get_mut(code).and_then(|x| x.uses_left.as_mut()) {
+            *uses += 1;
+        }
+    }
+
+    /// Delete a code.
+    /// Returns false if the code doesn't exist.
+    pub fn revoke(&mut self, code: &str) -> bool {
+        self.codes.remove(code).is_some()
+    }
+
+    /// Returns the codes which can still be used.
+    pub fn list(&self) -> Vec<(&String, &Invite)> {
+        self.codes.iter().filter(|(_, invite)| invite.is_valid()).collect()
+    }
+}
+
+#[cfg(test)]
+mod unit_testing {
+    use super::*;
+
+    #[test]
+    fn test_single_use_invite() {
+        let mut invites = Invites::new();
+        let code = invites.create(1, Duration::from_secs(60)).unwrap();
+        assert!(invites.consume(&code));
+        assert!(!invites.consume(&code));
+        // Inscription refusée : le code peut servir à nouveau
+        invites.restore(&code);
+        assert!(invites.consume(&code));
+        assert!(!invites.consume(&code));
+        assert!(!invites.consume("unknown"));
+    }
+
+    #[test]
+    fn test_multi_use_and_expired_invite() {
+        let mut invites = Invites::new();
+        let code = invites.create(0, Duration::from_secs(60)).unwrap();
+        for _ in 0..5 {
+            assert!(invites.consume(&code));
+        }
+        let expired = invites.create(3, Duration::from_secs(0)).unwrap();
+        assert!(!invites.consume(&expired));
+        assert_eq!(invites.list().len(), 1);
+        assert!(invites.revoke(&code));
+        assert!(!invites.consume(&code));
+        assert!(invites.create(1, Duration::MAX).is_none());
+    }
+}
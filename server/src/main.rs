@@ -1,110 +1,136 @@
-use std::{io::{ErrorKind, Read, Write}, net::{TcpListener, TcpStream}, thread, sync::{mpsc, Arc, Mutex}};
-use argon2::{self, Config};
-use json::{self, JsonValue, object};
+use std::{collections::{BTreeMap, HashSet}, io::{ErrorKind, Read, Write}, net::{SocketAddr, TcpListener, TcpStream}, path::Path, thread, sync::{mpsc, Arc, Mutex}};
+use chrono::{SecondsFormat, Utc};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use json::{self, object, JsonValue};
+use openssl::ssl::SslAcceptor;
 use rand::{Rng, thread_rng, distributions::Alphanumeric};
 
+mod archive;
+mod audit;
+mod auth;
+mod config;
+mod console;
+mod invite;
+mod mention;
+mod presence;
+mod retention;
+mod search;
+mod storage;
+mod tls;
+mod transfer;
+
+use audit::AuditLog;
+use auth::AuthBackend;
+use config::Config;
+use invite::Invites;
+use presence::{Presence, Status};
+use search::SearchQuery;
+use storage::{Account, Attachment, Cipher, Cursor, Direction, Storage, StoredMessage};
+use transfer::{Progress, Transfers, Upload, CHUNK_SIZE};
+
 // Définition des paramètres
 const CHAT: &str = "0.0.0.0:8888";
 const CONNECT: &str = "0.0.0.0:8889";
 const REGISTER: &str = "0.0.0.0:8890";
+/// Size of the messages exchanged with the server.
+const BUFFER_SIZE: usize = 1024;
+/// Answer of the register port when an invite code is required.
+const INVITE_REQUIRED: &str = "!invite";
+/// Maximum number of messages in a page of history.
+const MAX_PAGE: usize = 100;
+/// Maximum number of results of a search.
+const MAX_RESULTS: usize = 20;
+/// Maximum length of the name of a chat.
+const MAX_CHANNEL_NAME: usize = 32;
+/// Maximum number of characters of the message quoted by a reply.
+const MAX_QUOTE: usize = 60;
+/// Maximum number of different reactions to a message.
+const MAX_REACTIONS: usize = 10;
+/// Maximum length in characters of a reaction.
+const MAX_REACTION: usize = 32;
+/// Maximum length in bytes of the content of a message, the frames built from it have to fit in a buffer.
+const MAX_CONTENT: usize = 500;
+/// Space kept in a frame for the object wrapping a message, e.g. {"thread": 12, "message": ...}.
+const FRAME_ENVELOPE: usize = 64;
+/// Maximum lifetime in seconds of an ephemeral message, 30 days.
+const MAX_LIFETIME: u64 = 30 * 86400;
 
 fn sleep() {
     thread::sleep(::std::time::Duration::from_millis(100));
 }
 
+/// Chat connection of a user.
 struct User {
     /// The pseudo the user will use inside the chat.
     pseudo: String,
-    /// The user's password to authenticate on the chat.
-    pwd: String,
     /// The socket the user is connected on.
     socket: TcpStream,
-    /// Token send by the server to keep user connected
-    token: String
+    /// Address of the chat connection.
+    addr: SocketAddr
 }
 
 impl User {
     /// Function to get the user's pseudo.
     /// Returns a String
     fn get_pseudo(&self) -> &String {
-        return &self.pseudo;
-    }
-
-    /// Function to get the user's password.
-    /// Returns a String
-    fn get_pwd(&self) -> &String {
-        return &self.pwd;
+        &self.pseudo
     }
 
     /// Function to get the user's socket.
     /// Returns a TcpStream
     fn get_socket(&self) -> &TcpStream {
-        return &self.socket;
-    }
-
-    /// Function to get the user's token.
-    /// Returns a String
-    fn get_token(&self) -> &String {
-        return &self.token;
-    }
-
-    /// Function to set the new token of the user
-    fn set_token(&mut self, new_token: String) {
-        self.token = new_token
+        &self.socket
     }
 
     /// Function to create a new User.
     /// Returns an instance of User Structure
-    fn create_user(pseudo: String, pwd: String, socket: TcpStream) -> User {
+    fn create_user(pseudo: String, socket: TcpStream, addr: SocketAddr) -> User {
         User {
             pseudo,
             socket,
-            pwd,
-            token: String::new()
+            addr
         }
     }
+}
 
-    /// Function to update the socket of a user.
-    fn update_socket(&mut self, socket: TcpStream) {
-        self.socket = socket;
+fn main() {
+    println!("---- Massimora's Chat Server Listening to {} ! ----", CHAT);
+    let config = Config::load();
+    // Authentification par certificat client : le port de connexion n'accepte que des clients TLS
+    let acceptor = config.tls.as_ref().map(|tls| tls::build_acceptor(tls).expect("Unable to load TLS certificates"));
+    // Les comptes ne sont alors créés que par un certificat : le port d'inscription refuse les mots de passe
+    let certificates_only = acceptor.is_some();
+    if certificates_only {
+        println!("Certificate authentication enabled on {}, registration by password disabled", CONNECT);
     }
-
-    /// Returns a printable string containing user data
-    fn to_string(&self) -> String {
-        let mut serie:String = String::from("User: \"");
-        serie.push_str(self.pseudo.as_str());
-        serie.push_str("\", pwd: \"");
-        serie.push_str(self.pwd.as_str());
-        serie.push_str("\"");
-        serie.push_str("\", token: \"");
-        serie.push_str(self.token.as_str());
-        serie.push_str("\"");
-    
-        return serie;
+    // État du serveur : comptes, sessions, chats et messages
+    let storage: Arc<Mutex<Box<dyn Storage>>> = Arc::new(Mutex::new(storage::from_config(&config.storage, &config.data_dir, config.encryption.as_ref())));
+    {
+        let storage = storage.lock().unwrap();
+        println!("Storage: {} ({} accounts, {} messages)", storage.name(), storage.accounts().len(), storage.message_count());
     }
-
-    /// Returns a json string containing user data
-    fn to_json(&self) -> String {
-        let user_json:JsonValue = object!{
-            username: self.pseudo.clone(),
-            pwd: self.pwd.clone(),
-        };
-        
-        return json::stringify(user_json);
-    }
-}
-
-impl Clone for User {
-    fn clone(&self) -> User {
-        let mut user = User::create_user(self.get_pseudo().to_string(), self.get_pwd().to_string(), self.get_socket().try_clone().expect("Can't clone"));
-        user.set_token(self.get_token().to_string());
-        return user;
+    let backlog = config.backlog;
+    let queue_limit = config.queue_limit;
+    let moderators = config.moderators.clone();
+    // Vérification des mots de passe, par défaut dans le storage
+    let backend: Arc<dyn AuthBackend> = auth::from_config(&config.auth, Arc::clone(&storage));
+    println!("Authentication backend: {}", backend.name());
+    let registration_closed = config.registration_closed;
+    if registration_closed {
+        println!("Registration closed, an invite code is required");
     }
-}
-
+    let invites = Arc::new(Mutex::new(Invites::new()));
+    println!("Audit log: {}", config.audit.file);
+    let audit = Arc::new(Mutex::new(AuditLog::new(config.audit)));
+    // Fichiers envoyés dans les chats, conservés dans le répertoire de données
+    let files = Path::new(&config.data_dir).join("files");
+    println!("Files: {} (at most {} bytes)", files.display(), config.max_file_size);
+    // Les fichiers sont chiffrés comme le reste du répertoire de données
+    let transfers = Arc::new(Mutex::new(Transfers::new(files, config.max_file_size, config.encryption.as_ref().map(Cipher::from_config))));
+    // Suppression des messages expirés, selon la rétention du serveur, du chat ou du message
+    retention::start(Arc::clone(&storage), Arc::clone(&transfers), config.retention.clone());
+    console::start(Arc::clone(&invites), Arc::clone(&audit), Arc::clone(&storage));
 
-fn main() {
-    println!("---- Massimora's Chat Server Listening to {} ! ----", CHAT);
     // Création d'un Listener TCP, en mode non-bloquant
     let server = TcpListener::bind(CHAT).expect("Unable to bind listener");
     let connect = TcpListener::bind(CONNECT).expect("Unable to bind listener");
@@ -116,7 +142,13 @@ fn main() {
     // Tableau de nos clients
     // let mut clients = vec![];
 
-    let mut registered = Arc::new(Mutex::new(vec![]));
+    // Ordre des verrous : registered avant storage et presence, storage avant transfers.
+    // audit et invites sont verrouillés seuls, aucun autre verrou n'étant tenu.
+
+    // Connexions ouvertes sur le port du chat
+    let registered: Arc<Mutex<Vec<User>>> = Arc::new(Mutex::new(vec![]));
+    // Présence des utilisateurs, déduite des connexions ouvertes
+    let presence = Arc::new(Mutex::new(Presence::new()));
 
     // Sender / Received
     let (tx, rx) = mpsc::channel::<String>();
@@ -130,122 +162,469 @@ fn main() {
             // clients.push(socket.try_clone().expect("Unable to clone client"));
 
             // Création d'un thread, permettant la reception des données des clients
-            let mut clone_registered = Arc::clone(&registered);
+            let clone_registered = Arc::clone(&registered);
+            let clone_storage = Arc::clone(&storage);
+            let clone_presence = Arc::clone(&presence);
+            let clone_moderators = moderators.clone();
+            let clone_transfers = Arc::clone(&transfers);
             thread::spawn(move || loop {
-                let mut buff = vec![0; 256];
+                let mut buff = vec![0; BUFFER_SIZE];
 
                 match socket.read_exact(&mut buff) {
                     Ok(_) => {
                         let msg = buff.into_iter().take_while(|&x| x != 0).collect::<Vec<_>>();
-                        let mut msg = String::from_utf8(msg).expect("Invalid utf8 message");
-                        let mut data_registered = clone_registered.lock().unwrap();
+                        let msg = String::from_utf8(msg).expect("Invalid utf8 message");
 
                         // println!("{}: {:?}", addr, msg);
                         let content = json::parse(msg.as_str()).unwrap_or(object !{});
                         let user = content["from"].clone();
                         let user = json::parse(user.to_string().as_str()).unwrap_or(object !{});
-                        let my_user = User::create_user(user["username"].to_string(), user["pwd"].to_string(), socket.try_clone().expect("Can't clone socket"));
-                        // println!("user {} on {:?}", user["username"].to_string(), socket);
-                        update_user_socket(my_user.get_pseudo().to_string(), user["token"].to_string(), &mut data_registered, socket.try_clone().expect("Can't clone socket"));
 
                         // println!("{}", msg);
-                        
-                        if is_connected(user["username"].to_string(), user["token"].to_string(), data_registered.to_vec()) {
+
+                        if is_connected(&user["username"].to_string(), &user["token"].to_string(), &**clone_storage.lock().unwrap()) {
+                            let pseudo = user["username"].to_string();
+                            update_user_socket(pseudo.clone(), &mut clone_registered.lock().unwrap(), socket.try_clone().expect("Can't clone socket"), addr);
+                            let channel = content["to"].as_str().unwrap_or("general");
+                            if content["channels"].as_bool().unwrap_or(false) {
+                                // Liste des chats, avec leur nombre de membres
+                                let storage = clone_storage.lock().unwrap();
+                                let list:Vec<JsonValue> = storage.channels().iter()
+                                    .map(|x| object!{ name: x.as_str(), members: storage.members(x).len(), member: storage.is_member(x, &pseudo), topic: storage.topic(x) })
+                                    .collect();
+                                send_frame(&socket, json::stringify(object!{ channels: list }));
+                                continue;
+                            }
+                            if content["list"].as_bool().unwrap_or(false) {
+                                // Liste des utilisateurs connectés, avec leur statut
+                                let presence = clone_presence.lock().unwrap();
+                                let list:Vec<JsonValue> = presence.connected().iter().map(|(x, _)| presence.to_json(x)).collect();
+                                send_frame(&socket, json::stringify(object!{ users: list }));
+                                continue;
+                            }
+                            if let Some(other) = content["whois"].as_str() {
+                                let channels = {
+                                    let storage = clone_storage.lock().unwrap();
+                                    storage.account(other).map(|_| storage.channels_of(other))
+                                };
+                                match channels {
+                                    Some(channels) => {
+                                        let mut whois = clone_presence.lock().unwrap().to_json(other);
+                                        whois["channels"] = channels.into();
+                                        send_frame(&socket, json::stringify(object!{ whois: whois }));
+                                    }
+                                    None => { send_frame(&socket, format!("Unknown user {}", other)); }
+                                }
+                                continue;
+                            }
+                            if content.has_key("away") {
+                                // Absence annoncée par l'utilisateur, effacée par un message ou au retour
+                                let message = content["away"].as_str().map(|x| x.to_string());
+                                let notice = match &message {
+                                    Some(message) if message.is_empty() => String::from("You are now away"),
+                                    Some(message) => format!("You are now away: {}", message),
+                                    None => String::from("You are back")
+                                };
+                                clone_presence.lock().unwrap().set_away(&pseudo, message);
+                                send_frame(&socket, notice);
+                                continue;
+                            }
+                            if content["upload"].is_object() || content["upload"].is_string() {
+                                // Envoi d'un fichier par morceaux : l'annonce donne son nom, sa taille et son empreinte,
+                                // le serveur répond avec la position à partir de laquelle envoyer le contenu
+                                let progress = if content["upload"].is_object() {
+                                    if content["content"].to_string().len() > MAX_CONTENT {
+                                        Err(format!("The message is too long, at most {} bytes", MAX_CONTENT))
+                                    } else if clone_storage.lock().unwrap().is_member(channel, &pseudo) {
+                                        clone_transfers.lock().unwrap().start(&pseudo, channel, content["content"].as_str().unwrap_or(""), Attachment::from_json(&content["upload"]))
+                                    } else {
+                                        Err(format!("You are not a member of {}", channel))
+                                    }
+                                } else {
+                                    match (hex::decode(content["data"].as_str().unwrap_or("")), content["offset"].as_u64()) {
+                                        (Ok(data), Some(offset)) if data.len() <= CHUNK_SIZE => clone_transfers.lock().unwrap().write(&pseudo, &content["upload"].to_string(), offset, &data),
+                                        _ => Err(String::from("Invalid file chunk"))
+                                    }
+                                };
+                                match progress {
+                                    // Les morceaux suivants ne sont pas acquittés
+                                    Ok(Progress::Offset(offset)) if content["upload"].is_object() => { send_frame(&socket, json::stringify(object!{ upload: content["upload"]["hash"].as_str(), offset: offset })); }
+                                    Ok(Progress::Offset(_)) => (),
+                                    Ok(Progress::Done(upload)) => post_upload(&pseudo, upload, &mut clone_registered.lock().unwrap(), &mut **clone_storage.lock().unwrap()),
+                                    Err(err) => { send_frame(&socket, err); }
+                                }
+                                continue;
+                            }
+                            if let Some(id) = content["download"].as_u64() {
+                                // Téléchargement du fichier d'un message, à partir de la position donnée pour reprendre un téléchargement interrompu
+                                let offset = content["offset"].as_u64().unwrap_or(0);
+                                let attachment = {
+                                    let storage = clone_storage.lock().unwrap();
+                                    storage.message(id).filter(|x| storage.is_member(&x.channel, &pseudo)).and_then(|x| x.attachment)
+                                };
+                                let file = attachment.as_ref().and_then(|x| clone_transfers.lock().unwrap().read(&x.hash, offset));
+                                match (attachment, file) {
+                                    (Some(attachment), Some(content)) => {
+                                        let mut position = offset;
+                                        for chunk in content.chunks(CHUNK_SIZE) {
+                                            if !send_frame(&socket, json::stringify(object!{ file: id, offset: position, data: hex::encode(chunk) })) {
+                                                break;
+                                            }
+                                            position += chunk.len() as u64;
+                                        }
+                                        // Fin du fichier : le client vérifie le contenu reçu avec son empreinte
+                                        let mut end = attachment.to_json();
+                                        end["file"] = id.into();
+                                        send_frame(&socket, json::stringify(end));
+                                    }
+                                    _ => { send_frame(&socket, format!("No file in message #{}", id)); }
+                                }
+                                continue;
+                            }
+                            if let Some(id) = content["thread"].as_u64() {
+                                // Fil d'un message : le premier message et toutes les réponses
+                                let storage = clone_storage.lock().unwrap();
+                                let thread = storage.thread(id);
+                                match thread.first() {
+                                    Some(root) if storage.is_member(&root.channel, &pseudo) => {
+                                        for message in thread.iter().take(MAX_PAGE) {
+                                            send_frame(&socket, json::stringify(object!{ thread: root.id, message: message_frame(message, &**storage) }));
+                                        }
+                                        send_frame(&socket, json::stringify(object!{ thread: root.id, count: thread.len() }));
+                                    }
+                                    _ => { send_frame(&socket, format!("Unknown message #{}", id)); }
+                                }
+                                continue;
+                            }
+                            if let Some(id) = content["react"].as_u64() {
+                                // Réaction ajoutée, ou retirée si l'utilisateur avait déjà réagi de la même façon
+                                let reaction = content["reaction"].as_str().unwrap_or("");
+                                let mut storage = clone_storage.lock().unwrap();
+                                let message = storage.message(id).filter(|x| !x.deleted && storage.is_member(&x.channel, &pseudo));
+                                let reacted = match message {
+                                    _ if !is_reaction(reaction) => {
+                                        send_frame(&socket, format!("Invalid reaction, use an emoji or a shortcode of at most {} characters", MAX_REACTION));
+                                        None
+                                    }
+                                    Some(message) if !message.reactions.contains_key(reaction) && message.reactions.len() >= MAX_REACTIONS => {
+                                        send_frame(&socket, format!("The message #{} already has {} different reactions", id, MAX_REACTIONS));
+                                        None
+                                    }
+                                    Some(_) => storage.react(id, &pseudo, reaction),
+                                    None => {
+                                        send_frame(&socket, format!("Unknown message #{}", id));
+                                        None
+                                    }
+                                };
+                                if let Some(message) = reacted {
+                                    let added = message.reactions.get(reaction).map(|x| x.contains(&pseudo)).unwrap_or(false);
+                                    let members = storage.members(&message.channel);
+                                    drop(storage);
+                                    let event = object!{
+                                        reacted: id,
+                                        channel: message.channel.as_str(),
+                                        pseudo: pseudo.as_str(),
+                                        reaction: reaction,
+                                        added: added,
+                                        reactions: message.reaction_counts(),
+                                    };
+                                    send_event(event, &members, &mut clone_registered.lock().unwrap());
+                                }
+                                continue;
+                            }
+                            if content["mentions"].as_bool().unwrap_or(false) {
+                                // Boîte des mentions : les derniers messages qui mentionnent l'utilisateur
+                                let storage = clone_storage.lock().unwrap();
+                                let messages = storage.mentions_of(&pseudo, MAX_RESULTS);
+                                for message in &messages {
+                                    send_frame(&socket, json::stringify(object!{ inbox: true, message: message_frame(message, &**storage) }));
+                                }
+                                send_frame(&socket, json::stringify(object!{ inbox: true, count: messages.len() }));
+                                continue;
+                            }
+                            if let Some(id) = content["revisions"].as_u64() {
+                                // Versions précédentes d'un message, lisibles par les membres de son chat
+                                let message = {
+                                    let storage = clone_storage.lock().unwrap();
+                                    storage.message(id).filter(|x| storage.is_member(&x.channel, &pseudo))
+                                };
+                                match message {
+                                    Some(message) => {
+                                        for revision in &message.history {
+                                            send_frame(&socket, json::stringify(object!{ revision: id, content: revision.content.as_str(), timestamp: revision.timestamp.as_str() }));
+                                        }
+                                        send_frame(&socket, json::stringify(object!{ revisions: id, count: message.history.len() }));
+                                    }
+                                    None => { send_frame(&socket, format!("Unknown message #{}", id)); }
+                                }
+                                continue;
+                            }
+                            if let Some(id) = content["edit"].as_u64().or(content["remove"].as_u64()) {
+                                // Seuls l'auteur et les modérateurs peuvent modifier ou supprimer un message
+                                let mut storage = clone_storage.lock().unwrap();
+                                let message = storage.message(id).filter(|x| !x.deleted && storage.is_member(&x.channel, &pseudo));
+                                let hashes:Vec<String> = message.iter().filter_map(|x| x.attachment.as_ref()).map(|x| x.hash.clone()).collect();
+                                let updated = match message {
+                                    Some(message) if message.from != pseudo && !clone_moderators.contains(&pseudo) => {
+                                        send_frame(&socket, format!("Only the author or a moderator can change the message #{}", id));
+                                        None
+                                    }
+                                    Some(_) if content.has_key("edit") && content["content"].is_empty() => {
+                                        send_frame(&socket, String::from("The new content of a message can't be empty"));
+                                        None
+                                    }
+                                    Some(_) if content.has_key("edit") && content["content"].to_string().len() > MAX_CONTENT => {
+                                        send_frame(&socket, format!("The message is too long, at most {} bytes", MAX_CONTENT));
+                                        None
+                                    }
+                                    Some(_) if content.has_key("edit") => storage.edit_message(id, &content["content"].to_string()),
+                                    Some(_) => storage.delete_message(id),
+                                    None => {
+                                        send_frame(&socket, format!("Unknown message #{}", id));
+                                        None
+                                    }
+                                };
+                                if let Some(message) = updated {
+                                    println!("{} {} the message #{}", pseudo, if message.deleted { "deleted" } else { "edited" }, id);
+                                    if message.deleted {
+                                        // Le fichier du message est supprimé s'il n'est plus envoyé par aucun message
+                                        clone_transfers.lock().unwrap().remove_unused(&hashes, &storage.attachments());
+                                    }
+                                    let members = storage.members(&message.channel);
+                                    drop(storage);
+                                    let frame = message_frame(&message, &**clone_storage.lock().unwrap());
+                                    send_event(object!{ update: frame }, &members, &mut clone_registered.lock().unwrap());
+                                }
+                                continue;
+                            }
+                            if content["create"].as_bool().unwrap_or(false) {
+                                let mut storage = clone_storage.lock().unwrap();
+                                let notice = if !is_channel_name(channel) {
+                                    format!("Invalid chat name {}, use at most {} letters, digits, '-' or '_'", channel, MAX_CHANNEL_NAME)
+                                } else if !storage.create_channel(channel) {
+                                    format!("The chat {} already exists", channel)
+                                } else {
+                                    // Le créateur est le premier membre du chat
+                                    storage.join_channel(channel, &pseudo);
+                                    println!("{} created the chat {}", pseudo, channel);
+                                    format!("Chat {} created", channel)
+                                };
+                                send_frame(&socket, notice);
+                                continue;
+                            }
+                            if content["leave"].as_bool().unwrap_or(false) {
+                                let left = clone_storage.lock().unwrap().leave_channel(channel, &pseudo);
+                                if left {
+                                    send_frame(&socket, format!("You left {}", channel));
+                                    let members = clone_storage.lock().unwrap().members(channel);
+                                    send_event(system_event("left", channel, &pseudo), &members, &mut clone_registered.lock().unwrap());
+                                } else {
+                                    send_frame(&socket, format!("You are not a member of {}", channel));
+                                }
+                                continue;
+                            }
+                            if let Some(topic) = content["topic"].as_str() {
+                                // Tout membre peut changer le sujet du chat
+                                let members = {
+                                    let mut storage = clone_storage.lock().unwrap();
+                                    if storage.is_member(channel, &pseudo) && storage.set_topic(channel, topic) {
+                                        Some(storage.members(channel))
+                                    } else {
+                                        None
+                                    }
+                                };
+                                match members {
+                                    Some(members) => {
+                                        let mut event = system_event("topic", channel, &pseudo);
+                                        event["topic"] = topic.into();
+                                        send_event(event, &members, &mut clone_registered.lock().unwrap());
+                                    }
+                                    None => { send_frame(&socket, format!("You are not a member of {}", channel)); }
+                                }
+                                continue;
+                            }
+                            if (content.has_key("rename") || content.has_key("kick") || content.has_key("unkick")) && !clone_moderators.contains(&pseudo) {
+                                send_frame(&socket, String::from("Only the moderators can rename a chat or kick its members"));
+                                continue;
+                            }
+                            if let Some(name) = content["rename"].as_str() {
+                                let renamed = is_channel_name(name) && clone_storage.lock().unwrap().rename_channel(channel, name);
+                                if renamed {
+                                    println!("{} renamed the chat {} to {}", pseudo, channel, name);
+                                    let members = clone_storage.lock().unwrap().members(name);
+                                    let mut event = system_event("renamed", channel, &pseudo);
+                                    event["name"] = name.into();
+                                    send_event(event, &members, &mut clone_registered.lock().unwrap());
+                                } else {
+                                    send_frame(&socket, format!("Unable to rename {} to {}", channel, name));
+                                }
+                                continue;
+                            }
+                            if let Some(other) = content["kick"].as_str() {
+                                // L'utilisateur exclu ne peut plus revenir tant qu'un modérateur ne l'a pas réadmis
+                                let kicked = {
+                                    let mut storage = clone_storage.lock().unwrap();
+                                    let kicked = storage.leave_channel(channel, other);
+                                    if kicked {
+                                        storage.set_kicked(channel, other, true);
+                                    }
+                                    kicked
+                                };
+                                if kicked {
+                                    println!("{} kicked {} from {}", pseudo, other, channel);
+                                    // L'utilisateur exclu est prévenu avec les membres restants
+                                    let mut members = clone_storage.lock().unwrap().members(channel);
+                                    members.push(other.to_string());
+                                    let mut event = system_event("kicked", channel, other);
+                                    event["by"] = pseudo.as_str().into();
+                                    send_event(event, &members, &mut clone_registered.lock().unwrap());
+                                } else {
+                                    send_frame(&socket, format!("{} is not a member of {}", other, channel));
+                                }
+                                continue;
+                            }
+                            if let Some(other) = content["unkick"].as_str() {
+                                let notice = if clone_storage.lock().unwrap().set_kicked(channel, other, false) {
+                                    println!("{} let {} back in {}", pseudo, other, channel);
+                                    format!("{} can enter {} again", other, channel)
+                                } else {
+                                    format!("{} was not kicked from {}", other, channel)
+                                };
+                                send_frame(&socket, notice);
+                                continue;
+                            }
+                            if content["history"].is_object() && !clone_storage.lock().unwrap().is_member(channel, &pseudo) {
+                                send_frame(&socket, format!("You are not a member of {}", channel));
+                                continue;
+                            }
+                            if content["history"].is_object() {
+                                // Demande d'une page de l'historique, à partir d'un curseur
+                                let request = &content["history"];
+                                let (direction, cursor) = if request.has_key("after") {
+                                    (Direction::After, Cursor::from_json(&request["after"]))
+                                } else {
+                                    (Direction::Before, Cursor::from_json(&request["before"]))
+                                };
+                                let limit = request["limit"].as_usize().unwrap_or(backlog).min(MAX_PAGE);
+                                let page = clone_storage.lock().unwrap().messages(channel, cursor.as_ref(), direction, limit);
+                                send_history(&socket, &page, &**clone_storage.lock().unwrap());
+                                send_frame(&socket, json::stringify(object!{ history: channel, count: page.len() }));
+                                continue;
+                            }
+                            if content["search"].is_object() {
+                                // Recherche dans l'historique des chats que l'utilisateur peut lire
+                                let storage = clone_storage.lock().unwrap();
+                                let query = SearchQuery::from_json(&content["search"], storage.channels_of(&pseudo));
+                                let results = storage.search(&query, MAX_RESULTS);
+                                for message in &results {
+                                    send_search_result(&socket, message, &**storage);
+                                }
+                                send_frame(&socket, json::stringify(object!{ results: results.len() }));
+                                continue;
+                            }
+                            // Un message vide signale l'entrée dans le chat : l'utilisateur en devient membre et reçoit les derniers messages
+                            if content["content"].is_empty() {
+                                let mut storage = clone_storage.lock().unwrap();
+                                let kicked = storage.is_kicked(channel, &pseudo);
+                                let joined = !kicked && storage.join_channel(channel, &pseudo);
+                                if joined || storage.is_member(channel, &pseudo) {
+                                    send_history(&socket, &storage.last_messages(channel, backlog), &**storage);
+                                } else if kicked {
+                                    send_frame(&socket, format!("You were kicked from {}, a moderator has to let you back in", channel));
+                                } else {
+                                    send_frame(&socket, format!("Unknown chat {}, create it with !create {}", channel, channel));
+                                }
+                                deliver_queue(&socket, &pseudo, &mut **storage);
+                                let members = storage.members(channel);
+                                drop(storage);
+                                if joined {
+                                    send_event(system_event("joined", channel, &pseudo), &members, &mut clone_registered.lock().unwrap());
+                                }
+                            }
                             tx.send(msg.clone()).expect("Unable to send message to client");
                         }
-                    }, 
-                    Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
+                    },
+                    // Le thread n'attend que lorsqu'aucune trame n'est arrivée, les suivantes sont lues sans délai
+                    Err(ref err) if err.kind() == ErrorKind::WouldBlock => sleep(),
                     Err(_) => {
                         println!("{} has closed connection", addr);
+                        // L'utilisateur n'est plus joignable sur cette connexion
+                        clone_registered.lock().unwrap().retain(|x| x.addr != addr);
                         break;
                     }
                 }
-
-                sleep();
             });
         }
 
         // User login
-        if let Ok((mut socket, addr)) = connect.accept() {
+        if let Ok((socket, addr)) = connect.accept() {
             println!("Client {} try to connect", addr);
 
-            let tx = tx.clone();
             // clients.push(socket.try_clone().expect("Unable to clone client"));
 
-            let mut clone_registered = Arc::clone(&registered);
-            thread::spawn(move || loop {
-                let mut buff = vec![0; 256];
-                match socket.read_exact(&mut buff) {
-                    Ok(_) => {
-                        let msg = buff.into_iter().take_while(|&x| x != 0).collect::<Vec<_>>();
-                        let msg = String::from_utf8(msg).expect("Invalid utf8 message");
-                        let mut data_registered = clone_registered.lock().unwrap();
-
-                        // println!("{}: {:?}", addr, msg);
-                        let users = json::parse(msg.as_str()).unwrap_or(object!{});
-
-                        let username:String = users["username"].to_string();
-                        let pwd:String = users["pwd"].to_string();
-
-                        let user:User = User::create_user(username, pwd, socket.try_clone().expect("Can't open stream"));
-
-                        if search_registered(user.clone(), data_registered.to_vec()) {
-                            println!("{} connected", user.get_pseudo());
-                            let token = create_token();
-                            define_token(token.clone(), &mut data_registered, user.clone());
-                            let mut buffer = token.into_bytes();
-                            buffer.resize(30, 0);
-                            user.get_socket().write(&buffer).map(|_| user.get_socket()).ok();
-                        } else {
-                            let mut buffer = String::from("").into_bytes();
-                            buffer.resize(30, 0);
-                            user.get_socket().write_all(&buffer).map(|_| user.get_socket()).ok();
-                        }
-                    }, 
-                    Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
-                    Err(_) => {
-                        println!("{} has closed connection", addr);
-                        break;
-                    }
-                }
-                sleep();
-            });
+            let clone_registered = Arc::clone(&registered);
+            let clone_storage = Arc::clone(&storage);
+            let clone_backend = Arc::clone(&backend);
+            let clone_audit = Arc::clone(&audit);
+            match acceptor.clone() {
+                Some(acceptor) => thread::spawn(move || certificate_login(acceptor, socket, addr, clone_storage, clone_audit)),
+                None => thread::spawn(move || login(socket, addr, clone_registered, clone_storage, clone_backend, clone_audit))
+            };
         }
 
         // User register
         if let Ok((mut socket, addr)) = register.accept() {
             println!("Client {} try to register", addr);
 
-            let tx = tx.clone();
             // clients.push(socket.try_clone().expect("Unable to clone client"));
 
-            let mut clone_registered = Arc::clone(&registered);
+            let clone_storage = Arc::clone(&storage);
+            let clone_backend = Arc::clone(&backend);
+            let clone_invites = Arc::clone(&invites);
+            let clone_audit = Arc::clone(&audit);
 
             thread::spawn(move || loop {
-                let mut buff = vec![0; 256];
+                let mut buff = vec![0; BUFFER_SIZE];
 
                 match socket.read_exact(&mut buff) {
                     Ok(_) => {
                         let msg = buff.into_iter().take_while(|&x| x != 0).collect::<Vec<_>>();
                         let msg = String::from_utf8(msg).expect("Invalid utf8 message");
-                        let mut data_registered = clone_registered.lock().unwrap();
-
                         let data = json::parse(msg.as_str()).unwrap_or(object!{});
-                        let mut user:User = User::create_user(data["username"].to_string(), data["pwd"].to_string(), socket.try_clone().expect("Can't clone"));
-
-                        if verify_pseudo(data["username"].to_string(), data_registered.to_vec()) {
-                            println!("{} registered", user.get_pseudo());
-                            user.set_token(create_token());
-                            data_registered.push(user.clone());
-                            let mut buffer = user.get_token().to_string().into_bytes();
-                            buffer.resize(30, 0);
-                            user.get_socket().write(&buffer).map(|_| user.get_socket()).ok();
+                        let pseudo = data["username"].to_string();
+                        let invite = data["invite"].to_string();
+
+                        let mut answer = String::new();
+                        let taken = clone_storage.lock().unwrap().exists(&pseudo);
+                        if certificates_only {
+                            // Un pseudo réservé par mot de passe serait ensuite attribué au titulaire du certificat
+                            println!("{} refused: only certificates are accepted", pseudo);
+                            clone_audit.lock().unwrap().record(&addr, "register", &pseudo, false, "certificate authentication only");
+                        } else if registration_closed && data["invite"].is_null() {
+                            // Inscription fermée : le client doit renvoyer sa demande avec un code d'invitation
+                            answer = String::from(INVITE_REQUIRED);
+                        } else if registration_closed && !clone_invites.lock().unwrap().consume(&invite) {
+                            println!("{} refused: invalid invite code", pseudo);
+                            clone_audit.lock().unwrap().record(&addr, "register", &pseudo, false, "invalid invite code");
+                        } else if !taken && clone_backend.register(&pseudo, data["pwd"].to_string().as_str()) {
+                            // Le backend local a déjà créé le compte, les autres ne gèrent que le mot de passe
+                            println!("{} registered", pseudo);
+                            clone_audit.lock().unwrap().record(&addr, "register", &pseudo, true, if registration_closed { "invite code" } else { "open registration" });
+                            let mut storage = clone_storage.lock().unwrap();
+                            storage.insert_account(Account::new(pseudo.clone(), String::new()));
+                            answer = open_session(&pseudo, &mut **storage);
                         } else {
-                            let mut buffer = String::from("").into_bytes();
-                            buffer.resize(30, 0);
-                            user.get_socket().write(&buffer).map(|_| user.get_socket()).ok();
+                            // Le code consommé n'a pas servi
+                            if registration_closed {
+                                clone_invites.lock().unwrap().restore(&invite);
+                            }
+                            clone_audit.lock().unwrap().record(&addr, "register", &pseudo, false, "pseudo taken or refused by the backend");
                         }
-                    }, 
+
+                        let mut buffer = answer.into_bytes();
+                        buffer.resize(30, 0);
+                        socket.write_all(&buffer).ok();
+                    },
                     Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
                     Err(_) => {
                         println!("{} has closed connection", addr);
@@ -258,118 +637,567 @@ fn main() {
 
         // Envoie du message à tous les clients
         if let Ok(msg) = rx.try_recv() {
-            
+
             let content = json::parse(msg.as_str()).unwrap_or(object !{});
             let user = content["from"].clone();
             let user = json::parse(user.to_string().as_str()).unwrap_or(object !{});
-            if content["content"].to_string() != "" {
-            let mut msg = String::new();
-            msg.push_str(&user["username"].to_string());
-            msg.push_str(" : ");
-            msg.push_str(&content["content"].to_string());
-            println!("{}", msg);
-                for send_to in registered.lock().unwrap().clone() {
-                    // println!("{}", send_to.get_pseudo());
-                    // println!("{}", send_to.get_token());
-                    // println!("{}", user["username"].to_string());
-                    // println!("{}", user["token"].to_string());
-                    let mut buff = msg.clone().into_bytes();
-                    buff.resize(256, 0);
-                    if send_to.get_pseudo().to_string() != user["username"].to_string() && send_to.get_token().to_string() != user["token"].to_string() {
-                        // println!("Différent pour {} socket {:?}", send_to.get_pseudo(), send_to.get_socket());
-                        send_to.get_socket().write(&buff).map(|_| send_to.get_socket()).ok();
+            let channel = content["to"].as_str().unwrap_or("general");
+            if content["content"].to_string().len() > MAX_CONTENT {
+                if let Some(sender) = registered.lock().unwrap().iter().find(|x| *x.get_pseudo() == user["username"]) {
+                    send_frame(sender.get_socket(), format!("The message is too long, at most {} bytes", MAX_CONTENT));
+                }
+            } else if !content["content"].is_empty() && channel.starts_with('@') {
+                // Message privé : remis au destinataire connecté, sinon mis en attente
+                send_private(&user["username"].to_string(), &channel[1..], &content["content"].to_string(), &mut registered.lock().unwrap(), &mut **storage.lock().unwrap(), queue_limit);
+            } else if !content["content"].is_empty() {
+                let from = user["username"].to_string();
+                let members = storage.lock().unwrap().members(channel);
+                // Une réponse cite un message du même chat
+                let reply_to = content["reply_to"].as_u64();
+                let parent = reply_to.and_then(|x| storage.lock().unwrap().message(x)).filter(|x| x.channel == channel);
+                let mut registered = registered.lock().unwrap();
+                if !members.contains(&from) {
+                    if let Some(sender) = registered.iter().find(|x| *x.get_pseudo() == from) {
+                        send_frame(sender.get_socket(), format!("You are not a member of {}", channel));
+                    }
+                } else if reply_to.is_some() && parent.is_none() {
+                    if let Some(sender) = registered.iter().find(|x| *x.get_pseudo() == from) {
+                        send_frame(sender.get_socket(), format!("Unknown message #{} in {}", reply_to.unwrap_or(0), channel));
+                    }
+                } else if !content["expires_in"].is_null() && content["expires_in"].as_u64().and_then(expiration).is_none() {
+                    if let Some(sender) = registered.iter().find(|x| *x.get_pseudo() == from) {
+                        send_frame(sender.get_socket(), format!("Invalid lifetime, an ephemeral message lasts at most {} seconds", MAX_LIFETIME));
+                    }
+                } else {
+                    // Message éphémère : l'auteur donne sa durée de vie en secondes
+                    let expires = content["expires_in"].as_u64().and_then(expiration);
+                    // Mentions : les membres cités, tous les membres pour @channel, les membres disponibles pour @here
+                    let text = content["content"].to_string();
+                    let available:Vec<String> = presence.lock().unwrap().connected().into_iter().filter(|(_, status)| *status == Status::Online).map(|(x, _)| x).collect();
+                    let mentions = mention::recipients(&mention::parse(&text), &from, &members, &available);
+                    let message = storage.lock().unwrap().append_message(channel, &from, &text, expires, reply_to, mentions);
+                    // Écrire dans un chat met fin à l'absence
+                    presence.lock().unwrap().set_away(&from, None);
+                    println!("[{}] {}", channel, message.to_line());
+                    let msg = json::stringify(message_frame(&message, &**storage.lock().unwrap()));
+                    // Seuls les membres du chat reçoivent le message, les connexions fermées sont retirées
+                    registered.retain(|send_to| !members.contains(send_to.get_pseudo()) || *send_to.get_pseudo() == user["username"] || send_frame(send_to.get_socket(), msg.clone()));
+                    if !message.mentions.is_empty() {
+                        // Les utilisateurs mentionnés sont prévenus, quel que soit leur chat actif
+                        let event = json::stringify(object!{
+                            mention: message.id,
+                            channel: channel,
+                            from: from.as_str(),
+                            content: text.chars().take(MAX_QUOTE).collect::<String>(),
+                            timestamp: message.timestamp.as_str(),
+                        });
+                        registered.retain(|send_to| !message.mentions.contains(send_to.get_pseudo()) || send_frame(send_to.get_socket(), event.clone()));
                     }
                 }
             }
         }
+
+        // Changements de statut, envoyés aux utilisateurs qui partagent un chat
+        let connected:HashSet<String> = registered.lock().unwrap().iter().map(|x| x.get_pseudo().clone()).collect();
+        let changes = presence.lock().unwrap().update(&connected, Utc::now());
+        for (pseudo, status) in changes {
+            println!("{} is {}", pseudo, status.name());
+            let frame = json::stringify(object!{ presence: presence.lock().unwrap().to_json(&pseudo) });
+            let storage = storage.lock().unwrap();
+            let interested:HashSet<String> = storage.channels_of(&pseudo).iter().flat_map(|x| storage.members(x)).collect();
+            drop(storage);
+            registered.lock().unwrap().retain(|x| !interested.contains(x.get_pseudo()) || *x.get_pseudo() == pseudo || send_frame(x.get_socket(), frame.clone()));
+        }
         sleep();
     }
 }
 
-/// Function to read a user entry.
-/// Returns a String.
-fn read_user_entry() -> String {
-    use std::io;
-    let mut user_entry = String::new();
-    let _ = io::stdout().flush();
-    let _ = io::stdin().read_line(&mut user_entry);
+/// Returns the expiration date of a message lasting the given number of seconds, None if it exceeds the maximum lifetime.
+fn expiration(lifetime: u64) -> Option<String> {
+    if lifetime > MAX_LIFETIME {
+        return None;
+    }
+    let lifetime = chrono::TimeDelta::try_seconds(lifetime as i64)?;
+    Some(Utc::now().checked_add_signed(lifetime)?.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
 
-    user_entry = user_entry.trim().parse().unwrap();
+/// Returns true if the name can be used for a chat: letters, digits, '-' and '_'.
+fn is_channel_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_CHANNEL_NAME && name.chars().all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_')
+}
 
-    return user_entry;
+/// Build an event of a chat sent by the server, e.g. a user joined or left it.
+/// The event names the chat and the user concerned and is dated by the server.
+fn system_event(kind: &str, channel: &str, pseudo: &str) -> JsonValue {
+    object!{
+        system: kind,
+        channel: channel,
+        pseudo: pseudo,
+        timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+    }
+}
 
+/// Send an event to the connections of the given users, the closed connections are removed.
+fn send_event(event: JsonValue, pseudos: &[String], users: &mut Vec<User>) {
+    let frame = json::stringify(event);
+    users.retain(|x| !pseudos.contains(x.get_pseudo()) || send_frame(x.get_socket(), frame.clone()));
 }
 
-/// Return an encoded string corresponding to the hash of the given one.
-fn encode_pwd(pwd:String) -> String{
-    return argon2::hash_encoded(pwd.as_bytes(), String::from("rust_messaging").as_bytes(), &Config::default()).unwrap();
+/// Check that a reaction is an emoji or a shortcode like ":tada:", without spaces.
+fn is_reaction(reaction: &str) -> bool {
+    !reaction.is_empty()
+        && reaction.chars().count() <= MAX_REACTION
+        && !reaction.chars().any(|x| x.is_whitespace() || x.is_control())
 }
 
-/// Verify the match between the pwd and the hash.
-/// Returns true if match, else false.
-fn verify_pwd(pwd:String, hash:&String) -> bool {
-    return argon2::verify_encoded(&hash, pwd.as_bytes()).unwrap();
+/// Send a frame on the chat port, a frame longer than the buffer is refused instead of being cut.
+/// Returns false if the connection is closed.
+fn send_frame(mut socket: &TcpStream, frame: String) -> bool {
+    if frame.len() > BUFFER_SIZE {
+        println!("Frame of {} bytes not sent, a frame holds at most {} bytes", frame.len(), BUFFER_SIZE);
+        return true;
+    }
+    let mut buff = frame.into_bytes();
+    buff.resize(BUFFER_SIZE, 0);
+    socket.write_all(&buff).is_ok()
 }
 
-fn search_registered(user: User, users:Vec<User> ) -> bool {
-    for all_users in users {
-        if all_users.get_pseudo() == user.get_pseudo() && all_users.get_pwd() == user.get_pwd() {
-            return true;
+/// Returns a message as sent to the clients, with the beginning of the message it replies to.
+fn message_frame(message: &StoredMessage, storage: &dyn Storage) -> JsonValue {
+    let mut frame = message.to_frame();
+    if let Some(parent) = message.reply_to.and_then(|x| storage.message(x)) {
+        // La citation est tronquée pour que le message tienne dans une trame
+        frame["quote"] = object!{
+            from: parent.from.as_str(),
+            content: parent.content.chars().take(MAX_QUOTE).collect::<String>(),
+            deleted: parent.deleted,
+        };
+    }
+    fit_frame(frame)
+}
+
+/// Shorten the frame of a message which doesn't fit in a buffer with its envelope:
+/// the quote is removed first, then the least used reactions, then the end of the content.
+fn fit_frame(mut frame: JsonValue) -> JsonValue {
+    let overflow = |frame: &JsonValue| (frame.dump().len() + FRAME_ENVELOPE).saturating_sub(BUFFER_SIZE);
+    if overflow(&frame) > 0 && frame["quote"].is_object() {
+        frame["quote"]["content"] = "…".into();
+    }
+    while overflow(&frame) > 0 && !frame["reactions"].is_empty() {
+        let least = frame["reactions"].entries().min_by_key(|(_, count)| count.as_u64().unwrap_or(0)).map(|(x, _)| x.to_string());
+        frame["reactions"].remove(&least.unwrap_or_default());
+    }
+    let excess = overflow(&frame);
+    if excess > 0 {
+        // Les caractères échappés prennent plus de place dans la trame : le contenu est raccourci jusqu'à tenir
+        let mut content = frame["content"].to_string();
+        let mut end = content.len().saturating_sub(excess + "…".len());
+        loop {
+            while !content.is_char_boundary(end) {
+                end -= 1;
+            }
+            content.truncate(end);
+            frame["content"] = format!("{}…", content).into();
+            if overflow(&frame) == 0 || end == 0 {
+                break;
+            }
+            end = end.saturating_sub(overflow(&frame));
         }
     }
-    return false;
+    frame
 }
 
-fn create_token() -> String {
-    let token:String = thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(30)
-        .map(char::from)
-        .collect();
-    return token;
+/// Store the message of a completed upload and send it to the members of its chat.
+fn post_upload(pseudo: &str, upload: Upload, users: &mut Vec<User>, storage: &mut dyn Storage) {
+    let message = storage.import_message(StoredMessage {
+        id: 0,
+        channel: upload.channel.clone(),
+        from: pseudo.to_string(),
+        // Sans texte, le message porte le nom du fichier
+        content: if upload.content.is_empty() { upload.attachment.name.clone() } else { upload.content },
+        timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        expires: None,
+        reply_to: None,
+        mentions: vec![],
+        edited: None,
+        history: vec![],
+        deleted: false,
+        reactions: BTreeMap::new(),
+        attachment: Some(upload.attachment)
+    });
+    println!("[{}] {}", message.channel, message.to_line());
+    send_event(message_frame(&message, storage), &storage.members(&message.channel), users);
 }
 
-fn verify_pseudo(pseudo:String, users: Vec<User>) -> bool {
-    for all_users in users {
-        if all_users.get_pseudo().to_string() == pseudo {
-            return false;
+/// Send messages of the history, one JSON frame per message.
+fn send_history(socket: &TcpStream, messages: &[StoredMessage], storage: &dyn Storage) {
+    for message in messages {
+        send_frame(socket, json::stringify(message_frame(message, storage)));
+    }
+}
+
+/// Send a result of a search with the previous and the next message of its chat.
+fn send_search_result(socket: &TcpStream, message: &StoredMessage, storage: &dyn Storage) {
+    let cursor = Cursor::Id(message.id);
+    let mut messages = storage.messages(&message.channel, Some(&cursor), Direction::Before, 1);
+    messages.push(message.clone());
+    messages.append(&mut storage.messages(&message.channel, Some(&cursor), Direction::After, 1));
+    for context in messages {
+        send_frame(socket, json::stringify(object!{ search: message.id, message: message_frame(&context, storage) }));
+    }
+}
+
+/// Deliver the private messages received while the user was offline, in order.
+/// The messages which couldn't be sent stay in the queue.
+fn deliver_queue(socket: &TcpStream, pseudo: &str, storage: &mut dyn Storage) {
+    let messages = storage.queue_take(pseudo);
+    for (index, message) in messages.iter().enumerate() {
+        if !send_frame(socket, private_frame(&message.from, pseudo, &message.content, &message.timestamp)) {
+            storage.queue_restore(pseudo, messages[index..].to_vec());
+            return;
         }
     }
-    return true;
 }
 
-fn define_token(token:String, users:&mut Vec<User>, user:User) {
-for all_users in users {
-        if all_users.get_pseudo() == user.get_pseudo() && all_users.get_pwd() == user.get_pwd() {
-            all_users.set_token(token.clone());
+/// Returns the frame of a private message.
+fn private_frame(from: &str, to: &str, content: &str, timestamp: &str) -> String {
+    json::stringify(object!{ private: true, from: from, to: to, content: content, timestamp: timestamp })
+}
+
+/// Send a private message to all the chat connections of the recipient, or keep it in its offline queue
+/// which holds at most `queue_limit` messages.
+/// The other connections of the sender get a copy of the message, the sender is told when it couldn't be delivered immediately.
+fn send_private(from: &str, to: &str, content: &str, users: &mut Vec<User>, storage: &mut dyn Storage, queue_limit: usize) {
+    if !storage.exists(to) {
+        if let Some(sender) = users.iter().find(|x| x.get_pseudo() == from) {
+            send_frame(sender.get_socket(), format!("Unknown user {}, the message is not delivered", to));
+        }
+        return;
+    }
+    let frame = private_frame(from, to, content, &Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true));
+    // Les connexions fermées du destinataire sont retirées
+    users.retain(|x| x.get_pseudo() != to || send_frame(x.get_socket(), frame.clone()));
+    let delivered = users.iter().any(|x| x.get_pseudo() == to);
+
+    let queued = !delivered && storage.queue_len(to) < queue_limit;
+    if queued {
+        storage.queue_push(to, from, content);
+    }
+    // Les autres connexions de l'auteur affichent le message dans la conversation
+    if from != to && (delivered || queued) {
+        users.retain(|x| x.get_pseudo() != from || send_frame(x.get_socket(), frame.clone()));
+    }
+
+    let notice = if delivered {
+        None
+    } else if queued {
+        Some(format!("{} is offline, the message will be delivered at its next connection", to))
+    } else {
+        Some(format!("The offline queue of {} is full, the message is not delivered", to))
+    };
+    if let Some(notice) = notice {
+        if let Some(sender) = users.iter().find(|x| x.get_pseudo() == from) {
+            send_frame(sender.get_socket(), notice);
         }
     }
 }
 
-fn is_connected(pseudo:String, token:String, users:Vec<User>) -> bool {
-    for mut x in users {
-        if x.get_pseudo().to_string() == pseudo && x.get_token().to_string() == token {
-            return true;
+/// Authenticate a client on the connect port, with a password or by signing a nonce with a registered key.
+/// The same port lets a connected user manage its account (public keys, password, deletion).
+fn login(mut socket: TcpStream, addr: SocketAddr, registered: Arc<Mutex<Vec<User>>>, storage: Arc<Mutex<Box<dyn Storage>>>, backend: Arc<dyn AuthBackend>, audit: Arc<Mutex<AuditLog>>) {
+    // Challenge en attente de signature : (pseudo, clé publique, nonce)
+    let mut challenge: Option<(String, String, String)> = None;
+    loop {
+        let mut buff = vec![0; BUFFER_SIZE];
+        match socket.read_exact(&mut buff) {
+            Ok(_) => {
+                let msg = buff.into_iter().take_while(|&x| x != 0).collect::<Vec<_>>();
+                let msg = String::from_utf8(msg).expect("Invalid utf8 message");
+
+                // println!("{}: {:?}", addr, msg);
+                let users = json::parse(msg.as_str()).unwrap_or(object!{});
+
+                let pseudo:String = users["username"].to_string();
+                let pwd:String = users["pwd"].to_string();
+
+                // Le mot de passe est vérifié sans aucun verrou tenu,
+                // un backend externe lent ne retarde que cette connexion
+                let is_key_request = users["key"].is_string() || users["signature"].is_string();
+                let authenticated = !is_key_request && backend.authenticate(&pseudo, &pwd);
+                let is_connected = is_connected(&pseudo, &users["token"].to_string(), &**storage.lock().unwrap());
+
+                let mut answer = String::new();
+                // Le journal n'est verrouillé que le temps d'écrire un événement, sans autre verrou tenu
+                let record = |event: &str, pseudo: &str, success: bool, reason: &str| audit.lock().unwrap().record(&addr, event, pseudo, success, reason);
+                if users["add_key"].is_string() {
+                    // Ajout d'une clé publique par un utilisateur connecté, le mot de passe doit être fourni :
+                    // un jeton volé ne doit pas permettre de garder l'accès au compte
+                    let key = users["add_key"].to_string();
+                    if !is_connected || !authenticated {
+                        record("add_key", &pseudo, false, "invalid token or password");
+                    } else if parse_public_key(&key).is_none() || !storage.lock().unwrap().add_key(&pseudo, key) {
+                        record("add_key", &pseudo, false, "invalid or already registered key");
+                    } else {
+                        println!("{} added a public key", pseudo);
+                        record("add_key", &pseudo, true, "key added");
+                        answer = String::from("ok");
+                    }
+                } else if users["remove_key"].is_string() {
+                    // Retrait d'une clé publique, le mot de passe doit être fourni
+                    if !is_connected || !authenticated {
+                        record("remove_key", &pseudo, false, "invalid token or password");
+                    } else if !storage.lock().unwrap().remove_key(&pseudo, &users["remove_key"].to_string()) {
+                        record("remove_key", &pseudo, false, "unknown key");
+                    } else {
+                        println!("{} removed a public key", pseudo);
+                        record("remove_key", &pseudo, true, "key removed");
+                        answer = String::from("ok");
+                    }
+                } else if users["key"].is_string() {
+                    // Première étape d'une connexion par clé : envoi d'un nonce à signer
+                    let key = users["key"].to_string();
+                    if has_key(&pseudo, &key, &**storage.lock().unwrap()) {
+                        let nonce = create_token();
+                        challenge = Some((pseudo, key, nonce.clone()));
+                        answer = nonce;
+                    } else {
+                        record("login", &pseudo, false, "unknown public key");
+                    }
+                } else if users["signature"].is_string() {
+                    // Deuxième étape : vérification de la signature du nonce
+                    match challenge.take() {
+                        Some((expected, key, nonce)) if expected == pseudo && verify_signature(&key, &nonce, &users["signature"].to_string()) => {
+                            println!("{} connected with a key", pseudo);
+                            record("login", &pseudo, true, "public key");
+                            answer = open_session(&pseudo, &mut **storage.lock().unwrap());
+                        }
+                        Some(_) => record("login", &pseudo, false, "invalid signature"),
+                        None => record("login", &pseudo, false, "no pending challenge")
+                    }
+                } else if users["new_pwd"].is_string() {
+                    // Changement du mot de passe, l'ancien doit être fourni
+                    if !is_connected || !authenticated {
+                        record("change_password", &pseudo, false, "invalid token or password");
+                    } else if !backend.change_password(&pseudo, &users["new_pwd"].to_string()) {
+                        record("change_password", &pseudo, false, "refused by the backend");
+                    } else {
+                        println!("{} changed its password", pseudo);
+                        record("change_password", &pseudo, true, "password changed");
+                        // Les sessions ouvertes avec l'ancien mot de passe sont fermées, seule la nouvelle reste valide
+                        registered.lock().unwrap().retain(|x| *x.get_pseudo() != pseudo);
+                        let mut storage = storage.lock().unwrap();
+                        storage.clear_sessions(&pseudo);
+                        answer = open_session(&pseudo, &mut **storage);
+                    }
+                } else if users["delete"].as_bool().unwrap_or(false) {
+                    // Suppression du compte, le mot de passe doit être fourni
+                    if !is_connected || !authenticated {
+                        record("delete", &pseudo, false, "invalid token or password");
+                    } else {
+                        backend.delete(&pseudo);
+                        storage.lock().unwrap().delete_account(&pseudo);
+                        registered.lock().unwrap().retain(|x| *x.get_pseudo() != pseudo);
+                        println!("{} deleted its account", pseudo);
+                        record("delete", &pseudo, true, "account deleted");
+                        answer = String::from("ok");
+                    }
+                } else if authenticated {
+                    println!("{} connected", pseudo);
+                    record("login", &pseudo, true, "password");
+                    // Les comptes d'un backend externe sont créés à leur première connexion
+                    let mut storage = storage.lock().unwrap();
+                    storage.insert_account(Account::new(pseudo.clone(), String::new()));
+                    answer = open_session(&pseudo, &mut **storage);
+                } else {
+                    record("login", &pseudo, false, "invalid credentials");
+                }
+
+                let mut buffer = answer.into_bytes();
+                buffer.resize(30, 0);
+                socket.write_all(&buffer).ok();
+            },
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
+            Err(_) => {
+                println!("{} has closed connection", addr);
+                break;
+            }
         }
+        sleep();
     }
-    return false;
 }
 
-fn update_user_socket(pseudo:String, token:String, users:&mut Vec<User>, socket:TcpStream) {
-    for x in users {
-        // println!("{}", x.get_token());
-        // println!("{}", x.get_pseudo());
-        // println!("{} !{}!", pseudo, token);
-        if x.get_pseudo().to_string() == pseudo && x.get_token().to_string() == token {
-            x.update_socket(socket.try_clone().expect("Can't clone socket"));
-            // println!("socket update")
+/// Authenticate a client with its TLS certificate, the common name of the subject is used as pseudo.
+/// The account is created on the first connection of a certified user.
+fn certificate_login(acceptor: SslAcceptor, socket: TcpStream, addr: SocketAddr, storage: Arc<Mutex<Box<dyn Storage>>>, audit: Arc<Mutex<AuditLog>>) {
+    let mut stream = match acceptor.accept(socket) {
+        Ok(stream) => stream,
+        Err(err) => {
+            println!("{} refused: a valid client certificate is required ({})", addr, err);
+            audit.lock().unwrap().record(&addr, "login", "", false, "invalid client certificate");
+            return;
+        }
+    };
+
+    let answer = match tls::peer_name(&stream) {
+        Some(pseudo) => {
+            let (created, token) = {
+                let mut storage = storage.lock().unwrap();
+                let created = storage.insert_account(Account::new(pseudo.clone(), String::new()));
+                (created, open_session(&pseudo, &mut **storage))
+            };
+            if created {
+                println!("{} registered with a certificate", pseudo);
+                audit.lock().unwrap().record(&addr, "register", &pseudo, true, "client certificate");
+            }
+            println!("{} connected with a certificate", pseudo);
+            audit.lock().unwrap().record(&addr, "login", &pseudo, true, "client certificate");
+            object!{ username: pseudo, token: token }
         }
+        None => {
+            println!("{} refused: the certificate subject has no common name", addr);
+            audit.lock().unwrap().record(&addr, "login", "", false, "no common name in the client certificate");
+            object!{ error: "The certificate subject has no common name" }
+        }
+    };
+
+    let mut buffer = json::stringify(answer).into_bytes();
+    buffer.resize(BUFFER_SIZE, 0);
+    stream.write_all(&buffer).ok();
+    stream.shutdown().ok();
+}
+
+fn create_token() -> String {
+    let token:String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(30)
+        .map(char::from)
+        .collect();
+    token
+}
+
+/// Open a new session for the user, next to the ones already opened.
+/// Returns the token.
+fn open_session(pseudo:&str, storage:&mut dyn Storage) -> String {
+    let token = create_token();
+    storage.add_session(pseudo, token.clone());
+    token
+}
+
+/// Verify the token of the user.
+/// Returns true if the token is the one of a session opened by the user.
+fn is_connected(pseudo:&str, token:&str, storage:&dyn Storage) -> bool {
+    storage.has_session(pseudo, token)
+}
+
+/// Register the chat connection of the user, a user can have several connections opened at the same time.
+fn update_user_socket(pseudo:String, users:&mut Vec<User>, socket:TcpStream, addr:SocketAddr) {
+    if !users.iter().any(|x| x.addr == addr) {
+        users.push(User::create_user(pseudo, socket, addr));
     }
 }
 
+/// Verify that the given public key is registered for the user.
+/// Returns true if the user owns the key, else false.
+fn has_key(pseudo:&str, key:&String, storage:&dyn Storage) -> bool {
+    storage.account(pseudo).map(|x| x.keys.contains(key)).unwrap_or(false)
+}
+
+/// Decode a hex encoded Ed25519 public key.
+/// Returns None if the key is malformed.
+fn parse_public_key(key:&str) -> Option<VerifyingKey> {
+    let bytes:[u8; 32] = hex::decode(key).ok()?.try_into().ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
+
+/// Verify the hex encoded signature of the nonce with the given public key.
+/// Returns true if the signature is valid, else false.
+fn verify_signature(key:&str, nonce:&str, signature:&str) -> bool {
+    let key = match parse_public_key(key) {
+        Some(key) => key,
+        None => return false
+    };
+    let signature:[u8; 64] = match hex::decode(signature).ok().and_then(|x| x.try_into().ok()) {
+        Some(signature) => signature,
+        None => return false
+    };
+    key.verify(nonce.as_bytes(), &Signature::from_bytes(&signature)).is_ok()
+}
+
 // fn print_user(users:Vec<User>) -> bool {
 //     for x in users {
 //         println!("{} <{:?}>", x.get_pseudo(), x.get_socket());
 //     }
 //     return false;
-// }
\ No newline at end of file
+// }
+
+#[cfg(test)]
+mod unit_testing {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    #[test]
+    fn test_is_channel_name() {
+        assert!(is_channel_name("rust-fr_2"));
+        assert!(!is_channel_name(""));
+        assert!(!is_channel_name("@toto"));
+        assert!(!is_channel_name("two words"));
+        assert!(!is_channel_name(&"a".repeat(MAX_CHANNEL_NAME + 1)));
+    }
+
+    #[test]
+    fn test_expiration() {
+        assert!(expiration(60).is_some());
+        assert!(expiration(MAX_LIFETIME).is_some());
+        assert!(expiration(MAX_LIFETIME + 1).is_none());
+        assert!(expiration(u64::MAX).is_none());
+    }
+
+    #[test]
+    fn test_message_frame_fits() {
+        let mut storage = storage::MemoryStorage::new();
+        storage.append_message("general", "toto", &"é".repeat(MAX_QUOTE * 2), None, None, vec![]);
+        let reply = storage.append_message("general", "titi", &"\"quoted\" ".repeat(40), None, Some(1), vec![]);
+        for index in 0..MAX_REACTIONS - 1 {
+            storage.react(reply.id, "toto", &format!(":{}:", "a".repeat(MAX_REACTION - 3 - index)));
+        }
+        storage.react(reply.id, "toto", ":a:");
+        storage.react(reply.id, "titi", ":a:");
+        // La citation puis les réactions les moins utilisées sont retirées, le contenu est gardé
+        let frame = message_frame(&storage.message(reply.id).unwrap(), &storage);
+        assert!(frame.dump().len() + FRAME_ENVELOPE <= BUFFER_SIZE);
+        assert_eq!(frame["quote"]["content"], "…");
+        assert_eq!(frame["reactions"][":a:"], 2);
+        assert!(frame["reactions"].len() < MAX_REACTIONS);
+        assert_eq!(frame["content"].to_string(), reply.content);
+
+        // Un message importé ou écrit avant la limite peut dépasser MAX_CONTENT : sa fin est coupée
+        let long = storage.append_message("general", "titi", &"\"é\"".repeat(400), None, None, vec![]);
+        let frame = message_frame(&long, &storage);
+        assert!(frame.dump().len() + FRAME_ENVELOPE <= BUFFER_SIZE);
+        assert!(frame["content"].to_string().ends_with('…'));
+
+        // Un message qui tient dans une trame n'est pas modifié
+        let short = storage.append_message("general", "titi", &"é".repeat(MAX_CONTENT / 2), None, Some(1), vec![]);
+        assert_eq!(message_frame(&short, &storage)["content"].to_string(), "é".repeat(MAX_CONTENT / 2));
+    }
+
+    #[test]
+    fn test_is_reaction() {
+        assert!(is_reaction("👍"));
+        assert!(is_reaction(":tada:"));
+        assert!(!is_reaction(""));
+        assert!(!is_reaction("thumbs up"));
+        assert!(!is_reaction(&":".repeat(MAX_REACTION + 1)));
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let signing = SigningKey::from_bytes(&[7; 32]);
+        let key = hex::encode(signing.verifying_key().to_bytes());
+        let signature = hex::encode(signing.sign(b"nonce").to_bytes());
+
+        assert!(verify_signature(&key, "nonce", &signature));
+        assert!(!verify_signature(&key, "other nonce", &signature));
+        assert!(!verify_signature("not a key", "nonce", &signature));
+    }
+}
@@ -0,0 +1,62 @@
+/// Mention of all the members of a chat.
+pub const CHANNEL: &str = "channel";
+/// Mention of the members of a chat who are online and not away.
+pub const HERE: &str = "here";
+
+/// Returns the names mentioned in a message with "@name", without duplicates and in order.
+/// An "@" inside a word, like in an email address, isn't a mention.
+pub fn parse(content: &str) -> Vec<String> {
+    let mut mentions: Vec<String> = vec![];
+    let mut previous = ' ';
+    for (index, c) in content.char_indices() {
+        if c == '@' && !previous.is_alphanumeric() {
+            let name: String = content[index + 1..].chars()
+                .take_while(|x| x.is_alphanumeric() || *x == '-' || *x == '_' || *x == '.')
+                .collect();
+            // Le point final d'une phrase ne fait pas partie du pseudo
+            let name = name.trim_end_matches('.');
+            if !name.is_empty() && !mentions.iter().any(|x| x == name) {
+                mentions.push(name.to_string());
+            }
+        }
+        previous = c;
+    }
+    mentions
+}
+
+/// Returns the users notified by the mentions of a message: the members of the chat mentioned by their pseudo,
+/// all the members for @channel and the available ones for @here. The author is never notified.
+pub fn recipients(mentions: &[String], author: &str, members: &[String], available: &[String]) -> Vec<String> {
+    let mut recipients: Vec<String> = vec![];
+    for mention in mentions {
+        let pseudos: Vec<&String> = match mention.as_str() {
+            CHANNEL => members.iter().collect(),
+            HERE => members.iter().filter(|x| available.contains(x)).collect(),
+            _ => members.iter().filter(|x| *x == mention).collect()
+        };
+        for pseudo in pseudos {
+            if pseudo != author && !recipients.contains(pseudo) {
+                recipients.push(pseudo.clone());
+            }
+        }
+    }
+    recipients
+}
+
+#[cfg(test)]
+mod unit_testing {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_recipients() {
+        assert_eq!(parse("@alice, lunch with @bob.smith and @alice ?"), vec!["alice", "bob.smith"]);
+        assert_eq!(parse("mail toto@example.com or @here."), vec!["here"]);
+        assert!(parse("@ nobody").is_empty());
+
+        let members = vec![String::from("alice"), String::from("bob"), String::from("carol")];
+        let available = vec![String::from("bob")];
+        assert_eq!(recipients(&parse("@alice @dave"), "bob", &members, &available), vec!["alice"]);
+        assert_eq!(recipients(&parse("@here"), "alice", &members, &available), vec!["bob"]);
+        assert_eq!(recipients(&parse("@channel @carol"), "alice", &members, &available), vec!["bob", "carol"]);
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use chrono::{DateTime, SecondsFormat, Utc};
+use json::{object, JsonValue};
+
+/// Status of a user, derived from its chat connections.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Status {
+    /// At least one chat connection is opened.
+    Online,
+    /// Connected, but the user said it is away, with its message.
+    Away(String),
+    Offline
+}
+
+impl Status {
+    pub fn name(&self) -> &str {
+        match self {
+            Status::Online => "online",
+            Status::Away(_) => "away",
+            Status::Offline => "offline"
+        }
+    }
+}
+
+/// Presence of the users seen since the server started.
+#[derive(Default)]
+pub struct Presence {
+    /// Last status computed for each user.
+    statuses: BTreeMap<String, Status>,
+    /// Users who said they are away, with their message.
+    away: HashMap<String, String>,
+    /// Last date each user was connected.
+    last_seen: HashMap<String, DateTime<Utc>>
+}
+
+impl Presence {
+    pub fn new() -> Presence {
+        Presence::default()
+    }
+
+    /// Mark a connected user as away with a message, or as back with None.
+    pub fn set_away(&mut self, pseudo: &str, message: Option<String>) {
+        match message {
+            Some(message) => self.away.insert(pseudo.to_string(), message),
+            None => self.away.remove(pseudo)
+        };
+    }
+
+    /// Compute the status of the users from the pseudos of the opened chat connections.
+    /// Returns the users whose status changed, with their new status.
+    pub fn update(&mut self, connected: &HashSet<String>, now: DateTime<Utc>) -> Vec<(String, Status)> {
+        // Un utilisateur déconnecté n'est plus absent à sa prochaine connexion
+        self.away.retain(|pseudo, _| connected.contains(pseudo));
+        let mut changes = vec![];
+        for pseudo in connected {
+            self.last_seen.insert(pseudo.clone(), now);
+        }
+        let pseudos:HashSet<String> = self.statuses.keys().cloned().chain(connected.iter().cloned()).collect();
+        for pseudo in pseudos {
+            let status = match (connected.contains(&pseudo), self.away.get(&pseudo)) {
+                (false, _) => Status::Offline,
+                (true, Some(message)) => Status::Away(message.clone()),
+                (true, None) => Status::Online
+            };
+            if self.statuses.get(&pseudo) != Some(&status) && (status != Status::Offline || self.statuses.contains_key(&pseudo)) {
+                changes.push((pseudo.clone(), status.clone()));
+            }
+            self.statuses.insert(pseudo, status);
+        }
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+        changes
+    }
+
+    pub fn status(&self, pseudo: &str) -> Status {
+        self.statuses.get(pseudo).cloned().unwrap_or(Status::Offline)
+    }
+
+    /// Returns the connected users, ordered by pseudo.
+    pub fn connected(&self) -> Vec<(String, Status)> {
+        self.statuses.iter().filter(|(_, status)| **status != Status::Offline).map(|(pseudo, status)| (pseudo.clone(), status.clone())).collect()
+    }
+
+    /// Returns the presence of a user as sent to the clients.
+    pub fn to_json(&self, pseudo: &str) -> JsonValue {
+        let status = self.status(pseudo);
+        let mut data = object!{ pseudo: pseudo, status: status.name() };
+        if let Status::Away(message) = status {
+            data["message"] = message.into();
+        }
+        if let Some(date) = self.last_seen.get(pseudo) {
+            data["last_seen"] = date.to_rfc3339_opts(SecondsFormat::Secs, true).into();
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod unit_testing {
+    use super::*;
+    use crate::search::parse_date;
+
+    #[test]
+    fn test_status_changes() {
+        let mut presence = Presence::new();
+        let now = parse_date("2026-10-19T10:00:00Z").unwrap();
+        let connected = HashSet::from([String::from("toto"), String::from("titi")]);
+        assert_eq!(presence.update(&connected, now), vec![(String::from("titi"), Status::Online), (String::from("toto"), Status::Online)]);
+        assert!(presence.update(&connected, now).is_empty());
+
+        presence.set_away("toto", Some(String::from("lunch")));
+        assert_eq!(presence.update(&connected, now), vec![(String::from("toto"), Status::Away(String::from("lunch")))]);
+
+        let later = parse_date("2026-10-19T11:00:00Z").unwrap();
+        let connected = HashSet::from([String::from("titi")]);
+        assert_eq!(presence.update(&connected, later), vec![(String::from("toto"), Status::Offline)]);
+        assert_eq!(presence.to_json("toto")["last_seen"], "2026-10-19T10:00:00Z");
+        assert_eq!(presence.connected(), vec![(String::from("titi"), Status::Online)]);
+
+        // L'absence ne survit pas à la déconnexion
+        let connected = HashSet::from([String::from("titi"), String::from("toto")]);
+        assert_eq!(presence.update(&connected, later), vec![(String::from("toto"), Status::Online)]);
+        assert_eq!(presence.status("tata"), Status::Offline);
+    }
+}
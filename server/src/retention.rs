@@ -0,0 +1,85 @@
+use std::{sync::{Arc, Mutex}, thread, time::Duration};
+use chrono::{DateTime, Utc};
+use crate::{config::RetentionConfig, search::parse_date, storage::{Storage, StoredMessage}, transfer::Transfers};
+
+/// Returns true if the message has to be purged: its own expiry is passed,
+/// or it is older than the retention of its chat.
+pub fn is_expired(config: &RetentionConfig, message: &StoredMessage, now: DateTime<Utc>) -> bool {
+    if message.expires.as_deref().and_then(parse_date).map(|x| x <= now).unwrap_or(false) {
+        return true;
+    }
+    let days = match config.channels.get(&message.channel) {
+        Some(0) => None,
+        Some(days) => Some(*days),
+        None => config.days
+    };
+    // Une rétention trop longue pour calculer une date revient à garder le message
+    let limit = days
+        .and_then(|days| chrono::TimeDelta::try_days(i64::try_from(days).ok()?))
+        .and_then(|retention| parse_date(&message.timestamp)?.checked_add_signed(retention));
+    limit.map(|x| x <= now).unwrap_or(false)
+}
+
+/// Start the thread purging the expired messages of the storage, with the files they were the last to send.
+pub fn start(storage: Arc<Mutex<Box<dyn Storage>>>, transfers: Arc<Mutex<Transfers>>, config: RetentionConfig) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(config.sweep_interval.max(1)));
+        let now = Utc::now();
+        let mut storage = storage.lock().unwrap();
+        let hashes:Vec<String> = storage.attachments().into_keys().collect();
+        let count = storage.purge_messages(&|x| is_expired(&config, x, now));
+        if count > 0 {
+            println!("{} expired messages purged", count);
+            let removed = transfers.lock().unwrap().remove_unused(&hashes, &storage.attachments());
+            if removed > 0 {
+                println!("{} unused files deleted", removed);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod unit_testing {
+    use super::*;
+    use std::collections::{BTreeMap, HashMap};
+
+    fn message(channel: &str, timestamp: &str, expires: Option<&str>) -> StoredMessage {
+        StoredMessage {
+            id: 1,
+            channel: channel.to_string(),
+            from: String::from("toto"),
+            content: String::from("hello"),
+            timestamp: timestamp.to_string(),
+            expires: expires.map(|x| x.to_string()),
+            reply_to: None,
+            mentions: vec![],
+            edited: None,
+            history: vec![],
+            deleted: false,
+            reactions: BTreeMap::new(),
+            attachment: None
+        }
+    }
+
+    #[test]
+    fn test_is_expired() {
+        let now = parse_date("2026-10-19T12:00:00Z").unwrap();
+        let config = RetentionConfig {
+            days: Some(30),
+            channels: HashMap::from([(String::from("short"), 1), (String::from("forever"), 0)]),
+            sweep_interval: 10
+        };
+
+        assert!(!is_expired(&config, &message("general", "2026-10-01T12:00:00Z", None), now));
+        assert!(is_expired(&config, &message("general", "2026-09-01T12:00:00Z", None), now));
+        assert!(is_expired(&config, &message("short", "2026-10-18T11:00:00Z", None), now));
+        assert!(!is_expired(&config, &message("forever", "2020-01-01T00:00:00Z", None), now));
+        assert!(is_expired(&config, &message("forever", "2026-10-19T11:00:00Z", Some("2026-10-19T11:59:00Z")), now));
+        assert!(!is_expired(&config, &message("general", "2026-10-19T11:00:00Z", Some("2026-10-19T12:01:00Z")), now));
+
+        let config = RetentionConfig { days: None, channels: HashMap::new(), sweep_interval: 10 };
+        assert!(!is_expired(&config, &message("general", "2020-01-01T00:00:00Z", None), now));
+        let config = RetentionConfig { days: Some(u64::MAX), channels: HashMap::new(), sweep_interval: 10 };
+        assert!(!is_expired(&config, &message("general", "2020-01-01T00:00:00Z", None), now));
+    }
+}
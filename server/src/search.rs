@@ -0,0 +1,149 @@
+use std::collections::{BTreeSet, HashMap};
+use chrono::{DateTime, NaiveDate, Utc};
+use json::JsonValue;
+use crate::storage::StoredMessage;
+
+/// Split a text into lowercase words.
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_lowercase())
+        .collect()
+}
+
+/// Read a date of a search, either a day ("2026-10-19") or a RFC 3339 date.
+pub fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(date) {
+        return Some(date.with_timezone(&Utc));
+    }
+    let day = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+    Some(day.and_hms_opt(0, 0, 0)?.and_utc())
+}
+
+/// Inverted index of the messages: each word gives the identifiers of the messages containing it.
+#[derive(Default)]
+pub struct SearchIndex {
+    words: HashMap<String, BTreeSet<u64>>
+}
+
+impl SearchIndex {
+    /// Index the words of a message.
+    pub fn add(&mut self, message: &StoredMessage) {
+        for word in tokenize(&message.content) {
+            self.words.entry(word).or_default().insert(message.id);
+        }
+    }
+
+    /// Remove a message from the index.
+    pub fn remove(&mut self, message: &StoredMessage) {
+        for word in tokenize(&message.content) {
+            if let Some(ids) = self.words.get_mut(&word) {
+                ids.remove(&message.id);
+                if ids.is_empty() {
+                    self.words.remove(&word);
+                }
+            }
+        }
+    }
+
+    /// Returns the identifiers of the messages containing all the words, None if no word is given.
+    pub fn find(&self, words: &[String]) -> Option<BTreeSet<u64>> {
+        let mut found: Option<BTreeSet<u64>> = None;
+        for word in words {
+            let ids = self.words.get(word).cloned().unwrap_or_default();
+            found = Some(match found {
+                Some(found) => found.intersection(&ids).cloned().collect(),
+                None => ids
+            });
+        }
+        found
+    }
+}
+
+/// Filters of a search.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SearchQuery {
+    /// Words the messages must all contain.
+    pub words: Vec<String>,
+    /// Author of the messages.
+    pub from: Option<String>,
+    /// Chat of the messages.
+    pub channel: Option<String>,
+    /// Messages received after this date.
+    pub after: Option<DateTime<Utc>>,
+    /// Messages received before this date.
+    pub before: Option<DateTime<Utc>>,
+    /// Chats the requester is allowed to read.
+    pub visible: Vec<String>
+}
+
+impl SearchQuery {
+    /// Read a search request, only the messages of the `visible` chats are searched.
+    pub fn from_json(data: &JsonValue, visible: Vec<String>) -> SearchQuery {
+        SearchQuery {
+            words: tokenize(data["words"].as_str().unwrap_or("")),
+            from: data["from"].as_str().map(|x| x.to_string()),
+            channel: data["channel"].as_str().map(|x| x.to_string()),
+            after: data["after"].as_str().and_then(parse_date),
+            before: data["before"].as_str().and_then(parse_date),
+            visible
+        }
+    }
+
+    /// Returns true if the message matches the filters other than the words.
+    pub fn matches(&self, message: &StoredMessage) -> bool {
+        let date = parse_date(&message.timestamp);
+        self.visible.contains(&message.channel)
+            && self.from.as_ref().map(|x| *x == message.from).unwrap_or(true)
+            && self.channel.as_ref().map(|x| *x == message.channel).unwrap_or(true)
+            && self.after.map(|x| date.map(|date| date >= x).unwrap_or(false)).unwrap_or(true)
+            && self.before.map(|x| date.map(|date| date < x).unwrap_or(false)).unwrap_or(true)
+    }
+}
+
+#[cfg(test)]
+mod unit_testing {
+    use super::*;
+    use std::collections::BTreeMap;
+    use json::object;
+
+    fn message(id: u64, channel: &str, from: &str, content: &str) -> StoredMessage {
+        StoredMessage {
+            id,
+            channel: channel.to_string(),
+            from: from.to_string(),
+            content: content.to_string(),
+            timestamp: String::from("2026-10-19T10:00:00Z"),
+            expires: None,
+            reply_to: None,
+            mentions: vec![],
+            edited: None,
+            history: vec![],
+            deleted: false,
+            reactions: BTreeMap::new(),
+            attachment: None
+        }
+    }
+
+    #[test]
+    fn test_index_and_filters() {
+        let mut index = SearchIndex::default();
+        index.add(&message(1, "general", "toto", "Hello, World!"));
+        index.add(&message(2, "general", "titi", "hello rust"));
+        index.add(&message(3, "general", "toto", "the world of rust"));
+
+        assert_eq!(index.find(&tokenize("HELLO")), Some(BTreeSet::from([1, 2])));
+        assert_eq!(index.find(&tokenize("rust world")), Some(BTreeSet::from([3])));
+        assert_eq!(index.find(&tokenize("python")), Some(BTreeSet::new()));
+        assert_eq!(index.find(&[]), None);
+        index.remove(&message(1, "general", "toto", "Hello, World!"));
+        assert_eq!(index.find(&tokenize("hello")), Some(BTreeSet::from([2])));
+
+        let query = SearchQuery::from_json(&object!{ from: "toto", after: "2026-10-19", before: "2026-10-20" }, vec![String::from("general")]);
+        assert!(query.matches(&message(1, "general", "toto", "")));
+        assert!(!query.matches(&message(1, "general", "titi", "")));
+        assert!(!query.matches(&message(1, "secret", "toto", "")));
+        let query = SearchQuery::from_json(&object!{ before: "2026-10-19" }, vec![String::from("general")]);
+        assert!(!query.matches(&message(1, "general", "toto", "")));
+    }
+}
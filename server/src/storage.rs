@@ -0,0 +1,451 @@
+use std::{cmp::Ordering, collections::{BTreeMap, HashMap, HashSet}};
+use chrono::{DateTime, Utc};
+use json::{self, object, JsonValue};
+use crate::{config::{EncryptionConfig, StorageConfig}, search::SearchQuery};
+
+mod cipher;
+mod data_dir;
+mod events;
+mod file;
+mod memory;
+
+pub use cipher::Cipher;
+use data_dir::DataDir;
+pub use events::EventStorage;
+pub use file::FileStorage;
+pub use memory::MemoryStorage;
+
+/// Maximum number of sessions opened at the same time by a user, e.g. on several computers.
+pub const MAX_SESSIONS: usize = 5;
+
+/// Account of a user, kept between two runs of the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Account {
+    /// The pseudo the user will use inside the chat.
+    pub pseudo: String,
+    /// Hash of the password, empty if the password is checked by an external backend.
+    pub pwd: String,
+    /// Hex encoded Ed25519 public keys allowed to log in as this user.
+    pub keys: Vec<String>
+}
+
+impl Account {
+    pub fn new(pseudo: String, pwd: String) -> Account {
+        Account { pseudo, pwd, keys: vec![] }
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        object!{
+            pseudo: self.pseudo.clone(),
+            pwd: self.pwd.clone(),
+            keys: self.keys.clone(),
+        }
+    }
+
+    pub fn from_json(data: &JsonValue) -> Account {
+        Account {
+            pseudo: data["pseudo"].to_string(),
+            pwd: data["pwd"].as_str().unwrap_or("").to_string(),
+            keys: data["keys"].members().map(|x| x.to_string()).collect()
+        }
+    }
+}
+
+/// Message accepted by the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredMessage {
+    /// Identifier given by the server, increasing with time.
+    pub id: u64,
+    /// Chat the message was sent to.
+    pub channel: String,
+    /// Pseudo of the author.
+    pub from: String,
+    /// Content of the message sent.
+    pub content: String,
+    /// UTC date of reception, RFC 3339 formatted.
+    pub timestamp: String,
+    /// UTC date the message disappears, chosen by the author.
+    pub expires: Option<String>,
+    /// Identifier of the message this one replies to.
+    pub reply_to: Option<u64>,
+    /// Pseudos of the users notified by the mentions of the message.
+    pub mentions: Vec<String>,
+    /// UTC date of the last edit, None if the message was never edited.
+    pub edited: Option<String>,
+    /// Previous contents of an edited message, from the oldest to the newest.
+    pub history: Vec<Revision>,
+    /// True once deleted by its author or a moderator, its content, history and attachment are then removed.
+    pub deleted: bool,
+    /// Pseudos of the users who reacted to the message, for each emoji or shortcode.
+    pub reactions: BTreeMap<String, Vec<String>>,
+    /// File sent with the message.
+    pub attachment: Option<Attachment>
+}
+
+/// Previous content of an edited message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Revision {
+    pub content: String,
+    /// UTC date the content was written.
+    pub timestamp: String
+}
+
+/// File attached to a message, its content is kept by the transfer module.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attachment {
+    /// Name of the file, without its directory.
+    pub name: String,
+    /// Size in bytes.
+    pub size: u64,
+    /// Hex encoded SHA-256 of the content.
+    pub hash: String
+}
+
+impl Attachment {
+    pub fn to_json(&self) -> JsonValue {
+        object!{
+            name: self.name.clone(),
+            size: self.size,
+            hash: self.hash.clone(),
+        }
+    }
+
+    pub fn from_json(data: &JsonValue) -> Attachment {
+        Attachment {
+            name: data["name"].to_string(),
+            size: data["size"].as_u64().unwrap_or(0),
+            hash: data["hash"].to_string()
+        }
+    }
+}
+
+impl StoredMessage {
+    pub fn to_json(&self) -> JsonValue {
+        object!{
+            id: self.id,
+            channel: self.channel.clone(),
+            from: self.from.clone(),
+            content: self.content.clone(),
+            timestamp: self.timestamp.clone(),
+            expires: self.expires.clone(),
+            reply_to: self.reply_to,
+            mentions: self.mentions.clone(),
+            edited: self.edited.clone(),
+            history: JsonValue::Array(self.history.iter().map(|x| object!{ content: x.content.clone(), timestamp: x.timestamp.clone() }).collect()),
+            deleted: self.deleted,
+            reactions: self.reactions.clone(),
+            attachment: self.attachment.as_ref().map(|x| x.to_json()),
+        }
+    }
+
+    /// Returns the message as sent to the clients, without its history and its mentions which may not fit in a frame,
+    /// and with the number of users for each reaction.
+    pub fn to_frame(&self) -> JsonValue {
+        let mut data = self.to_json();
+        data.remove("history");
+        data.remove("mentions");
+        data["reactions"] = self.reaction_counts();
+        data
+    }
+
+    /// Returns the number of users who reacted to the message, for each reaction.
+    pub fn reaction_counts(&self) -> JsonValue {
+        let mut counts = JsonValue::new_object();
+        for (reaction, pseudos) in &self.reactions {
+            counts[reaction.as_str()] = pseudos.len().into();
+        }
+        counts
+    }
+
+    pub fn from_json(data: &JsonValue) -> StoredMessage {
+        StoredMessage {
+            id: data["id"].as_u64().unwrap_or(0),
+            channel: data["channel"].to_string(),
+            from: data["from"].to_string(),
+            content: data["content"].to_string(),
+            timestamp: data["timestamp"].to_string(),
+            expires: data["expires"].as_str().map(|x| x.to_string()),
+            reply_to: data["reply_to"].as_u64(),
+            mentions: data["mentions"].members().map(|x| x.to_string()).collect(),
+            edited: data["edited"].as_str().map(|x| x.to_string()),
+            history: data["history"].members().map(|x| Revision { content: x["content"].to_string(), timestamp: x["timestamp"].to_string() }).collect(),
+            deleted: data["deleted"].as_bool().unwrap_or(false),
+            reactions: data["reactions"].entries().map(|(reaction, pseudos)| (reaction.to_string(), pseudos.members().map(|x| x.to_string()).collect())).collect(),
+            attachment: if data["attachment"].is_object() { Some(Attachment::from_json(&data["attachment"])) } else { None }
+        }
+    }
+
+    /// Returns the line displayed by the clients.
+    pub fn to_line(&self) -> String {
+        format!("{} : {}", self.from, self.content)
+    }
+}
+
+/// Private message waiting for its recipient.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueuedMessage {
+    /// Pseudo of the author.
+    pub from: String,
+    /// Content of the message sent.
+    pub content: String,
+    /// UTC date of reception, RFC 3339 formatted.
+    pub timestamp: String
+}
+
+impl QueuedMessage {
+    fn to_json(&self) -> JsonValue {
+        object!{
+            from: self.from.clone(),
+            content: self.content.clone(),
+            timestamp: self.timestamp.clone(),
+        }
+    }
+
+    fn from_json(data: &JsonValue) -> QueuedMessage {
+        QueuedMessage {
+            from: data["from"].to_string(),
+            content: data["content"].to_string(),
+            timestamp: data["timestamp"].to_string()
+        }
+    }
+}
+
+/// Position inside the history of a chat.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cursor {
+    /// Identifier of a message.
+    Id(u64),
+    /// Date of reception.
+    Time(DateTime<Utc>)
+}
+
+impl Cursor {
+    /// Read a cursor from a request, a number is an identifier and a string a RFC 3339 date.
+    /// Returns None if the value isn't a valid cursor.
+    pub fn from_json(data: &JsonValue) -> Option<Cursor> {
+        if let Some(id) = data.as_u64() {
+            return Some(Cursor::Id(id));
+        }
+        let time = DateTime::parse_from_rfc3339(data.as_str()?).ok()?;
+        Some(Cursor::Time(time.with_timezone(&Utc)))
+    }
+
+    /// Compare the position of a message with the cursor.
+    fn cmp(&self, message: &StoredMessage) -> Ordering {
+        match self {
+            Cursor::Id(id) => message.id.cmp(id),
+            Cursor::Time(time) => match DateTime::parse_from_rfc3339(&message.timestamp) {
+                Ok(date) => date.with_timezone(&Utc).cmp(time),
+                Err(_) => Ordering::Less
+            }
+        }
+    }
+}
+
+/// Direction of a page of history, relatively to its cursor.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// Messages older than the cursor, the latest ones without cursor.
+    Before,
+    /// Messages newer than the cursor, the first ones without cursor.
+    After
+}
+
+/// Place where the state of the server is kept: users, sessions, chats and messages.
+/// The connection handlers only use this trait, so the backends can be swapped.
+pub trait Storage: Send {
+    /// Name of the backend, displayed in the server logs.
+    fn name(&self) -> &str;
+
+    /// Returns all the accounts.
+    fn accounts(&self) -> Vec<Account>;
+
+    /// Find the account of a pseudo.
+    fn account(&self, pseudo: &str) -> Option<Account>;
+
+    /// Returns true if the pseudo is used by an account.
+    fn exists(&self, pseudo: &str) -> bool {
+        self.account(pseudo).is_some()
+    }
+
+    /// Add a new account.
+    /// Returns false if the pseudo is already taken.
+    fn insert_account(&mut self, account: Account) -> bool;
+
+    /// Replace the password hash of an account.
+    /// Returns false if the account doesn't exist.
+    fn set_pwd(&mut self, pseudo: &str, pwd: String) -> bool;
+
+    /// Register a new public key for the account.
+    /// Returns false if the account doesn't exist or already owns the key.
+    fn add_key(&mut self, pseudo: &str, key: String) -> bool;
+
+    /// Remove a public key of the account.
+    /// Returns false if the account doesn't own the key.
+    fn remove_key(&mut self, pseudo: &str, key: &str) -> bool;
+
+    /// Delete an account, with its session, its memberships and its waiting private messages.
+    /// Returns false if the account doesn't exist.
+    fn delete_account(&mut self, pseudo: &str) -> bool;
+
+    /// Returns true if the token is the one of a session opened by the user.
+    fn has_session(&self, pseudo: &str, token: &str) -> bool;
+
+    /// Open a new session, the oldest one is closed when the user already has `MAX_SESSIONS` sessions.
+    fn add_session(&mut self, pseudo: &str, token: String);
+
+    /// Close all the sessions of the user.
+    fn clear_sessions(&mut self, pseudo: &str);
+
+    /// Returns the names of the chats.
+    fn channels(&self) -> Vec<String>;
+
+    /// Create a chat.
+    /// Returns false if the name is already taken.
+    fn create_channel(&mut self, name: &str) -> bool;
+
+    /// Returns the pseudos of the members of a chat.
+    fn members(&self, channel: &str) -> Vec<String>;
+
+    /// Returns true if the user is a member of the chat.
+    fn is_member(&self, channel: &str, pseudo: &str) -> bool {
+        self.members(channel).iter().any(|x| x == pseudo)
+    }
+
+    /// Add a user to the members of a chat.
+    /// Returns false if the chat doesn't exist or the user is already a member.
+    fn join_channel(&mut self, channel: &str, pseudo: &str) -> bool;
+
+    /// Remove a user from the members of a chat.
+    /// Returns false if the user isn't a member.
+    fn leave_channel(&mut self, channel: &str, pseudo: &str) -> bool;
+
+    /// Returns the pseudos of the users kicked from a chat, who can't join it again.
+    fn kicked(&self, channel: &str) -> Vec<String>;
+
+    /// Returns true if the user was kicked from the chat.
+    fn is_kicked(&self, channel: &str, pseudo: &str) -> bool {
+        self.kicked(channel).iter().any(|x| x == pseudo)
+    }
+
+    /// Forbid a user to join a chat again, or let them back in.
+    /// Returns false if nothing changed.
+    fn set_kicked(&mut self, channel: &str, pseudo: &str, kicked: bool) -> bool;
+
+    /// Returns the topic of a chat, None if it has no topic.
+    fn topic(&self, channel: &str) -> Option<String>;
+
+    /// Change the topic of a chat, an empty topic removes it.
+    /// Returns false if the chat doesn't exist.
+    fn set_topic(&mut self, channel: &str, topic: &str) -> bool;
+
+    /// Rename a chat, its members, its topic and its messages are kept.
+    /// Returns false if the chat doesn't exist, is the general chat or the name is already taken.
+    fn rename_channel(&mut self, channel: &str, name: &str) -> bool;
+
+    /// Returns the chats the user is a member of.
+    fn channels_of(&self, pseudo: &str) -> Vec<String> {
+        self.channels().into_iter().filter(|x| self.is_member(x, pseudo)).collect()
+    }
+
+    /// Returns the number of messages stored.
+    fn message_count(&self) -> usize;
+
+    /// Store a new message, `expires` is the date it disappears, `reply_to` the message it replies to
+    /// and `mentions` the users notified by its mentions.
+    /// Returns the message with its identifier and timestamp.
+    fn append_message(&mut self, channel: &str, from: &str, content: &str, expires: Option<String>, reply_to: Option<u64>, mentions: Vec<String>) -> StoredMessage;
+
+    /// Store a message coming from another server, it keeps its author and its dates.
+    /// Returns the message with its new identifier.
+    fn import_message(&mut self, message: StoredMessage) -> StoredMessage;
+
+    /// Find a message by its identifier.
+    fn message(&self, id: u64) -> Option<StoredMessage>;
+
+    /// Returns at most `limit` messages mentioning the user in the chats it is a member of, from the newest to the oldest.
+    fn mentions_of(&self, pseudo: &str, limit: usize) -> Vec<StoredMessage> {
+        let mut messages:Vec<StoredMessage> = self.channels_of(pseudo).iter()
+            .flat_map(|channel| self.messages(channel, None, Direction::After, usize::MAX))
+            .filter(|x| !x.deleted && x.mentions.iter().any(|x| x == pseudo))
+            .collect();
+        messages.sort_by_key(|x| std::cmp::Reverse(x.id));
+        messages.truncate(limit);
+        messages
+    }
+
+    /// Returns the thread of a message: the first message of the thread and all its replies, from the oldest to the newest.
+    fn thread(&self, id: u64) -> Vec<StoredMessage> {
+        // Le premier message du fil est celui qui ne répond à aucun autre
+        let mut root = match self.message(id) {
+            Some(message) => message,
+            None => return vec![]
+        };
+        while let Some(parent) = root.reply_to.and_then(|x| self.message(x)) {
+            root = parent;
+        }
+        // Les réponses sont plus récentes que leur parent : un seul parcours suffit
+        let mut ids = HashSet::from([root.id]);
+        let mut thread = vec![];
+        for message in self.messages(&root.channel, Some(&Cursor::Id(root.id)), Direction::After, usize::MAX) {
+            if message.reply_to.map(|x| ids.contains(&x)).unwrap_or(false) {
+                ids.insert(message.id);
+                thread.push(message);
+            }
+        }
+        thread.insert(0, root);
+        thread
+    }
+
+    /// Replace the content of a message, the previous content is kept in its history.
+    /// Returns the edited message, None if the message doesn't exist or was deleted.
+    fn edit_message(&mut self, id: u64, content: &str) -> Option<StoredMessage>;
+
+    /// Remove the content, the history and the reactions of a message, it stays in the history of its chat as deleted.
+    /// Returns the deleted message, None if the message doesn't exist or was already deleted.
+    fn delete_message(&mut self, id: u64) -> Option<StoredMessage>;
+
+    /// Add the reaction of a user to a message, or remove it if the user already reacted the same way.
+    /// Returns the message with its reactions, None if the message doesn't exist or was deleted.
+    fn react(&mut self, id: u64, pseudo: &str, reaction: &str) -> Option<StoredMessage>;
+
+    /// Delete the messages for which `expired` returns true.
+    /// Returns the number of messages deleted.
+    fn purge_messages(&mut self, expired: &dyn Fn(&StoredMessage) -> bool) -> usize;
+
+    /// Returns the number of messages sending each stored file, by hash of the file.
+    fn attachments(&self) -> HashMap<String, usize>;
+
+    /// Returns at most `limit` messages of a chat next to the cursor, from the oldest to the newest.
+    fn messages(&self, channel: &str, cursor: Option<&Cursor>, direction: Direction, limit: usize) -> Vec<StoredMessage>;
+
+    /// Returns at most `limit` messages matching the search, from the newest to the oldest.
+    fn search(&self, query: &SearchQuery, limit: usize) -> Vec<StoredMessage>;
+
+    /// Returns the `count` last messages of a chat, from the oldest to the newest.
+    fn last_messages(&self, channel: &str, count: usize) -> Vec<StoredMessage> {
+        self.messages(channel, None, Direction::Before, count)
+    }
+
+    /// Returns the number of private messages waiting for a user.
+    fn queue_len(&self, pseudo: &str) -> usize;
+
+    /// Keep a private message until the recipient comes back.
+    fn queue_push(&mut self, to: &str, from: &str, content: &str);
+
+    /// Remove the private messages waiting for a user.
+    /// Returns the messages from the oldest to the newest.
+    fn queue_take(&mut self, pseudo: &str) -> Vec<QueuedMessage>;
+
+    /// Put back at the front of the queue the messages which couldn't be delivered.
+    fn queue_restore(&mut self, pseudo: &str, messages: Vec<QueuedMessage>);
+}
+
+/// Build the storage selected in the configuration, `encryption` only applies to the data directory.
+pub fn from_config(config: &StorageConfig, data_dir: &str, encryption: Option<&EncryptionConfig>) -> Box<dyn Storage> {
+    match config {
+        StorageConfig::Memory => Box::new(MemoryStorage::new()),
+        StorageConfig::File => Box::new(FileStorage::open(data_dir, encryption.map(Cipher::from_config))),
+        StorageConfig::Events(interval) => Box::new(EventStorage::open(data_dir, encryption.map(Cipher::from_config), *interval))
+    }
+}
@@ -0,0 +1,130 @@
+use std::{collections::HashMap, fs};
+use openssl::{hash::MessageDigest, pkcs5::pbkdf2_hmac, rand::rand_bytes, symm::{self, Cipher as Algorithm}};
+use crate::config::{EncryptionConfig, KeySource};
+
+/// Prefix of the encrypted records, followed by the key id, the nonce and the encrypted data.
+const PREFIX: &str = "enc1";
+/// Iterations of the key derivation, done once per key when the server starts.
+const ITERATIONS: usize = 100_000;
+/// Size of the authentication tag appended to the encrypted data.
+const TAG_SIZE: usize = 16;
+
+/// Encryption of the records written in the data directory, with AES-256-GCM.
+/// A record is written as "enc1:key id:nonce:data", the nonce and the data being hex encoded.
+pub struct Cipher {
+    /// Key encrypting the new records.
+    current: String,
+    keys: HashMap<String, [u8; 32]>,
+    /// Records written in clear are accepted, only while migrating an existing data directory.
+    migrate: bool
+}
+
+impl Cipher {
+    /// Derive the keys given by the operator.
+    pub fn from_config(config: &EncryptionConfig) -> Cipher {
+        let current = config.keys.first().expect("No encryption key configured").id.clone();
+        let mut keys = HashMap::new();
+        for key in &config.keys {
+            let secret = match &key.secret {
+                KeySource::Secret(secret) => secret.as_bytes().to_vec(),
+                KeySource::File(file) => fs::read(file).expect("Unable to read encryption key file")
+            };
+            keys.insert(key.id.clone(), derive(&key.id, &secret));
+        }
+        Cipher { current, keys, migrate: config.migrate }
+    }
+
+    /// Returns true if the record was written encrypted.
+    pub fn is_encrypted(record: &str) -> bool {
+        record.starts_with(PREFIX) && record[PREFIX.len()..].starts_with(':')
+    }
+
+    /// Returns true if the records written in clear are accepted.
+    pub fn migrate(&self) -> bool {
+        self.migrate
+    }
+
+    /// Name of the key encrypting the new records.
+    pub fn current(&self) -> &str {
+        &self.current
+    }
+
+    /// Encrypt a record with the current key.
+    pub fn encrypt(&self, text: &str) -> String {
+        let mut nonce = [0u8; 12];
+        rand_bytes(&mut nonce).expect("Unable to generate a nonce");
+        let mut tag = [0u8; TAG_SIZE];
+        let mut data = symm::encrypt_aead(Algorithm::aes_256_gcm(), &self.keys[&self.current], Some(&nonce), self.current.as_bytes(), text.as_bytes(), &mut tag)
+            .expect("Unable to encrypt a record");
+        data.extend_from_slice(&tag);
+        format!("{}:{}:{}:{}", PREFIX, self.current, hex::encode(nonce), hex::encode(data))
+    }
+
+    /// Decrypt a record, a record written in clear is only returned as is while migrating.
+    /// Returns the text and true if the record has to be written again with the current key.
+    pub fn decrypt(&self, record: &str) -> Result<(String, bool), String> {
+        let parts:Vec<&str> = record.trim_end().split(':').collect();
+        if parts.len() != 4 || parts[0] != PREFIX {
+            // Un enregistrement en clair peut avoir été écrit par un tiers à la place d'un enregistrement chiffré
+            if !self.migrate {
+                return Err(String::from("record written in clear, set \"migrate\" to encrypt existing data"));
+            }
+            return Ok((record.to_string(), true));
+        }
+        let key = self.keys.get(parts[1]).ok_or(format!("unknown encryption key {}", parts[1]))?;
+        let nonce = hex::decode(parts[2]).map_err(|_| String::from("invalid nonce"))?;
+        let data = hex::decode(parts[3]).map_err(|_| String::from("invalid data"))?;
+        if data.len() < TAG_SIZE {
+            return Err(String::from("truncated record"));
+        }
+        let (data, tag) = data.split_at(data.len() - TAG_SIZE);
+        let text = symm::decrypt_aead(Algorithm::aes_256_gcm(), key, Some(&nonce), parts[1].as_bytes(), data, tag)
+            .map_err(|_| format!("wrong key {} or altered record", parts[1]))?;
+        let text = String::from_utf8(text).map_err(|_| String::from("invalid text"))?;
+        Ok((text, parts[1] != self.current))
+    }
+}
+
+/// Derive a 256 bits key from the secret of the operator, the key id is used as salt.
+fn derive(id: &str, secret: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2_hmac(secret, format!("rust-messaging:{}", id).as_bytes(), ITERATIONS, MessageDigest::sha256(), &mut key)
+        .expect("Unable to derive the encryption key");
+    key
+}
+
+#[cfg(test)]
+mod unit_testing {
+    use super::*;
+    use crate::config::KeyConfig;
+
+    fn key(id: &str, secret: &str) -> KeyConfig {
+        KeyConfig { id: id.to_string(), secret: KeySource::Secret(secret.to_string()) }
+    }
+
+    fn config(keys: Vec<KeyConfig>) -> EncryptionConfig {
+        EncryptionConfig { keys, migrate: false }
+    }
+
+    #[test]
+    fn test_encrypt_and_rotate() {
+        let old = Cipher::from_config(&config(vec![key("old", "first secret")]));
+        let record = old.encrypt("hello");
+        assert!(record.starts_with("enc1:old:"));
+        assert!(!record.contains("hello"));
+        assert_ne!(record, old.encrypt("hello"));
+        assert_eq!(old.decrypt(&record), Ok((String::from("hello"), false)));
+
+        let new = Cipher::from_config(&config(vec![key("new", "second secret"), key("old", "first secret")]));
+        assert_eq!(new.decrypt(&record), Ok((String::from("hello"), true)));
+        // Les enregistrements en clair ne sont acceptés que pendant une migration
+        assert!(new.decrypt("{\"clear\": true}").is_err());
+        let migrate = Cipher::from_config(&EncryptionConfig { migrate: true, ..config(vec![key("new", "second secret")]) });
+        assert_eq!(migrate.decrypt("{\"clear\": true}"), Ok((String::from("{\"clear\": true}"), true)));
+        assert!(old.decrypt(&new.encrypt("hello")).is_err());
+
+        let wrong = Cipher::from_config(&config(vec![key("old", "wrong secret")]));
+        assert!(wrong.decrypt(&record).is_err());
+        assert!(old.decrypt(&record[..record.len() - 2]).is_err());
+    }
+}
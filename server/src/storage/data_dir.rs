@@ -0,0 +1,104 @@
+use std::{fs::{self, File, OpenOptions}, io::Write, path::PathBuf};
+use json::{self, JsonValue};
+use super::Cipher;
+
+/// Files of the data directory, encrypted when a cipher is given.
+pub struct DataDir {
+    dir: PathBuf,
+    cipher: Option<Cipher>
+}
+
+impl DataDir {
+    /// Open the data directory, the directory is created if needed.
+    pub fn open(data_dir: &str, cipher: Option<Cipher>) -> DataDir {
+        fs::create_dir_all(data_dir).expect("Unable to create data directory");
+        DataDir { dir: PathBuf::from(data_dir), cipher }
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    pub fn cipher(&self) -> Option<&Cipher> {
+        self.cipher.as_ref()
+    }
+
+    /// Read a JSON file, None if the file doesn't exist.
+    /// `stale` is set if the file has to be encrypted again.
+    pub fn read_json(&self, name: &str, stale: &mut bool) -> Option<JsonValue> {
+        let content = fs::read_to_string(self.path(name)).ok()?;
+        let (text, old) = self.decrypt(&content).unwrap_or_else(|err| panic!("Unable to read {}: {}", name, err));
+        *stale |= old;
+        Some(json::parse(text.as_str()).unwrap_or_else(|_| panic!("Corrupted data file {}", name)))
+    }
+
+    /// Read the JSON lines of a file, the lines which aren't valid JSON are ignored.
+    /// `stale` is set if a line has to be encrypted again.
+    /// Returns the lines and true if the last line was partially written.
+    pub fn read_lines(&self, name: &str, stale: &mut bool) -> (Vec<JsonValue>, bool) {
+        let content = fs::read_to_string(self.path(name)).unwrap_or_default();
+        let lines:Vec<&str> = content.lines().collect();
+        let mut values = vec![];
+        for (index, line) in lines.iter().enumerate() {
+            let last = index + 1 == lines.len();
+            let text = match self.decrypt(line) {
+                Ok((text, old)) => {
+                    *stale |= old;
+                    text
+                }
+                // Une ligne partiellement écrite lors d'un arrêt brutal est ignorée
+                Err(_) if last => return (values, true),
+                Err(err) => panic!("Unable to read {}: {}", name, err)
+            };
+            match json::parse(&text) {
+                Ok(value) => values.push(value),
+                Err(_) if last => return (values, true),
+                Err(_) => ()
+            }
+        }
+        (values, false)
+    }
+
+    /// Append a line to a file.
+    pub fn append(&self, name: &str, line: String) {
+        let file = self.path(name);
+        let result = OpenOptions::new().create(true).append(true).open(&file)
+            .and_then(|mut out| writeln!(out, "{}", self.encrypt(line)));
+        if let Err(err) = result {
+            println!("Unable to write in {}: {}", file.display(), err);
+        }
+    }
+
+    /// Replace a JSON file.
+    pub fn write(&self, name: &str, data: JsonValue) {
+        self.replace(name, self.encrypt(json::stringify_pretty(data, 2)));
+    }
+
+    /// Replace a file through a temporary file, so a crash never leaves a partially written file.
+    pub fn replace(&self, name: &str, content: String) {
+        let file = self.path(name);
+        let tmp = file.with_extension("tmp");
+        let result = File::create(&tmp)
+            .and_then(|mut out| out.write_all(content.as_bytes()).and_then(|_| out.sync_all()))
+            .and_then(|_| fs::rename(&tmp, &file));
+        if let Err(err) = result {
+            println!("Unable to save {}: {}", file.display(), err);
+        }
+    }
+
+    pub fn encrypt(&self, text: String) -> String {
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(&text),
+            None => text
+        }
+    }
+
+    /// Returns the text of a record and true if it has to be encrypted again.
+    fn decrypt(&self, record: &str) -> Result<(String, bool), String> {
+        match &self.cipher {
+            Some(cipher) => cipher.decrypt(record),
+            None if Cipher::is_encrypted(record) => Err(String::from("encrypted data but no encryption key configured")),
+            None => Ok((record.to_string(), false))
+        }
+    }
+}
@@ -0,0 +1,494 @@
+use std::{collections::HashMap, fs};
+use chrono::{SecondsFormat, Utc};
+use json::{self, object, JsonValue};
+use crate::search::SearchQuery;
+use super::{Account, Cipher, Cursor, DataDir, Direction, MemoryStorage, QueuedMessage, Storage, StoredMessage};
+
+/// Directory of the event log, inside the data directory.
+const EVENTS: &str = "events";
+
+/// State rebuilt from an append-only log of events, each change of the state being one event.
+/// The log is split in segments "events/<first sequence number>.jsonl", a new segment is started
+/// after each snapshot of the state ("snapshot.json"), so the server only replays the last segment when it starts.
+/// The segments are kept as the history of the server, only the content of the deleted or purged messages
+/// is removed from them.
+pub struct EventStorage {
+    dir: DataDir,
+    state: MemoryStorage,
+    /// Sequence number of the last event.
+    seq: u64,
+    /// Segment receiving the new events.
+    segment: String,
+    /// Events recorded since the last snapshot.
+    pending: u64,
+    /// Number of events between two snapshots.
+    snapshot_interval: u64
+}
+
+impl EventStorage {
+    /// Open the event log of the data directory, the state is rebuilt from the last snapshot and the following events.
+    pub fn open(data_dir: &str, cipher: Option<Cipher>, snapshot_interval: u64) -> EventStorage {
+        let dir = DataDir::open(data_dir, cipher);
+        fs::create_dir_all(dir.path(EVENTS)).expect("Unable to create events directory");
+        let mut storage = EventStorage { dir, state: MemoryStorage::new(), seq: 0, segment: String::new(), pending: 0, snapshot_interval };
+        let mut stale = false;
+
+        if let Some(snapshot) = storage.dir.read_json("snapshot.json", &mut stale) {
+            storage.seq = snapshot["seq"].as_u64().unwrap_or(0);
+            storage.state.load_accounts(&snapshot["users"]);
+            storage.state.load_sessions(&snapshot["sessions"]);
+            storage.state.load_channels(&snapshot["channels"]);
+            storage.state.load_members(&snapshot["members"]);
+            storage.state.load_topics(&snapshot["topics"]);
+            storage.state.load_kicked(&snapshot["kicked"]);
+            storage.state.load_queues(&snapshot["queues"]);
+            storage.state.load_next_id(snapshot["next_id"].as_u64().unwrap_or(1));
+            for message in snapshot["messages"].members() {
+                storage.state.push_message(StoredMessage::from_json(message));
+            }
+        }
+
+        let segments = storage.segments();
+        let mut partial = false;
+        for (index, (first, name)) in segments.iter().enumerate() {
+            // Les segments entièrement couverts par l'instantané ne sont pas relus
+            if segments.get(index + 1).map(|(next, _)| *next <= storage.seq + 1).unwrap_or(false) {
+                continue;
+            }
+            if *first > storage.seq + 1 {
+                panic!("Missing events before {}", name);
+            }
+            let (events, incomplete) = storage.dir.read_lines(name, &mut stale);
+            partial = incomplete;
+            for event in &events {
+                let seq = event["seq"].as_u64().unwrap_or(0);
+                if seq > storage.seq {
+                    storage.apply(event);
+                    storage.seq = seq;
+                    storage.pending += 1;
+                }
+            }
+            storage.segment = name.clone();
+        }
+        if storage.pending > 0 {
+            println!("{} events replayed", storage.pending);
+        }
+
+        if stale && storage.dir.cipher().is_some() {
+            // Le journal et l'instantané sont réécrits avec la clé courante
+            storage.rewrite_segments(&|_| true);
+            storage.snapshot();
+        } else if storage.segment.is_empty() || partial {
+            // Les événements suivants sont écrits dans un nouveau segment, après la ligne incomplète
+            storage.segment = segment_name(storage.seq + 1);
+        }
+        storage
+    }
+
+    /// Returns the segments of the log, ordered by their first sequence number.
+    fn segments(&self) -> Vec<(u64, String)> {
+        let mut segments:Vec<(u64, String)> = fs::read_dir(self.dir.path(EVENTS)).expect("Unable to read events directory")
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name().into_string().ok()?;
+                let first = name.strip_suffix(".jsonl")?.parse().ok()?;
+                Some((first, format!("{}/{}", EVENTS, name)))
+            })
+            .collect();
+        segments.sort();
+        segments
+    }
+
+    /// Append an event to the log, a snapshot is taken when enough events were recorded.
+    fn record(&mut self, mut event: JsonValue) {
+        self.seq += 1;
+        event["seq"] = self.seq.into();
+        event["time"] = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true).into();
+        self.dir.append(&self.segment, json::stringify(event));
+        self.pending += 1;
+        if self.snapshot_interval > 0 && self.pending >= self.snapshot_interval {
+            self.snapshot();
+        }
+    }
+
+    /// Save the whole state and start a new segment of the log.
+    fn snapshot(&mut self) {
+        self.dir.write("snapshot.json", object!{
+            seq: self.seq,
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            users: self.state.accounts_json(),
+            sessions: self.state.sessions_json(),
+            channels: self.state.channels_json(),
+            members: self.state.members_json(),
+            topics: self.state.topics_json(),
+            kicked: self.state.kicked_json(),
+            queues: self.state.queues_json(),
+            messages: JsonValue::Array(self.state.all_messages().iter().map(|x| x.to_json()).collect()),
+            next_id: self.state.next_id(),
+        });
+        self.segment = segment_name(self.seq + 1);
+        self.pending = 0;
+    }
+
+    /// Rewrite the segments of the log in which `change` modifies an event.
+    fn rewrite_segments(&self, change: &dyn Fn(&mut JsonValue) -> bool) {
+        for (_, name) in self.segments() {
+            let (mut events, _) = self.dir.read_lines(&name, &mut false);
+            let mut changed = false;
+            for event in events.iter_mut() {
+                changed |= change(event);
+            }
+            if changed {
+                let lines:String = events.into_iter().map(|x| self.dir.encrypt(json::stringify(x)) + "\n").collect();
+                self.dir.replace(&name, lines);
+            }
+        }
+    }
+
+    /// Remove the content of messages from the log and the snapshot, their other events are kept.
+    fn redact(&self, ids: &[u64]) {
+        self.rewrite_segments(&|event| match event["type"].as_str() {
+            Some("message") => redact_message(&mut event["message"], ids),
+            Some("edit") if event["id"].as_u64().is_some_and(|x| ids.contains(&x)) => {
+                event["content"] = "".into();
+                true
+            }
+            _ => false
+        });
+        let mut stale = false;
+        if let Some(mut snapshot) = self.dir.read_json("snapshot.json", &mut stale) {
+            let mut changed = false;
+            for message in snapshot["messages"].members_mut() {
+                changed |= redact_message(message, ids);
+            }
+            if changed {
+                self.dir.write("snapshot.json", snapshot);
+            }
+        }
+    }
+
+    /// Apply an event of the log to the state.
+    fn apply(&mut self, event: &JsonValue) {
+        let pseudo = event["pseudo"].as_str().unwrap_or("");
+        match event["type"].as_str().unwrap_or("") {
+            "register" => { self.state.insert_account(Account::from_json(&event["account"])); }
+            "password" => { self.state.set_pwd(pseudo, event["pwd"].to_string()); }
+            "key" => { self.state.add_key(pseudo, event["key"].to_string()); }
+            "remove_key" => { self.state.remove_key(pseudo, &event["key"].to_string()); }
+            "delete" => { self.state.delete_account(pseudo); }
+            "login" => self.state.add_session(pseudo, event["token"].to_string()),
+            "logout" => self.state.clear_sessions(pseudo),
+            "channel" => { self.state.create_channel(&event["name"].to_string()); }
+            "join" => { self.state.join_channel(&event["channel"].to_string(), pseudo); }
+            "leave" => { self.state.leave_channel(&event["channel"].to_string(), pseudo); }
+            "kick" => { self.state.set_kicked(&event["channel"].to_string(), pseudo, event["kicked"].as_bool().unwrap_or(false)); }
+            "topic" => { self.state.set_topic(&event["channel"].to_string(), &event["topic"].to_string()); }
+            "rename" => { self.state.rename_channel(&event["channel"].to_string(), &event["name"].to_string()); }
+            "message" => self.state.push_message(StoredMessage::from_json(&event["message"])),
+            "edit" => { self.state.revise(event["id"].as_u64().unwrap_or(0), &event["content"].to_string(), event["edited"].to_string()); }
+            "react" => { self.state.react(event["id"].as_u64().unwrap_or(0), pseudo, &event["reaction"].to_string()); }
+            "delete_message" => { self.state.delete_message(event["id"].as_u64().unwrap_or(0)); }
+            "purge" => {
+                let ids:Vec<u64> = event["ids"].members().filter_map(|x| x.as_u64()).collect();
+                self.state.purge_messages(&|x| ids.contains(&x.id));
+            }
+            "queue" => self.state.queues.entry(pseudo.to_string()).or_default().push_back(QueuedMessage::from_json(&event["message"])),
+            "deliver" => { self.state.queue_take(pseudo); }
+            "restore" => self.state.queue_restore(pseudo, event["messages"].members().map(QueuedMessage::from_json).collect()),
+            other => panic!("Unknown event {} in the log", other)
+        }
+    }
+}
+
+/// Remove the content, the history and the attachment of a message if it is one of `ids`.
+/// Returns true if the message was changed.
+fn redact_message(message: &mut JsonValue, ids: &[u64]) -> bool {
+    if !message["id"].as_u64().is_some_and(|x| ids.contains(&x)) {
+        return false;
+    }
+    message["content"] = "".into();
+    message["history"] = JsonValue::new_array();
+    message["attachment"] = JsonValue::Null;
+    true
+}
+
+/// Name of the segment starting at a sequence number, padded so the names sort like the numbers.
+fn segment_name(first: u64) -> String {
+    format!("{}/{:020}.jsonl", EVENTS, first)
+}
+
+impl Storage for EventStorage {
+    fn name(&self) -> &str {
+        "events"
+    }
+
+    fn accounts(&self) -> Vec<Account> {
+        self.state.accounts()
+    }
+
+    fn account(&self, pseudo: &str) -> Option<Account> {
+        self.state.account(pseudo)
+    }
+
+    fn insert_account(&mut self, account: Account) -> bool {
+        let event = object!{ type: "register", account: account.to_json() };
+        let inserted = self.state.insert_account(account);
+        if inserted {
+            self.record(event);
+        }
+        inserted
+    }
+
+    fn set_pwd(&mut self, pseudo: &str, pwd: String) -> bool {
+        let event = object!{ type: "password", pseudo: pseudo, pwd: pwd.as_str() };
+        let updated = self.state.set_pwd(pseudo, pwd);
+        if updated {
+            self.record(event);
+        }
+        updated
+    }
+
+    fn add_key(&mut self, pseudo: &str, key: String) -> bool {
+        let event = object!{ type: "key", pseudo: pseudo, key: key.as_str() };
+        let added = self.state.add_key(pseudo, key);
+        if added {
+            self.record(event);
+        }
+        added
+    }
+
+    fn remove_key(&mut self, pseudo: &str, key: &str) -> bool {
+        let removed = self.state.remove_key(pseudo, key);
+        if removed {
+            self.record(object!{ type: "remove_key", pseudo: pseudo, key: key });
+        }
+        removed
+    }
+
+    fn delete_account(&mut self, pseudo: &str) -> bool {
+        let deleted = self.state.delete_account(pseudo);
+        if deleted {
+            self.record(object!{ type: "delete", pseudo: pseudo });
+        }
+        deleted
+    }
+
+    fn has_session(&self, pseudo: &str, token: &str) -> bool {
+        self.state.has_session(pseudo, token)
+    }
+
+    fn add_session(&mut self, pseudo: &str, token: String) {
+        self.record(object!{ type: "login", pseudo: pseudo, token: token.as_str() });
+        self.state.add_session(pseudo, token);
+    }
+
+    fn clear_sessions(&mut self, pseudo: &str) {
+        self.record(object!{ type: "logout", pseudo: pseudo });
+        self.state.clear_sessions(pseudo);
+    }
+
+    fn channels(&self) -> Vec<String> {
+        self.state.channels()
+    }
+
+    fn create_channel(&mut self, name: &str) -> bool {
+        let created = self.state.create_channel(name);
+        if created {
+            self.record(object!{ type: "channel", name: name });
+        }
+        created
+    }
+
+    fn members(&self, channel: &str) -> Vec<String> {
+        self.state.members(channel)
+    }
+
+    fn join_channel(&mut self, channel: &str, pseudo: &str) -> bool {
+        let joined = self.state.join_channel(channel, pseudo);
+        if joined {
+            self.record(object!{ type: "join", channel: channel, pseudo: pseudo });
+        }
+        joined
+    }
+
+    fn leave_channel(&mut self, channel: &str, pseudo: &str) -> bool {
+        let left = self.state.leave_channel(channel, pseudo);
+        if left {
+            self.record(object!{ type: "leave", channel: channel, pseudo: pseudo });
+        }
+        left
+    }
+
+    fn kicked(&self, channel: &str) -> Vec<String> {
+        self.state.kicked(channel)
+    }
+
+    fn set_kicked(&mut self, channel: &str, pseudo: &str, kicked: bool) -> bool {
+        let updated = self.state.set_kicked(channel, pseudo, kicked);
+        if updated {
+            self.record(object!{ type: "kick", channel: channel, pseudo: pseudo, kicked: kicked });
+        }
+        updated
+    }
+
+    fn topic(&self, channel: &str) -> Option<String> {
+        self.state.topic(channel)
+    }
+
+    fn set_topic(&mut self, channel: &str, topic: &str) -> bool {
+        let updated = self.state.set_topic(channel, topic);
+        if updated {
+            self.record(object!{ type: "topic", channel: channel, topic: topic });
+        }
+        updated
+    }
+
+    fn rename_channel(&mut self, channel: &str, name: &str) -> bool {
+        let renamed = self.state.rename_channel(channel, name);
+        if renamed {
+            self.record(object!{ type: "rename", channel: channel, name: name });
+        }
+        renamed
+    }
+
+    fn message_count(&self) -> usize {
+        self.state.message_count()
+    }
+
+    fn append_message(&mut self, channel: &str, from: &str, content: &str, expires: Option<String>, reply_to: Option<u64>, mentions: Vec<String>) -> StoredMessage {
+        let message = self.state.append_message(channel, from, content, expires, reply_to, mentions);
+        self.record(object!{ type: "message", message: message.to_json() });
+        message
+    }
+
+    fn import_message(&mut self, message: StoredMessage) -> StoredMessage {
+        let message = self.state.import_message(message);
+        self.record(object!{ type: "message", message: message.to_json() });
+        message
+    }
+
+    fn message(&self, id: u64) -> Option<StoredMessage> {
+        self.state.message(id)
+    }
+
+    fn edit_message(&mut self, id: u64, content: &str) -> Option<StoredMessage> {
+        // Seul le nouveau contenu est journalisé, le précédent est déjà dans le journal ou l'instantané
+        let message = self.state.edit_message(id, content)?;
+        self.record(object!{ type: "edit", id: id, content: content, edited: message.edited.clone() });
+        Some(message)
+    }
+
+    fn delete_message(&mut self, id: u64) -> Option<StoredMessage> {
+        let message = self.state.delete_message(id)?;
+        self.record(object!{ type: "delete_message", id: id });
+        // Le contenu supprimé ne doit plus apparaître sur le disque
+        self.redact(&[id]);
+        Some(message)
+    }
+
+    fn react(&mut self, id: u64, pseudo: &str, reaction: &str) -> Option<StoredMessage> {
+        let message = self.state.react(id, pseudo, reaction)?;
+        self.record(object!{ type: "react", id: id, pseudo: pseudo, reaction: reaction });
+        Some(message)
+    }
+
+    fn purge_messages(&mut self, expired: &dyn Fn(&StoredMessage) -> bool) -> usize {
+        let ids:Vec<u64> = self.state.all_messages().iter().filter(|x| expired(x)).map(|x| x.id).collect();
+        let count = self.state.purge_messages(&|x| ids.contains(&x.id));
+        if count > 0 {
+            self.redact(&ids);
+            self.record(object!{ type: "purge", ids: ids });
+        }
+        count
+    }
+
+    fn attachments(&self) -> HashMap<String, usize> {
+        self.state.attachments()
+    }
+
+    fn messages(&self, channel: &str, cursor: Option<&Cursor>, direction: Direction, limit: usize) -> Vec<StoredMessage> {
+        self.state.messages(channel, cursor, direction, limit)
+    }
+
+    fn search(&self, query: &SearchQuery, limit: usize) -> Vec<StoredMessage> {
+        self.state.search(query, limit)
+    }
+
+    fn queue_len(&self, pseudo: &str) -> usize {
+        self.state.queue_len(pseudo)
+    }
+
+    fn queue_push(&mut self, to: &str, from: &str, content: &str) {
+        self.state.queue_push(to, from, content);
+        let message = self.state.queues[to].back().map(|x| x.to_json());
+        self.record(object!{ type: "queue", pseudo: to, message: message });
+    }
+
+    fn queue_take(&mut self, pseudo: &str) -> Vec<QueuedMessage> {
+        let messages = self.state.queue_take(pseudo);
+        if !messages.is_empty() {
+            self.record(object!{ type: "deliver", pseudo: pseudo });
+        }
+        messages
+    }
+
+    fn queue_restore(&mut self, pseudo: &str, messages: Vec<QueuedMessage>) {
+        let event = object!{ type: "restore", pseudo: pseudo, messages: JsonValue::Array(messages.iter().map(|x| x.to_json()).collect()) };
+        self.state.queue_restore(pseudo, messages);
+        self.record(event);
+    }
+}
+
+#[cfg(test)]
+mod unit_testing {
+    use super::*;
+
+    #[test]
+    fn test_replay_and_snapshots() {
+        let dir = std::env::temp_dir().join("rust_messaging_test_events");
+        fs::remove_dir_all(&dir).ok();
+        let path = dir.to_str().unwrap();
+
+        let mut storage = EventStorage::open(path, None, 5);
+        assert!(storage.insert_account(Account::new(String::from("toto"), String::from("hash"))));
+        storage.add_session("toto", String::from("token"));
+        storage.append_message("general", "toto", "hello", None, None, vec![]);
+        storage.queue_push("titi", "toto", "private");
+        storage.append_message("general", "toto", "old", None, None, vec![]);
+        // Le cinquième événement déclenche un instantané, les suivants vont dans un nouveau segment
+        assert!(dir.join("snapshot.json").exists());
+        assert_eq!(storage.purge_messages(&|x| x.content == "hello"), 1);
+        assert!(storage.set_pwd("toto", String::from("new hash")));
+        assert_eq!(storage.queue_take("titi").len(), 1);
+
+        let mut storage = EventStorage::open(path, None, 5);
+        assert_eq!(storage.seq, 8);
+        // Seuls les événements qui suivent l'instantané sont relus
+        assert_eq!(storage.pending, 3);
+        assert_eq!(storage.account("toto").unwrap().pwd, "new hash");
+        assert!(storage.has_session("toto", "token"));
+        assert_eq!(storage.message_count(), 1);
+        assert_eq!(storage.queue_len("titi"), 0);
+        assert_eq!(storage.append_message("general", "toto", "again", None, None, vec![]).id, 3);
+        // Les segments couverts par un instantané sont gardés
+        assert_eq!(storage.segments().len(), 2);
+
+        // Une modification ne journalise que le nouveau contenu
+        let edited = storage.edit_message(3, "rewritten").unwrap();
+        storage.react(3, "toto", ":+1:").unwrap();
+        let mut storage = EventStorage::open(path, None, 0);
+        assert_eq!(storage.message(3).unwrap(), StoredMessage { reactions: storage.message(3).unwrap().reactions, ..edited });
+        assert_eq!(storage.message(3).unwrap().reactions[":+1:"], vec![String::from("toto")]);
+
+        // Le contenu d'un message supprimé ou purgé disparaît du disque, ses autres événements restent
+        storage.delete_message(3).unwrap();
+        let on_disk = || fs::read_dir(dir.join(EVENTS)).unwrap()
+            .map(|x| fs::read_to_string(x.unwrap().path()).unwrap())
+            .collect::<String>() + &fs::read_to_string(dir.join("snapshot.json")).unwrap();
+        assert!(!on_disk().contains("again") && !on_disk().contains("rewritten") && !on_disk().contains("hello"));
+        assert!(on_disk().contains("old"));
+
+        // Les identifiants des messages supprimés ne sont pas réutilisés, même après un instantané
+        assert_eq!(storage.purge_messages(&|x| x.id == 3), 1);
+        assert_eq!(EventStorage::open(path, None, 0).append_message("general", "toto", "last", None, None, vec![]).id, 4);
+    }
+}
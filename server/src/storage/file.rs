@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+use crate::search::SearchQuery;
+use super::{Account, Cipher, Cursor, DataDir, Direction, MemoryStorage, QueuedMessage, Storage, StoredMessage};
+
+/// State kept in the data directory, loaded in memory when the server starts:
+/// "users.json", "sessions.json", "channels.json", "members.json", "kicked.json", "topics.json" and "queues.json" are rewritten on each change
+/// through a temporary file, so a crash never leaves a partially written file.
+/// The messages are appended to "messages.jsonl", "next_id.json" keeps the identifier of the next message once the last ones are purged.
+/// With a cipher, each file and each line of the messages is encrypted.
+pub struct FileStorage {
+    dir: DataDir,
+    state: MemoryStorage
+}
+
+impl FileStorage {
+    /// Open the storage of the data directory, the directory is created if needed.
+    /// The records written in clear or with an old key are encrypted again with the current key.
+    pub fn open(data_dir: &str, cipher: Option<Cipher>) -> FileStorage {
+        let mut storage = FileStorage { dir: DataDir::open(data_dir, cipher), state: MemoryStorage::new() };
+        let mut stale = false;
+
+        if let Some(data) = storage.dir.read_json("users.json", &mut stale) {
+            storage.state.load_accounts(&data);
+        }
+        if let Some(data) = storage.dir.read_json("sessions.json", &mut stale) {
+            storage.state.load_sessions(&data);
+        }
+        if let Some(data) = storage.dir.read_json("channels.json", &mut stale) {
+            storage.state.load_channels(&data);
+        }
+        if let Some(data) = storage.dir.read_json("members.json", &mut stale) {
+            storage.state.load_members(&data);
+        }
+        if let Some(data) = storage.dir.read_json("kicked.json", &mut stale) {
+            storage.state.load_kicked(&data);
+        }
+        if let Some(data) = storage.dir.read_json("topics.json", &mut stale) {
+            storage.state.load_topics(&data);
+        }
+        if let Some(data) = storage.dir.read_json("queues.json", &mut stale) {
+            storage.state.load_queues(&data);
+        }
+        let (messages, partial) = storage.dir.read_lines("messages.jsonl", &mut stale);
+        for message in messages {
+            storage.state.push_message(StoredMessage::from_json(&message));
+        }
+        if let Some(data) = storage.dir.read_json("next_id.json", &mut stale) {
+            storage.state.load_next_id(data.as_u64().unwrap_or(1));
+        }
+
+        if let (true, Some(cipher)) = (stale, storage.dir.cipher()) {
+            println!("Data directory encrypted again with key {}", cipher.current());
+            storage.save_accounts();
+            storage.save_sessions();
+            storage.save_channels();
+            storage.save_members();
+            storage.save_kicked();
+            storage.save_topics();
+            storage.save_queues();
+            storage.save_next_id();
+        }
+        // Le fichier est réécrit sans la ligne incomplète pour que les messages suivants restent lisibles
+        if stale || partial {
+            storage.save_messages();
+        }
+        storage
+    }
+
+    fn save_accounts(&self) {
+        self.dir.write("users.json", self.state.accounts_json());
+    }
+
+    fn save_sessions(&self) {
+        self.dir.write("sessions.json", self.state.sessions_json());
+    }
+
+    fn save_channels(&self) {
+        self.dir.write("channels.json", self.state.channels_json());
+    }
+
+    fn save_members(&self) {
+        self.dir.write("members.json", self.state.members_json());
+    }
+
+    fn save_kicked(&self) {
+        self.dir.write("kicked.json", self.state.kicked_json());
+    }
+
+    fn save_topics(&self) {
+        self.dir.write("topics.json", self.state.topics_json());
+    }
+
+    fn save_queues(&self) {
+        self.dir.write("queues.json", self.state.queues_json());
+    }
+
+    fn save_next_id(&self) {
+        self.dir.write("next_id.json", self.state.next_id().into());
+    }
+
+    /// Rewrite the whole messages file.
+    fn save_messages(&self) {
+        let content:String = self.state.all_messages().iter().map(|x| format!("{}\n", self.dir.encrypt(json::stringify(x.to_json())))).collect();
+        self.dir.replace("messages.jsonl", content);
+    }
+}
+
+impl Storage for FileStorage {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    fn accounts(&self) -> Vec<Account> {
+        self.state.accounts()
+    }
+
+    fn account(&self, pseudo: &str) -> Option<Account> {
+        self.state.account(pseudo)
+    }
+
+    fn insert_account(&mut self, account: Account) -> bool {
+        let inserted = self.state.insert_account(account);
+        if inserted {
+            self.save_accounts();
+        }
+        inserted
+    }
+
+    fn set_pwd(&mut self, pseudo: &str, pwd: String) -> bool {
+        let updated = self.state.set_pwd(pseudo, pwd);
+        if updated {
+            self.save_accounts();
+        }
+        updated
+    }
+
+    fn add_key(&mut self, pseudo: &str, key: String) -> bool {
+        let added = self.state.add_key(pseudo, key);
+        if added {
+            self.save_accounts();
+        }
+        added
+    }
+
+    fn remove_key(&mut self, pseudo: &str, key: &str) -> bool {
+        let removed = self.state.remove_key(pseudo, key);
+        if removed {
+            self.save_accounts();
+        }
+        removed
+    }
+
+    fn delete_account(&mut self, pseudo: &str) -> bool {
+        let deleted = self.state.delete_account(pseudo);
+        if deleted {
+            self.save_accounts();
+            self.save_sessions();
+            self.save_members();
+            self.save_kicked();
+            self.save_queues();
+        }
+        deleted
+    }
+
+    fn has_session(&self, pseudo: &str, token: &str) -> bool {
+        self.state.has_session(pseudo, token)
+    }
+
+    fn add_session(&mut self, pseudo: &str, token: String) {
+        self.state.add_session(pseudo, token);
+        self.save_sessions();
+    }
+
+    fn clear_sessions(&mut self, pseudo: &str) {
+        self.state.clear_sessions(pseudo);
+        self.save_sessions();
+    }
+
+    fn channels(&self) -> Vec<String> {
+        self.state.channels()
+    }
+
+    fn members(&self, channel: &str) -> Vec<String> {
+        self.state.members(channel)
+    }
+
+    fn join_channel(&mut self, channel: &str, pseudo: &str) -> bool {
+        let joined = self.state.join_channel(channel, pseudo);
+        if joined {
+            self.save_members();
+        }
+        joined
+    }
+
+    fn leave_channel(&mut self, channel: &str, pseudo: &str) -> bool {
+        let left = self.state.leave_channel(channel, pseudo);
+        if left {
+            self.save_members();
+        }
+        left
+    }
+
+    fn kicked(&self, channel: &str) -> Vec<String> {
+        self.state.kicked(channel)
+    }
+
+    fn set_kicked(&mut self, channel: &str, pseudo: &str, kicked: bool) -> bool {
+        let updated = self.state.set_kicked(channel, pseudo, kicked);
+        if updated {
+            self.save_kicked();
+        }
+        updated
+    }
+
+    fn topic(&self, channel: &str) -> Option<String> {
+        self.state.topic(channel)
+    }
+
+    fn set_topic(&mut self, channel: &str, topic: &str) -> bool {
+        let updated = self.state.set_topic(channel, topic);
+        if updated {
+            self.save_topics();
+        }
+        updated
+    }
+
+    fn rename_channel(&mut self, channel: &str, name: &str) -> bool {
+        let renamed = self.state.rename_channel(channel, name);
+        if renamed {
+            self.save_channels();
+            self.save_members();
+            self.save_kicked();
+            self.save_topics();
+            self.save_messages();
+        }
+        renamed
+    }
+
+    fn message_count(&self) -> usize {
+        self.state.message_count()
+    }
+
+    fn create_channel(&mut self, name: &str) -> bool {
+        let created = self.state.create_channel(name);
+        if created {
+            self.save_channels();
+        }
+        created
+    }
+
+    fn append_message(&mut self, channel: &str, from: &str, content: &str, expires: Option<String>, reply_to: Option<u64>, mentions: Vec<String>) -> StoredMessage {
+        let message = self.state.append_message(channel, from, content, expires, reply_to, mentions);
+        self.dir.append("messages.jsonl", json::stringify(message.to_json()));
+        message
+    }
+
+    fn import_message(&mut self, message: StoredMessage) -> StoredMessage {
+        let message = self.state.import_message(message);
+        self.dir.append("messages.jsonl", json::stringify(message.to_json()));
+        message
+    }
+
+    fn message(&self, id: u64) -> Option<StoredMessage> {
+        self.state.message(id)
+    }
+
+    fn edit_message(&mut self, id: u64, content: &str) -> Option<StoredMessage> {
+        let message = self.state.edit_message(id, content);
+        if message.is_some() {
+            self.save_messages();
+        }
+        message
+    }
+
+    fn delete_message(&mut self, id: u64) -> Option<StoredMessage> {
+        let message = self.state.delete_message(id);
+        if message.is_some() {
+            // Le contenu supprimé ne doit plus apparaître dans le fichier
+            self.save_messages();
+        }
+        message
+    }
+
+    fn react(&mut self, id: u64, pseudo: &str, reaction: &str) -> Option<StoredMessage> {
+        let message = self.state.react(id, pseudo, reaction);
+        if message.is_some() {
+            self.save_messages();
+        }
+        message
+    }
+
+    fn purge_messages(&mut self, expired: &dyn Fn(&StoredMessage) -> bool) -> usize {
+        let count = self.state.purge_messages(expired);
+        if count > 0 {
+            // Le fichier des messages est réécrit sans les messages supprimés, leurs identifiants ne sont pas réutilisés
+            self.save_messages();
+            self.save_next_id();
+        }
+        count
+    }
+
+    fn attachments(&self) -> HashMap<String, usize> {
+        self.state.attachments()
+    }
+
+    fn messages(&self, channel: &str, cursor: Option<&Cursor>, direction: Direction, limit: usize) -> Vec<StoredMessage> {
+        self.state.messages(channel, cursor, direction, limit)
+    }
+
+    fn search(&self, query: &SearchQuery, limit: usize) -> Vec<StoredMessage> {
+        self.state.search(query, limit)
+    }
+
+    fn queue_len(&self, pseudo: &str) -> usize {
+        self.state.queue_len(pseudo)
+    }
+
+    fn queue_push(&mut self, to: &str, from: &str, content: &str) {
+        self.state.queue_push(to, from, content);
+        self.save_queues();
+    }
+
+    fn queue_take(&mut self, pseudo: &str) -> Vec<QueuedMessage> {
+        let messages = self.state.queue_take(pseudo);
+        if !messages.is_empty() {
+            self.save_queues();
+        }
+        messages
+    }
+
+    fn queue_restore(&mut self, pseudo: &str, messages: Vec<QueuedMessage>) {
+        self.state.queue_restore(pseudo, messages);
+        self.save_queues();
+    }
+}
+
+#[cfg(test)]
+mod unit_testing {
+    use super::*;
+    use std::fs;
+    use crate::config::{EncryptionConfig, KeyConfig, KeySource};
+
+    #[test]
+    fn test_storage_survives_reopening() {
+        let dir = std::env::temp_dir().join("rust_messaging_test_storage");
+        fs::remove_dir_all(&dir).ok();
+        let dir = dir.to_str().unwrap();
+
+        let mut storage = FileStorage::open(dir, None);
+        assert!(storage.insert_account(Account::new(String::from("toto"), String::from("hash"))));
+        assert!(storage.insert_account(Account::new(String::from("titi"), String::from("hash"))));
+        assert!(storage.add_key("toto", String::from("key")));
+        assert!(storage.set_pwd("toto", String::from("new hash")));
+        assert!(storage.delete_account("titi"));
+        storage.add_session("toto", String::from("token"));
+        assert!(storage.create_channel("rust"));
+        assert!(storage.join_channel("rust", "toto"));
+        storage.append_message("general", "toto", "hello", None, None, vec![]);
+        storage.append_message("general", "titi", "world", None, None, vec![]);
+        storage.queue_push("toto", "titi", "private");
+
+        let mut storage = FileStorage::open(dir, None);
+        assert_eq!(storage.accounts().len(), 1);
+        let account = storage.account("toto").unwrap();
+        assert_eq!(account.pwd, "new hash");
+        assert_eq!(account.keys, vec![String::from("key")]);
+        assert!(storage.has_session("toto", "token"));
+        assert_eq!(storage.channels(), vec![String::from("general"), String::from("rust")]);
+        assert_eq!(storage.channels_of("toto"), vec![String::from("rust")]);
+        assert_eq!(storage.message_count(), 2);
+        let query = SearchQuery::from_json(&json::object!{ words: "world" }, vec![String::from("general")]);
+        assert_eq!(storage.search(&query, 10)[0].from, "titi");
+        assert_eq!(storage.append_message("general", "toto", "again", None, None, vec![]).id, 3);
+        assert_eq!(storage.last_messages("general", 1)[0].to_line(), "toto : again");
+        assert_eq!(storage.queue_take("toto")[0].content, "private");
+        assert_eq!(FileStorage::open(dir, None).queue_len("toto"), 0);
+
+        assert_eq!(storage.purge_messages(&|x| x.content == "hello" || x.content == "again"), 2);
+        let mut storage = FileStorage::open(dir, None);
+        assert_eq!(storage.message_count(), 1);
+        assert_eq!(storage.append_message("general", "toto", "last", None, None, vec![]).id, 4);
+    }
+
+    #[test]
+    fn test_encrypted_storage_and_rotation() {
+        let dir = std::env::temp_dir().join("rust_messaging_test_encrypted_storage");
+        fs::remove_dir_all(&dir).ok();
+        let path = dir.to_str().unwrap();
+        let key = |id: &str| KeyConfig { id: id.to_string(), secret: KeySource::Secret(format!("secret {}", id)) };
+        let old = |migrate| Some(Cipher::from_config(&EncryptionConfig { keys: vec![key("old")], migrate }));
+        let new = || Some(Cipher::from_config(&EncryptionConfig { keys: vec![key("new"), key("old")], migrate: false }));
+
+        // Les données écrites en clair ne sont chiffrées à la réouverture que pendant une migration
+        let mut storage = FileStorage::open(path, None);
+        storage.insert_account(Account::new(String::from("toto"), String::from("hash")));
+        assert!(std::panic::catch_unwind(|| FileStorage::open(path, old(false))).is_err());
+        let mut storage = FileStorage::open(path, old(true));
+        storage.append_message("general", "toto", "secret message", None, None, vec![]);
+        for name in ["users.json", "channels.json", "messages.jsonl"] {
+            let content = fs::read_to_string(dir.join(name)).unwrap();
+            assert!(content.starts_with("enc1:old:"));
+            assert!(!content.contains("toto"));
+        }
+
+        let storage = FileStorage::open(path, new());
+        assert_eq!(storage.account("toto").unwrap().pwd, "hash");
+        assert_eq!(storage.last_messages("general", 1)[0].content, "secret message");
+        assert!(fs::read_to_string(dir.join("messages.jsonl")).unwrap().starts_with("enc1:new:"));
+        assert!(fs::read_to_string(dir.join("users.json")).unwrap().starts_with("enc1:new:"));
+        let storage = FileStorage::open(path, Some(Cipher::from_config(&EncryptionConfig { keys: vec![key("new")], migrate: false })));
+        assert_eq!(storage.message_count(), 1);
+    }
+}
@@ -0,0 +1,703 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use chrono::{SecondsFormat, Utc};
+use json::JsonValue;
+use crate::search::{SearchIndex, SearchQuery};
+use super::{Account, Cursor, Direction, QueuedMessage, Revision, Storage, StoredMessage, MAX_SESSIONS};
+
+/// Chat available on every server.
+const GENERAL: &str = "general";
+
+/// State kept in memory only, lost when the server stops.
+/// Used by the tests and as the in-memory state of the file storage.
+pub struct MemoryStorage {
+    accounts: Vec<Account>,
+    /// Tokens of the sessions of each user, from the oldest to the newest.
+    sessions: HashMap<String, Vec<String>>,
+    channels: Vec<String>,
+    /// Members of each chat.
+    members: HashMap<String, Vec<String>>,
+    /// Topic of the chats which have one.
+    topics: HashMap<String, String>,
+    /// Users kicked from each chat.
+    kicked: HashMap<String, Vec<String>>,
+    messages: Vec<StoredMessage>,
+    /// Identifier of the next message, never reused even once the last messages are purged.
+    next_id: u64,
+    index: SearchIndex,
+    pub(super) queues: HashMap<String, VecDeque<QueuedMessage>>
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage {
+            accounts: vec![],
+            sessions: HashMap::new(),
+            channels: vec![String::from(GENERAL)],
+            members: HashMap::new(),
+            topics: HashMap::new(),
+            kicked: HashMap::new(),
+            messages: vec![],
+            next_id: 1,
+            index: SearchIndex::default(),
+            queues: HashMap::new()
+        }
+    }
+
+    /// Returns the messages of every chat.
+    pub(super) fn all_messages(&self) -> &[StoredMessage] {
+        &self.messages
+    }
+
+    /// Add a message to the history and to the search index.
+    pub(super) fn push_message(&mut self, message: StoredMessage) {
+        self.next_id = self.next_id.max(message.id + 1);
+        self.index.add(&message);
+        self.messages.push(message);
+    }
+
+    /// Replace a message by a newer version of it, e.g. once edited.
+    pub(super) fn replace_message(&mut self, message: StoredMessage) {
+        if let Some(index) = self.position(message.id) {
+            self.index.remove(&self.messages[index]);
+            self.index.add(&message);
+            self.messages[index] = message;
+        }
+    }
+
+    /// Edit a message at the given date, the previous content is kept in its history.
+    pub(super) fn revise(&mut self, id: u64, content: &str, edited: String) -> Option<StoredMessage> {
+        let mut message = self.message(id).filter(|x| !x.deleted)?;
+        // Le contenu précédent est daté de sa dernière modification
+        let timestamp = message.edited.clone().unwrap_or_else(|| message.timestamp.clone());
+        message.history.push(Revision { content: message.content.clone(), timestamp });
+        message.content = content.to_string();
+        message.edited = Some(edited);
+        self.replace_message(message.clone());
+        Some(message)
+    }
+
+    /// Returns the position of a message in the history.
+    fn position(&self, id: u64) -> Option<usize> {
+        // Les identifiants sont croissants : recherche du message par dichotomie
+        self.messages.binary_search_by_key(&id, |x| x.id).ok()
+    }
+
+    /// Returns the identifier of the next message.
+    pub(super) fn next_id(&self) -> u64 {
+        self.next_id
+    }
+
+    /// Restore the identifier of the next message, it never goes back to the identifier of a message already stored.
+    pub(super) fn load_next_id(&mut self, next_id: u64) {
+        self.next_id = self.next_id.max(next_id);
+    }
+
+    pub(super) fn accounts_json(&self) -> JsonValue {
+        JsonValue::Array(self.accounts.iter().map(|x| x.to_json()).collect())
+    }
+
+    pub(super) fn sessions_json(&self) -> JsonValue {
+        let mut data = JsonValue::new_object();
+        for (pseudo, tokens) in &self.sessions {
+            data[pseudo.as_str()] = tokens.clone().into();
+        }
+        data
+    }
+
+    pub(super) fn channels_json(&self) -> JsonValue {
+        self.channels.clone().into()
+    }
+
+    pub(super) fn members_json(&self) -> JsonValue {
+        let mut data = JsonValue::new_object();
+        for (channel, members) in &self.members {
+            data[channel.as_str()] = members.clone().into();
+        }
+        data
+    }
+
+    pub(super) fn topics_json(&self) -> JsonValue {
+        let mut data = JsonValue::new_object();
+        for (channel, topic) in &self.topics {
+            data[channel.as_str()] = topic.as_str().into();
+        }
+        data
+    }
+
+    pub(super) fn kicked_json(&self) -> JsonValue {
+        let mut data = JsonValue::new_object();
+        for (channel, kicked) in &self.kicked {
+            data[channel.as_str()] = kicked.clone().into();
+        }
+        data
+    }
+
+    pub(super) fn queues_json(&self) -> JsonValue {
+        let mut data = JsonValue::new_object();
+        for (pseudo, messages) in &self.queues {
+            data[pseudo.as_str()] = JsonValue::Array(messages.iter().map(|x| x.to_json()).collect());
+        }
+        data
+    }
+
+    pub(super) fn load_accounts(&mut self, data: &JsonValue) {
+        self.accounts = data.members().map(Account::from_json).collect();
+    }
+
+    pub(super) fn load_sessions(&mut self, data: &JsonValue) {
+        // Les anciens fichiers n'ont qu'une session par utilisateur
+        self.sessions = data.entries().map(|(pseudo, tokens)| {
+            let tokens = match tokens.as_str() {
+                Some(token) => vec![token.to_string()],
+                None => tokens.members().map(|x| x.to_string()).collect()
+            };
+            (pseudo.to_string(), tokens)
+        }).collect();
+    }
+
+    pub(super) fn load_channels(&mut self, data: &JsonValue) {
+        self.channels = data.members().map(|x| x.to_string()).collect();
+    }
+
+    pub(super) fn load_members(&mut self, data: &JsonValue) {
+        self.members = data.entries().map(|(channel, members)| (channel.to_string(), members.members().map(|x| x.to_string()).collect())).collect();
+    }
+
+    pub(super) fn load_topics(&mut self, data: &JsonValue) {
+        self.topics = data.entries().map(|(channel, topic)| (channel.to_string(), topic.to_string())).collect();
+    }
+
+    pub(super) fn load_kicked(&mut self, data: &JsonValue) {
+        self.kicked = data.entries().map(|(channel, kicked)| (channel.to_string(), kicked.members().map(|x| x.to_string()).collect())).collect();
+    }
+
+    pub(super) fn load_queues(&mut self, data: &JsonValue) {
+        self.queues = data.entries()
+            .map(|(pseudo, messages)| (pseudo.to_string(), messages.members().map(QueuedMessage::from_json).collect()))
+            .collect();
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> MemoryStorage {
+        MemoryStorage::new()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn name(&self) -> &str {
+        "memory"
+    }
+
+    fn accounts(&self) -> Vec<Account> {
+        self.accounts.clone()
+    }
+
+    fn account(&self, pseudo: &str) -> Option<Account> {
+        self.accounts.iter().find(|x| x.pseudo == pseudo).cloned()
+    }
+
+    fn insert_account(&mut self, account: Account) -> bool {
+        if self.exists(&account.pseudo) {
+            return false;
+        }
+        self.accounts.push(account);
+        true
+    }
+
+    fn set_pwd(&mut self, pseudo: &str, pwd: String) -> bool {
+        match self.accounts.iter_mut().find(|x| x.pseudo == pseudo) {
+            Some(account) => account.pwd = pwd,
+            None => return false
+        }
+        true
+    }
+
+    fn add_key(&mut self, pseudo: &str, key: String) -> bool {
+        match self.accounts.iter_mut().find(|x| x.pseudo == pseudo) {
+            Some(account) if !account.keys.contains(&key) => account.keys.push(key),
+            _ => return false
+        }
+        true
+    }
+
+    fn remove_key(&mut self, pseudo: &str, key: &str) -> bool {
+        match self.accounts.iter_mut().find(|x| x.pseudo == pseudo) {
+            Some(account) if account.keys.iter().any(|x| x == key) => account.keys.retain(|x| x != key),
+            _ => return false
+        }
+        true
+    }
+
+    fn delete_account(&mut self, pseudo: &str) -> bool {
+        let count = self.accounts.len();
+        self.accounts.retain(|x| x.pseudo != pseudo);
+        self.sessions.remove(pseudo);
+        self.queues.remove(pseudo);
+        for members in self.members.values_mut().chain(self.kicked.values_mut()) {
+            members.retain(|x| x != pseudo);
+        }
+        count != self.accounts.len()
+    }
+
+    fn has_session(&self, pseudo: &str, token: &str) -> bool {
+        self.sessions.get(pseudo).map(|x| x.iter().any(|x| x == token)).unwrap_or(false)
+    }
+
+    fn add_session(&mut self, pseudo: &str, token: String) {
+        let tokens = self.sessions.entry(pseudo.to_string()).or_default();
+        tokens.push(token);
+        if tokens.len() > MAX_SESSIONS {
+            tokens.remove(0);
+        }
+    }
+
+    fn clear_sessions(&mut self, pseudo: &str) {
+        self.sessions.remove(pseudo);
+    }
+
+    fn channels(&self) -> Vec<String> {
+        self.channels.clone()
+    }
+
+    fn create_channel(&mut self, name: &str) -> bool {
+        if self.channels.iter().any(|x| x == name) {
+            return false;
+        }
+        self.channels.push(name.to_string());
+        true
+    }
+
+    fn members(&self, channel: &str) -> Vec<String> {
+        self.members.get(channel).cloned().unwrap_or_default()
+    }
+
+    fn join_channel(&mut self, channel: &str, pseudo: &str) -> bool {
+        if !self.channels.iter().any(|x| x == channel) || self.is_member(channel, pseudo) {
+            return false;
+        }
+        self.members.entry(channel.to_string()).or_default().push(pseudo.to_string());
+        true
+    }
+
+    fn leave_channel(&mut self, channel: &str, pseudo: &str) -> bool {
+        let members = self.members.entry(channel.to_string()).or_default();
+        let count = members.len();
+        members.retain(|x| x != pseudo);
+        count != members.len()
+    }
+
+    fn kicked(&self, channel: &str) -> Vec<String> {
+        self.kicked.get(channel).cloned().unwrap_or_default()
+    }
+
+    fn set_kicked(&mut self, channel: &str, pseudo: &str, kicked: bool) -> bool {
+        if !self.channels.iter().any(|x| x == channel) || self.is_kicked(channel, pseudo) == kicked {
+            return false;
+        }
+        let users = self.kicked.entry(channel.to_string()).or_default();
+        if kicked {
+            users.push(pseudo.to_string());
+        } else {
+            users.retain(|x| x != pseudo);
+        }
+        true
+    }
+
+    fn topic(&self, channel: &str) -> Option<String> {
+        self.topics.get(channel).cloned()
+    }
+
+    fn set_topic(&mut self, channel: &str, topic: &str) -> bool {
+        if !self.channels.iter().any(|x| x == channel) {
+            return false;
+        }
+        if topic.is_empty() {
+            self.topics.remove(channel);
+        } else {
+            self.topics.insert(channel.to_string(), topic.to_string());
+        }
+        true
+    }
+
+    fn rename_channel(&mut self, channel: &str, name: &str) -> bool {
+        if channel == GENERAL || !self.channels.iter().any(|x| x == channel) || self.channels.iter().any(|x| x == name) {
+            return false;
+        }
+        // Les membres, les exclus, le sujet et l'historique suivent le chat
+        for x in self.channels.iter_mut().filter(|x| *x == channel) {
+            *x = name.to_string();
+        }
+        if let Some(members) = self.members.remove(channel) {
+            self.members.insert(name.to_string(), members);
+        }
+        if let Some(kicked) = self.kicked.remove(channel) {
+            self.kicked.insert(name.to_string(), kicked);
+        }
+        if let Some(topic) = self.topics.remove(channel) {
+            self.topics.insert(name.to_string(), topic);
+        }
+        for message in self.messages.iter_mut().filter(|x| x.channel == channel) {
+            message.channel = name.to_string();
+        }
+        true
+    }
+
+    fn message_count(&self) -> usize {
+        self.messages.len()
+    }
+
+    fn append_message(&mut self, channel: &str, from: &str, content: &str, expires: Option<String>, reply_to: Option<u64>, mentions: Vec<String>) -> StoredMessage {
+        self.import_message(StoredMessage {
+            id: 0,
+            channel: channel.to_string(),
+            from: from.to_string(),
+            content: content.to_string(),
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            expires,
+            reply_to,
+            mentions,
+            edited: None,
+            history: vec![],
+            deleted: false,
+            reactions: BTreeMap::new(),
+            attachment: None
+        })
+    }
+
+    fn import_message(&mut self, mut message: StoredMessage) -> StoredMessage {
+        message.id = self.next_id;
+        self.push_message(message.clone());
+        message
+    }
+
+    fn message(&self, id: u64) -> Option<StoredMessage> {
+        self.position(id).map(|x| self.messages[x].clone())
+    }
+
+    fn edit_message(&mut self, id: u64, content: &str) -> Option<StoredMessage> {
+        self.revise(id, content, Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true))
+    }
+
+    fn delete_message(&mut self, id: u64) -> Option<StoredMessage> {
+        let mut message = self.message(id).filter(|x| !x.deleted)?;
+        message.content = String::new();
+        message.history.clear();
+        message.reactions.clear();
+        message.attachment = None;
+        message.deleted = true;
+        self.replace_message(message.clone());
+        Some(message)
+    }
+
+    fn react(&mut self, id: u64, pseudo: &str, reaction: &str) -> Option<StoredMessage> {
+        let mut message = self.message(id).filter(|x| !x.deleted)?;
+        let pseudos = message.reactions.entry(reaction.to_string()).or_default();
+        if pseudos.iter().any(|x| x == pseudo) {
+            pseudos.retain(|x| x != pseudo);
+            if pseudos.is_empty() {
+                message.reactions.remove(reaction);
+            }
+        } else {
+            pseudos.push(pseudo.to_string());
+        }
+        self.replace_message(message.clone());
+        Some(message)
+    }
+
+    fn purge_messages(&mut self, expired: &dyn Fn(&StoredMessage) -> bool) -> usize {
+        let count = self.messages.len();
+        let index = &mut self.index;
+        self.messages.retain(|x| {
+            let keep = !expired(x);
+            if !keep {
+                index.remove(x);
+            }
+            keep
+        });
+        count - self.messages.len()
+    }
+
+    fn attachments(&self) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for attachment in self.messages.iter().filter_map(|x| x.attachment.as_ref()) {
+            *counts.entry(attachment.hash.clone()).or_default() += 1;
+        }
+        counts
+    }
+
+    fn messages(&self, channel: &str, cursor: Option<&Cursor>, direction: Direction, limit: usize) -> Vec<StoredMessage> {
+        let messages = self.messages.iter().filter(|x| x.channel == channel);
+        match direction {
+            Direction::Before => {
+                let mut page:Vec<StoredMessage> = messages.rev()
+                    .filter(|x| cursor.map(|c| c.cmp(x).is_lt()).unwrap_or(true))
+                    .take(limit)
+                    .cloned()
+                    .collect();
+                page.reverse();
+                page
+            }
+            Direction::After => messages
+                .filter(|x| cursor.map(|c| c.cmp(x).is_gt()).unwrap_or(true))
+                .take(limit)
+                .cloned()
+                .collect()
+        }
+    }
+
+    fn search(&self, query: &SearchQuery, limit: usize) -> Vec<StoredMessage> {
+        let found = self.index.find(&query.words);
+        let get = |id: &u64| self.position(*id).map(|x| &self.messages[x]);
+        let messages: Box<dyn Iterator<Item = &StoredMessage>> = match &found {
+            Some(ids) => Box::new(ids.iter().rev().filter_map(get)),
+            None => Box::new(self.messages.iter().rev())
+        };
+        messages.filter(|x| !x.deleted && query.matches(x)).take(limit).cloned().collect()
+    }
+
+    fn queue_len(&self, pseudo: &str) -> usize {
+        self.queues.get(pseudo).map(|x| x.len()).unwrap_or(0)
+    }
+
+    fn queue_push(&mut self, to: &str, from: &str, content: &str) {
+        self.queues.entry(to.to_string()).or_default().push_back(QueuedMessage {
+            from: from.to_string(),
+            content: content.to_string(),
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+        });
+    }
+
+    fn queue_take(&mut self, pseudo: &str) -> Vec<QueuedMessage> {
+        self.queues.remove(pseudo).map(|x| x.into()).unwrap_or_default()
+    }
+
+    fn queue_restore(&mut self, pseudo: &str, messages: Vec<QueuedMessage>) {
+        let queue = self.queues.entry(pseudo.to_string()).or_default();
+        for message in messages.into_iter().rev() {
+            queue.push_front(message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_testing {
+    use super::*;
+
+    #[test]
+    fn test_accounts_and_sessions() {
+        let mut storage = MemoryStorage::new();
+        assert!(storage.insert_account(Account::new(String::from("toto"), String::from("hash"))));
+        assert!(!storage.insert_account(Account::new(String::from("toto"), String::from("other"))));
+        assert!(storage.add_key("toto", String::from("key")));
+        assert!(!storage.add_key("toto", String::from("key")));
+        assert!(storage.set_pwd("toto", String::from("new hash")));
+        storage.add_session("toto", String::from("token"));
+        for index in 0..MAX_SESSIONS {
+            storage.add_session("toto", format!("token {}", index));
+        }
+        storage.queue_push("toto", "titi", "hello");
+        assert!(storage.join_channel("general", "toto"));
+        assert!(!storage.join_channel("general", "toto"));
+        assert!(!storage.join_channel("unknown", "toto"));
+        assert_eq!(storage.channels_of("toto"), vec![String::from("general")]);
+        assert!(!storage.has_session("toto", "token"));
+        assert!(storage.has_session("toto", "token 0"));
+        storage.clear_sessions("toto");
+        assert!(!storage.has_session("toto", "token 0"));
+        storage.add_session("toto", String::from("token 0"));
+        assert!(storage.remove_key("toto", "key"));
+        assert!(!storage.remove_key("toto", "key"));
+        assert!(storage.account("toto").unwrap().keys.is_empty());
+
+        assert!(storage.delete_account("toto"));
+        assert!(!storage.delete_account("toto"));
+        assert!(!storage.has_session("toto", "token 0"));
+        assert_eq!(storage.queue_len("toto"), 0);
+        assert!(storage.members("general").is_empty());
+        assert_eq!(storage.channels(), vec![String::from("general")]);
+        assert!(!storage.create_channel("general"));
+        assert!(storage.create_channel("rust"));
+    }
+
+    #[test]
+    fn test_edit_and_delete() {
+        let mut storage = MemoryStorage::new();
+        let message = storage.append_message("general", "toto", "helo", None, None, vec![]);
+        storage.append_message("general", "titi", "hello", None, None, vec![]);
+
+        let edited = storage.edit_message(message.id, "hello world").unwrap();
+        assert_eq!(edited.history, vec![Revision { content: String::from("helo"), timestamp: message.timestamp.clone() }]);
+        assert!(edited.edited.is_some());
+        let query = SearchQuery::from_json(&json::parse(r#"{"words": "world"}"#).unwrap(), vec![String::from("general")]);
+        assert_eq!(storage.search(&query, 5), vec![edited.clone()]);
+
+        let deleted = storage.delete_message(message.id).unwrap();
+        assert!(deleted.deleted && deleted.content.is_empty() && deleted.history.is_empty());
+        assert!(storage.search(&query, 5).is_empty());
+        assert!(storage.delete_message(message.id).is_none());
+        assert!(storage.edit_message(message.id, "again").is_none());
+        assert!(storage.edit_message(42, "unknown").is_none());
+        assert_eq!(storage.last_messages("general", 5).len(), 2);
+    }
+
+    #[test]
+    fn test_reactions() {
+        let mut storage = MemoryStorage::new();
+        let message = storage.append_message("general", "toto", "hello", None, None, vec![]);
+        storage.react(message.id, "titi", "👍");
+        storage.react(message.id, "tata", "👍");
+        let reacted = storage.react(message.id, "titi", ":tada:").unwrap();
+        assert_eq!(reacted.reaction_counts(), json::parse(r#"{":tada:": 1, "👍": 2}"#).unwrap());
+
+        // Réagir deux fois de la même façon retire la réaction
+        let reacted = storage.react(message.id, "titi", ":tada:").unwrap();
+        assert_eq!(reacted.reactions, BTreeMap::from([(String::from("👍"), vec![String::from("titi"), String::from("tata")])]));
+        storage.delete_message(message.id);
+        assert!(storage.react(message.id, "titi", "👍").is_none());
+        assert!(storage.message(message.id).unwrap().reactions.is_empty());
+    }
+
+    #[test]
+    fn test_mentions_of() {
+        let mut storage = MemoryStorage::new();
+        storage.create_channel("rust");
+        storage.join_channel("general", "toto");
+        storage.append_message("general", "titi", "hello @toto", None, None, vec![String::from("toto")]);
+        storage.append_message("rust", "titi", "@toto in rust", None, None, vec![String::from("toto")]);
+        let message = storage.append_message("general", "titi", "again @toto", None, None, vec![String::from("toto")]);
+        storage.append_message("general", "titi", "hello", None, None, vec![]);
+
+        // Seuls les chats dont l'utilisateur est membre sont lus
+        assert_eq!(storage.mentions_of("toto", 5).iter().map(|x| x.id).collect::<Vec<_>>(), vec![3, 1]);
+        assert_eq!(storage.mentions_of("toto", 1).len(), 1);
+        storage.delete_message(message.id);
+        assert_eq!(storage.mentions_of("toto", 5).len(), 1);
+        assert!(storage.message(1).unwrap().to_frame()["mentions"].is_null());
+    }
+
+    #[test]
+    fn test_thread() {
+        let mut storage = MemoryStorage::new();
+        let root = storage.append_message("general", "toto", "lunch ?", None, None, vec![]);
+        storage.append_message("general", "titi", "other subject", None, None, vec![]);
+        let reply = storage.append_message("general", "titi", "yes", None, Some(root.id), vec![]);
+        storage.append_message("general", "tata", "me too", None, Some(reply.id), vec![]);
+
+        let ids:Vec<u64> = storage.thread(reply.id).iter().map(|x| x.id).collect();
+        assert_eq!(ids, vec![1, 3, 4]);
+        assert_eq!(storage.thread(2).len(), 1);
+        assert!(storage.thread(42).is_empty());
+    }
+
+    #[test]
+    fn test_topic_and_rename() {
+        let mut storage = MemoryStorage::new();
+        assert!(storage.create_channel("rust"));
+        assert!(storage.join_channel("rust", "toto"));
+        assert!(storage.set_topic("rust", "borrow checker"));
+        assert!(!storage.set_topic("unknown", "topic"));
+        storage.append_message("rust", "toto", "hello", None, None, vec![]);
+        assert!(storage.set_kicked("rust", "titi", true));
+        assert!(!storage.set_kicked("rust", "titi", true));
+        assert!(!storage.set_kicked("unknown", "titi", true));
+
+        assert!(storage.rename_channel("rust", "rustacean"));
+        assert!(!storage.rename_channel("rust", "other"));
+        assert!(!storage.rename_channel("general", "other"));
+        assert!(!storage.rename_channel("rustacean", "general"));
+        assert_eq!(storage.channels(), vec![String::from("general"), String::from("rustacean")]);
+        assert!(storage.is_member("rustacean", "toto"));
+        assert_eq!(storage.topic("rustacean"), Some(String::from("borrow checker")));
+        assert_eq!(storage.last_messages("rustacean", 5).len(), 1);
+        assert!(storage.set_topic("rustacean", ""));
+        assert_eq!(storage.topic("rustacean"), None);
+        // L'utilisateur exclu le reste dans le chat renommé, jusqu'à sa réadmission
+        assert!(storage.is_kicked("rustacean", "titi"));
+        assert!(storage.set_kicked("rustacean", "titi", false));
+        assert!(!storage.is_kicked("rustacean", "titi"));
+    }
+
+    #[test]
+    fn test_last_messages_of_a_chat() {
+        let mut storage = MemoryStorage::new();
+        for index in 0..5 {
+            storage.append_message("general", "toto", &format!("message {}", index), None, None, vec![]);
+            storage.append_message("other", "titi", "hello", None, None, vec![]);
+        }
+
+        let messages = storage.last_messages("general", 3);
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].content, "message 2");
+        assert_eq!(messages[2].content, "message 4");
+        assert_eq!(messages[2].id, 9);
+        assert_eq!(storage.last_messages("unknown", 3).len(), 0);
+    }
+
+    #[test]
+    fn test_pages_around_a_cursor() {
+        let mut storage = MemoryStorage::new();
+        for index in 0..10 {
+            storage.append_message("general", "toto", &format!("message {}", index), None, None, vec![]);
+        }
+
+        let page = storage.messages("general", Some(&Cursor::Id(5)), Direction::Before, 3);
+        assert_eq!(page.iter().map(|x| x.id).collect::<Vec<_>>(), vec![2, 3, 4]);
+        let page = storage.messages("general", Some(&Cursor::Id(8)), Direction::After, 3);
+        assert_eq!(page.iter().map(|x| x.id).collect::<Vec<_>>(), vec![9, 10]);
+        let page = storage.messages("general", None, Direction::After, 2);
+        assert_eq!(page.iter().map(|x| x.id).collect::<Vec<_>>(), vec![1, 2]);
+
+        let cursor = Cursor::from_json(&JsonValue::from("2000-01-01T00:00:00Z")).unwrap();
+        assert_eq!(storage.messages("general", Some(&cursor), Direction::After, 20).len(), 10);
+        assert_eq!(storage.messages("general", Some(&cursor), Direction::Before, 20).len(), 0);
+        assert_eq!(Cursor::from_json(&JsonValue::from(4)), Some(Cursor::Id(4)));
+        assert_eq!(Cursor::from_json(&JsonValue::from("yesterday")), None);
+    }
+
+    #[test]
+    fn test_purge_messages() {
+        let mut storage = MemoryStorage::new();
+        storage.append_message("general", "toto", "hello", None, None, vec![]);
+        storage.append_message("general", "toto", "secret", Some(String::from("2000-01-01T00:00:00Z")), None, vec![]);
+        assert_eq!(storage.purge_messages(&|x| x.expires.is_some()), 1);
+        assert_eq!(storage.message_count(), 1);
+        // L'identifiant du message supprimé n'est pas réutilisé
+        assert_eq!(storage.append_message("general", "toto", "again", None, None, vec![]).id, 3);
+        let query = SearchQuery::from_json(&json::object!{ words: "secret" }, vec![String::from("general")]);
+        assert!(storage.search(&query, 10).is_empty());
+    }
+
+    #[test]
+    fn test_search() {
+        let mut storage = MemoryStorage::new();
+        storage.append_message("general", "toto", "hello world", None, None, vec![]);
+        storage.append_message("secret", "toto", "hello world", None, None, vec![]);
+        storage.append_message("general", "titi", "Hello there", None, None, vec![]);
+        storage.append_message("general", "toto", "bye", None, None, vec![]);
+
+        let visible = vec![String::from("general")];
+        let query = SearchQuery::from_json(&json::object!{ words: "hello" }, visible.clone());
+        assert_eq!(storage.search(&query, 10).iter().map(|x| x.id).collect::<Vec<_>>(), vec![3, 1]);
+        assert_eq!(storage.search(&query, 1).len(), 1);
+        let query = SearchQuery::from_json(&json::object!{ from: "toto" }, visible);
+        assert_eq!(storage.search(&query, 10).iter().map(|x| x.id).collect::<Vec<_>>(), vec![4, 1]);
+    }
+
+    #[test]
+    fn test_queue_order() {
+        let mut storage = MemoryStorage::new();
+        storage.queue_push("titi", "toto", "first");
+        storage.queue_push("titi", "tata", "second");
+        storage.queue_push("tata", "toto", "hello");
+
+        let messages = storage.queue_take("titi");
+        assert_eq!(messages.iter().map(|x| x.content.as_str()).collect::<Vec<_>>(), vec!["first", "second"]);
+        assert_eq!(storage.queue_len("titi"), 0);
+        assert!(storage.queue_take("titi").is_empty());
+
+        storage.queue_push("titi", "toto", "third");
+        storage.queue_restore("titi", messages);
+        assert_eq!(storage.queue_take("titi").iter().map(|x| x.content.as_str()).collect::<Vec<_>>(), vec!["first", "second", "third"]);
+    }
+}
@@ -0,0 +1,22 @@
+use openssl::{error::ErrorStack, nid::Nid, ssl::{SslAcceptor, SslFiletype, SslMethod, SslStream, SslVerifyMode}};
+use crate::config::TlsConfig;
+
+/// Build the TLS acceptor of the certificate authentication mode.
+/// Clients must present a certificate signed by the configured CA.
+pub fn build_acceptor(config: &TlsConfig) -> Result<SslAcceptor, ErrorStack> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+    builder.set_ca_file(&config.ca)?;
+    builder.set_certificate_chain_file(&config.certificate)?;
+    builder.set_private_key_file(&config.key, SslFiletype::PEM)?;
+    builder.check_private_key()?;
+    builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    Ok(builder.build())
+}
+
+/// Get the common name of the certificate presented by the client.
+/// Returns None if the subject has no common name.
+pub fn peer_name<S>(stream: &SslStream<S>) -> Option<String> {
+    let certificate = stream.ssl().peer_certificate()?;
+    let name = certificate.subject_name().entries_by_nid(Nid::COMMONNAME).next()?;
+    String::from_utf8(name.data().as_slice().to_vec()).ok()
+}
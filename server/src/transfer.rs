@@ -0,0 +1,309 @@
+use std::{collections::HashMap, fs::{self, OpenOptions}, io::{Error, ErrorKind, Write}, path::{Path, PathBuf}};
+use openssl::sha::sha256;
+use crate::storage::{Attachment, Cipher};
+
+/// Bytes of a file carried by a frame, hex encoded so that the frame fits in the buffer.
+pub const CHUNK_SIZE: usize = 256;
+/// Maximum number of characters of a file name.
+pub const MAX_NAME: usize = 64;
+
+/// File being received from a user.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Upload {
+    /// Chat the file is sent to.
+    pub channel: String,
+    /// Text of the message sent with the file.
+    pub content: String,
+    pub attachment: Attachment
+}
+
+/// State of an upload after a request.
+#[derive(Debug, PartialEq)]
+pub enum Progress {
+    /// The content is expected from this offset.
+    Offset(u64),
+    /// The whole content was received and matches its hash.
+    Done(Upload)
+}
+
+/// Files sent with the messages, stored in a directory under the hex encoded SHA-256 of their content.
+/// An incomplete file is kept as "hash.uploader.part", an interrupted upload resumes at its end.
+/// The whole content is always sent, knowing the hash of a stored file doesn't give access to it.
+/// With a cipher, the files and the parts are written as lines, each line being an encrypted chunk of the content.
+pub struct Transfers {
+    dir: PathBuf,
+    /// Maximum size of a file in bytes.
+    max_size: u64,
+    /// Encryption of the data directory, also used for the files.
+    cipher: Option<Cipher>,
+    /// Uploads in progress, by pseudo of the uploader and hash, with the number of bytes received.
+    uploads: HashMap<(String, String), (Upload, u64)>
+}
+
+/// Returns the hex encoded SHA-256 of a content.
+pub fn hash(content: &[u8]) -> String {
+    hex::encode(sha256(content))
+}
+
+/// Returns true if the text is a hex encoded SHA-256, which can be used as a file name.
+pub fn is_hash(text: &str) -> bool {
+    text.len() == 64 && text.chars().all(|x| x.is_ascii_digit() || ('a'..='f').contains(&x))
+}
+
+/// Returns the name of a file without its directory, None if the name is invalid.
+pub fn file_name(name: &str) -> Option<String> {
+    let name = Path::new(name).file_name()?.to_str()?;
+    if name.chars().count() > MAX_NAME || name.chars().any(|x| x.is_control()) {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+/// Log an error of the files directory, the user is only told that the upload failed.
+fn storage_error(action: &str, err: std::io::Error) -> String {
+    println!("Unable to {}: {}", action, err);
+    String::from("The server was unable to store the file, try again later")
+}
+
+impl Transfers {
+    /// Open the directory of the files, it is created at the first upload.
+    /// With a cipher, the files written in clear or with an old key are encrypted again with the current key.
+    pub fn new(dir: PathBuf, max_size: u64, cipher: Option<Cipher>) -> Transfers {
+        let transfers = Transfers { dir, max_size, cipher, uploads: HashMap::new() };
+        if transfers.cipher.is_some() {
+            let hashes:Vec<String> = fs::read_dir(&transfers.dir).into_iter().flatten()
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .filter(|x| is_hash(x))
+                .collect();
+            for hash in hashes {
+                match transfers.read_file(&transfers.path(&hash)) {
+                    Ok((content, true)) => transfers.encrypt_again(&hash, &content),
+                    Ok(_) => (),
+                    Err(err) => panic!("Unable to read the file {}: {}", hash, err)
+                }
+            }
+        }
+        transfers
+    }
+
+    fn path(&self, hash: &str) -> PathBuf {
+        self.dir.join(hash)
+    }
+
+    /// Part received from an uploader, each uploader sending its own copy of the content.
+    fn part(&self, pseudo: &str, hash: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}.part", hash, hex::encode(pseudo)))
+    }
+
+    /// Start or resume the upload of a file to a chat.
+    pub fn start(&mut self, pseudo: &str, channel: &str, content: &str, attachment: Attachment) -> Result<Progress, String> {
+        let name = file_name(&attachment.name).ok_or(format!("Invalid file name, at most {} characters", MAX_NAME))?;
+        if !is_hash(&attachment.hash) {
+            return Err(String::from("Invalid file hash, a hex encoded SHA-256 is expected"));
+        }
+        if attachment.size > self.max_size {
+            return Err(format!("The file {} is too large, at most {} bytes", name, self.max_size));
+        }
+        fs::create_dir_all(&self.dir).map_err(|err| storage_error("create the files directory", err))?;
+        let upload = Upload {
+            channel: channel.to_string(),
+            content: content.to_string(),
+            attachment: Attachment { name, ..attachment }
+        };
+        let hash = upload.attachment.hash.clone();
+        let part = self.part(pseudo, &hash);
+        // Reprise à la fin de la partie déjà reçue, une partie trop longue ou illisible est recommencée
+        let received = match self.read_file(&part) {
+            Ok((content, _)) if content.len() as u64 <= upload.attachment.size => content.len() as u64,
+            Err(err) if err.kind() == ErrorKind::NotFound => 0,
+            _ => {
+                fs::remove_file(&part).ok();
+                0
+            }
+        };
+        self.uploads.insert((pseudo.to_string(), hash.clone()), (upload, received));
+        self.write(pseudo, &hash, received, &[])
+    }
+
+    /// Write a chunk of an upload at the given offset, a chunk which doesn't follow the part received is ignored.
+    /// The upload is done once the content is complete and matches its hash.
+    pub fn write(&mut self, pseudo: &str, hash: &str, offset: u64, data: &[u8]) -> Result<Progress, String> {
+        let key = (pseudo.to_string(), hash.to_string());
+        let (upload, received) = self.uploads.get(&key).ok_or(String::from("No upload of this file in progress"))?.clone();
+        let size = upload.attachment.size;
+        if offset != received {
+            return Ok(Progress::Offset(received));
+        }
+        if received + data.len() as u64 > size {
+            self.uploads.remove(&key);
+            fs::remove_file(self.part(pseudo, hash)).ok();
+            return Err(format!("The file {} is larger than announced", upload.attachment.name));
+        }
+        if !data.is_empty() {
+            if let Err(err) = self.append(&self.part(pseudo, hash), data) {
+                // La partie a pu être écrite en partie, l'envoi est recommencé
+                self.uploads.remove(&key);
+                fs::remove_file(self.part(pseudo, hash)).ok();
+                return Err(storage_error("write the uploaded file", err));
+            }
+        }
+        let received = received + data.len() as u64;
+        if received < size {
+            self.uploads.insert(key, (upload, received));
+            return Ok(Progress::Offset(received));
+        }
+        // Contenu complet : il n'est conservé que s'il correspond à son empreinte
+        self.uploads.remove(&key);
+        let (content, _) = self.read_file(&self.part(pseudo, hash)).map_err(|err| storage_error("read the uploaded file", err))?;
+        if self::hash(&content) != hash {
+            fs::remove_file(self.part(pseudo, hash)).ok();
+            return Err(format!("The content of {} doesn't match its hash, the upload is cancelled", upload.attachment.name));
+        }
+        fs::rename(self.part(pseudo, hash), self.path(hash)).map_err(|err| storage_error("store the uploaded file", err))?;
+        Ok(Progress::Done(upload))
+    }
+
+    /// Delete the stored files which are no longer sent by a message, `attachments` being the number of messages
+    /// sending each file. Returns the number of files deleted.
+    pub fn remove_unused(&self, hashes: &[String], attachments: &HashMap<String, usize>) -> usize {
+        let mut count = 0;
+        for hash in hashes.iter().filter(|x| is_hash(x) && !attachments.contains_key(*x)) {
+            match fs::remove_file(self.path(hash)) {
+                Ok(_) => count += 1,
+                Err(err) if err.kind() == ErrorKind::NotFound => (),
+                Err(err) => println!("Unable to delete the file {}: {}", hash, err)
+            }
+        }
+        count
+    }
+
+    /// Read a stored file from the given offset, None if the file isn't stored.
+    pub fn read(&self, hash: &str, offset: u64) -> Option<Vec<u8>> {
+        if !is_hash(hash) {
+            return None;
+        }
+        let (content, _) = match self.read_file(&self.path(hash)) {
+            Ok(result) => result,
+            Err(err) if err.kind() == ErrorKind::NotFound => return None,
+            Err(err) => {
+                println!("Unable to read the file {}: {}", hash, err);
+                return None;
+            }
+        };
+        Some(content.get(offset as usize..).unwrap_or_default().to_vec())
+    }
+
+    /// Append a chunk of content to a file, as an encrypted line with a cipher.
+    fn append(&self, path: &Path, data: &[u8]) -> std::io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        match &self.cipher {
+            Some(cipher) => writeln!(file, "{}", cipher.encrypt(&hex::encode(data))),
+            None => file.write_all(data)
+        }
+    }
+
+    /// Read the whole content of a file.
+    /// Returns the content and true if the file has to be encrypted again with the current key.
+    fn read_file(&self, path: &Path) -> std::io::Result<(Vec<u8>, bool)> {
+        let data = fs::read(path)?;
+        let cipher = match &self.cipher {
+            Some(cipher) => cipher,
+            None => return Ok((data, false))
+        };
+        // Un fichier reçu avant le chiffrement du répertoire de données est en clair
+        if !data.is_empty() && !Cipher::is_encrypted(&String::from_utf8_lossy(&data[..data.len().min(5)])) {
+            if !cipher.migrate() {
+                return Err(Error::new(ErrorKind::InvalidData, "file written in clear, set \"migrate\" to encrypt existing files"));
+            }
+            return Ok((data, true));
+        }
+        let text = String::from_utf8(data).map_err(|_| Error::new(ErrorKind::InvalidData, "invalid encrypted file"))?;
+        let mut content = vec![];
+        let mut stale = false;
+        for line in text.lines() {
+            let (chunk, old) = cipher.decrypt(line).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+            content.extend(hex::decode(chunk).map_err(|_| Error::new(ErrorKind::InvalidData, "invalid chunk"))?);
+            stale |= old;
+        }
+        Ok((content, stale))
+    }
+
+    /// Replace a stored file by its content encrypted with the current key.
+    fn encrypt_again(&self, hash: &str, content: &[u8]) {
+        let tmp = self.path(hash).with_extension("tmp");
+        fs::remove_file(&tmp).ok();
+        let result = content.chunks(CHUNK_SIZE).try_for_each(|x| self.append(&tmp, x))
+            .and_then(|_| fs::rename(&tmp, self.path(hash)));
+        if let Err(err) = result {
+            println!("Unable to encrypt the file {} again: {}", hash, err);
+            fs::remove_file(&tmp).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_testing {
+    use super::*;
+    use crate::config::{EncryptionConfig, KeyConfig, KeySource};
+
+    #[test]
+    fn test_resumed_upload() {
+        let dir = std::env::temp_dir().join("rust_messaging_test_files");
+        fs::remove_dir_all(&dir).ok();
+        let mut transfers = Transfers::new(dir.clone(), 10, None);
+        let attachment = Attachment { name: String::from("logs/server.log"), size: 9, hash: hash(b"123456789") };
+
+        assert!(transfers.start("toto", "general", "", Attachment { size: 11, ..attachment.clone() }).is_err());
+        assert_eq!(transfers.start("toto", "general", "", attachment.clone()), Ok(Progress::Offset(0)));
+        assert_eq!(transfers.write("toto", &attachment.hash, 0, b"1234"), Ok(Progress::Offset(4)));
+        // Un morceau déjà reçu est ignoré
+        assert_eq!(transfers.write("toto", &attachment.hash, 0, b"1234"), Ok(Progress::Offset(4)));
+
+        // Reprise après une déconnexion
+        assert_eq!(transfers.start("toto", "general", "the logs", attachment.clone()), Ok(Progress::Offset(4)));
+        let done = transfers.write("toto", &attachment.hash, 4, b"56789").unwrap();
+        let upload = Upload { channel: String::from("general"), content: String::from("the logs"), attachment: Attachment { name: String::from("server.log"), ..attachment.clone() } };
+        assert_eq!(done, Progress::Done(upload));
+        assert_eq!(transfers.read(&attachment.hash, 2).unwrap(), b"3456789");
+
+        // Connaître l'empreinte d'un fichier conservé ne suffit pas : son contenu doit être envoyé
+        assert_eq!(transfers.start("titi", "general", "", attachment.clone()), Ok(Progress::Offset(0)));
+        assert!(matches!(transfers.write("titi", &attachment.hash, 0, b"123456789"), Ok(Progress::Done(_))));
+        // Un contenu différent de son empreinte est refusé
+        let other = Attachment { name: String::from("other"), size: 2, hash: hash(b"ab") };
+        transfers.start("toto", "general", "", other.clone()).unwrap();
+        assert!(transfers.write("toto", &other.hash, 0, b"ac").is_err());
+        assert!(transfers.read(&other.hash, 0).is_none());
+        assert!(transfers.read("../secret", 0).is_none());
+
+        // Un fichier n'est supprimé que lorsqu'aucun message ne l'envoie plus
+        let hashes = vec![attachment.hash.clone()];
+        assert_eq!(transfers.remove_unused(&hashes, &HashMap::from([(attachment.hash.clone(), 1)])), 0);
+        assert!(transfers.read(&attachment.hash, 0).is_some());
+        assert_eq!(transfers.remove_unused(&hashes, &HashMap::new()), 1);
+        assert!(transfers.read(&attachment.hash, 0).is_none());
+
+        // Une erreur du disque est renvoyée à l'utilisateur sans arrêter le serveur
+        let file = std::env::temp_dir().join("rust_messaging_test_not_a_dir");
+        fs::write(&file, "").unwrap();
+        assert!(Transfers::new(file.clone(), 10, None).start("toto", "general", "", attachment.clone()).is_err());
+        fs::remove_file(file).ok();
+
+        // Avec un répertoire de données chiffré, les morceaux conservés sont chiffrés
+        let key = KeyConfig { id: String::from("files"), secret: KeySource::Secret(String::from("secret")) };
+        let mut transfers = Transfers::new(dir.clone(), 10, Some(Cipher::from_config(&EncryptionConfig { keys: vec![key], migrate: false })));
+        transfers.start("toto", "general", "", attachment.clone()).unwrap();
+        assert_eq!(transfers.write("toto", &attachment.hash, 0, b"1234"), Ok(Progress::Offset(4)));
+        assert_eq!(transfers.start("toto", "general", "", attachment.clone()), Ok(Progress::Offset(4)));
+        assert!(matches!(transfers.write("toto", &attachment.hash, 4, b"56789"), Ok(Progress::Done(_))));
+        assert!(!fs::read_to_string(dir.join(&attachment.hash)).unwrap().contains(&hex::encode("1234")));
+        assert_eq!(transfers.read(&attachment.hash, 0).unwrap(), b"123456789");
+        // Un fichier resté en clair n'est chiffré que pendant une migration
+        fs::write(dir.join(&other.hash), "ab").unwrap();
+        assert!(transfers.read(&other.hash, 0).is_none());
+        let key = KeyConfig { id: String::from("files"), secret: KeySource::Secret(String::from("secret")) };
+        let transfers = Transfers::new(dir.clone(), 10, Some(Cipher::from_config(&EncryptionConfig { keys: vec![key], migrate: true })));
+        assert!(Cipher::is_encrypted(&fs::read_to_string(dir.join(&other.hash)).unwrap()));
+        assert_eq!(transfers.read(&other.hash, 0).unwrap(), b"ab");
+    }
+}